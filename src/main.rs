@@ -1,13 +1,25 @@
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::f32::consts::PI;
+use std::fmt;
 use std::fs::File;
-use std::io::{self, Write};
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+// Instant::now panics on wasm32, so the wasm build does not time renders
+#[cfg(not(feature = "wasm"))]
+use std::time::Instant;
 
-use nalgebra::{Matrix3x1, SimdValue, Vector3};
+use nalgebra::{Affine3, Matrix3, Matrix4, Point3, Rotation3, Vector2, Vector3};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 struct Light {
     position: Vector3<f32>,
     intensity: f32,
+    group: Option<String>,
 }
 
 impl Light {
@@ -15,40 +27,481 @@ impl Light {
         Self {
             position,
             intensity,
+            group: None,
+        }
+    }
+
+    fn in_group(self, group: &str) -> Self {
+        Self {
+            group: Some(group.to_string()),
+            ..self
         }
     }
 
     fn pos(&self) -> Vector3<f32> {
-        self.position.clone()
+        self.position
     }
 
     fn diffuse_for_intersection(&self, intersection: &Intersection) -> f32 {
         let direction = (self.pos() - intersection.point).normalize();
-        self.intensity * f32::max(0.0, (direction * intersection.distance).norm())
+        self.intensity * f32::max(0.0, direction.dot(&intersection.normal))
+    }
+
+    fn specular_for_intersection(
+        &self,
+        intersection: &Intersection,
+        to_viewer: Vector3<f32>,
+        surface: &Surface,
+        config: &RenderConfig,
+    ) -> f32 {
+        let to_light = self.pos() - intersection.point;
+        // A disc of light mirrors as a blur of its own width, which spreads
+        // the lobe by the angle it subtends
+        let angular_radius = config.light_radius.atan2(to_light.norm());
+        let lobe = match surface.anisotropy {
+            // Ward's lobe is normalized already, so there's no classic form.
+            // The light's blur moves the half vector by half its angle
+            Some(roughness) => ward(
+                intersection.normal,
+                intersection.tangent,
+                to_light.normalize(),
+                to_viewer,
+                roughness.map(|r| (r * r + angular_radius * angular_radius / 4.0).sqrt()),
+            ),
+            None => phong(
+                intersection.normal,
+                to_light.normalize(),
+                to_viewer,
+                widened_exponent(surface.shininess, angular_radius),
+                config.classic_specular,
+            ),
+        };
+        self.intensity * surface.specular * lobe
+    }
+}
+
+fn ward(
+    normal: Vector3<f32>,
+    tangent: Vector3<f32>,
+    to_light: Vector3<f32>,
+    to_viewer: Vector3<f32>,
+    roughness: Vector2<f32>,
+) -> f32 {
+    // Ward's anisotropic lobe, per unit of light and weighted by N.L like the
+    // normalized Phong one. The half vector's slope is spread by roughness.x
+    // along the tangent and roughness.y along the bitangent, so the highlight
+    // stretches along whichever is rougher
+    let (n_dot_l, n_dot_v) = (normal.dot(&to_light), normal.dot(&to_viewer));
+    if n_dot_l <= 0.0 || n_dot_v <= 0.0 {
+        return 0.0;
+    }
+    let tangent = (tangent - normal * normal.dot(&tangent)).normalize();
+    let bitangent = normal.cross(&tangent);
+    let half = (to_light + to_viewer).normalize();
+    let slope = Vector2::new(half.dot(&tangent), half.dot(&bitangent)) / half.dot(&normal);
+    let spread = slope.component_div(&roughness).norm_squared();
+    n_dot_l * (-spread).exp() / (4.0 * PI * roughness.x * roughness.y * (n_dot_l * n_dot_v).sqrt())
+}
+
+// The Phong exponent of a lobe about as wide as one of the given exponent
+// blurred over a light of the given angular radius. A lobe of exponent n falls
+// off like a Gaussian of variance 1/n and a disc seen at angle a like one of
+// a^2/2, and the two spreads add
+fn widened_exponent(exponent: f32, angular_radius: f32) -> f32 {
+    if angular_radius <= 0.0 {
+        return exponent;
+    }
+    1.0 / (1.0 / exponent + angular_radius * angular_radius / 2.0)
+}
+
+fn phong(
+    normal: Vector3<f32>,
+    to_light: Vector3<f32>,
+    to_viewer: Vector3<f32>,
+    exponent: f32,
+    classic: bool,
+) -> f32 {
+    // Phong's lobe about the mirror direction of the light, per unit of light.
+    // The classic lobe peaks at 1 whatever the exponent, so sharp highlights
+    // reflect less in all. Normalized by (n + 2) / 2pi and weighted by N.L,
+    // it reflects at most the light arriving, however sharp
+    let n_dot_l = normal.dot(&to_light);
+    if n_dot_l <= 0.0 {
+        return 0.0;
+    }
+    let lobe = reflect(-to_light, normal)
+        .dot(&to_viewer)
+        .max(0.0)
+        .powf(exponent);
+    if classic {
+        lobe
+    } else {
+        (exponent + 2.0) / (2.0 * PI) * lobe * n_dot_l
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ShadingModel {
+    // Ambient plus N.L diffuse from every light
+    Lambert,
+    // The diffuse color as is, ignoring all lighting
+    Unlit,
+    // Mirror reflection tinted by the diffuse color, with no diffuse at all
+    Metal,
+    // Glass: untinted reflection blended by Fresnel with refraction, the
+    // light passing through filtered by the diffuse color
+    Dielectric,
+}
+
+// A single shading model with its albedo, the building block of materials
 #[derive(Debug, Clone, Copy)]
-struct Material {
+struct Surface {
     diffuse_color: Vector3<f32>,
+    shading: ShadingModel,
+    // Fraction of the light let through to shadow rays per channel, black for
+    // opaque. Camera rays still see the surface as usual
+    transmission: Vector3<f32>,
+    // Whether the back of the surface shades like the front. A single-sided
+    // surface keeps its outward normal on the back, which lights in front of
+    // it can't reach
+    two_sided: bool,
+    // Scene textures looked up at the hit's UV: one multiplying the diffuse
+    // color, and a tangent-space normal map bending the shading normal
+    texture: Option<TextureId>,
+    normal_map: Option<TextureId>,
+    texture_filter: TextureFilter,
+    // Index of refraction of a dielectric per channel, differing for dispersion
+    ior: Vector3<f32>,
+    // How far reflected and refracted rays stray from the mirror direction,
+    // 0 for sharp
+    roughness: f32,
+    // White Phong highlight over the diffuse shading, off at 0 strength
+    specular: f32,
+    shininess: f32,
+    // Roughness along and across the tangent for a stretched Ward highlight
+    // in place of the round Phong one, as on brushed metal
+    anisotropy: Option<Vector2<f32>>,
+}
+
+impl Surface {
+    fn new(diffuse_color: Vector3<f32>) -> Self {
+        Self {
+            diffuse_color,
+            shading: ShadingModel::Lambert,
+            transmission: Vector3::zeros(),
+            two_sided: true,
+            texture: None,
+            normal_map: None,
+            texture_filter: TextureFilter::Nearest,
+            ior: Vector3::repeat(1.5),
+            roughness: 0.0,
+            specular: 0.0,
+            shininess: 50.0,
+            anisotropy: None,
+        }
+    }
+
+    fn single_sided(self) -> Self {
+        Self {
+            two_sided: false,
+            ..self
+        }
+    }
+
+    fn tinted(self, transmission: Vector3<f32>) -> Self {
+        Self {
+            transmission,
+            ..self
+        }
+    }
+
+    fn unlit(self) -> Self {
+        Self {
+            shading: ShadingModel::Unlit,
+            ..self
+        }
+    }
+
+    fn metal(self) -> Self {
+        Self {
+            shading: ShadingModel::Metal,
+            ..self
+        }
+    }
+
+    fn dielectric(self, ior: f32) -> Self {
+        Self {
+            shading: ShadingModel::Dielectric,
+            ior: Vector3::repeat(ior),
+            ..self
+        }
+    }
+
+    fn with_dispersion(self, spread: f32) -> Self {
+        // Blue bends the most and red the least, spread apart around green
+        let ior = self.ior.y;
+        Self {
+            ior: Vector3::new(ior - spread / 2.0, ior, ior + spread / 2.0),
+            ..self
+        }
+    }
+
+    fn rough(self, roughness: f32) -> Self {
+        Self { roughness, ..self }
+    }
+
+    fn shiny(self, specular: f32, shininess: f32) -> Self {
+        Self {
+            specular,
+            shininess,
+            ..self
+        }
+    }
+
+    fn brushed(self, along_tangent: f32, across_tangent: f32) -> Self {
+        Self {
+            anisotropy: Some(Vector2::new(along_tangent, across_tangent)),
+            ..self
+        }
+    }
+
+    fn with_texture(self, texture: TextureId) -> Self {
+        Self {
+            texture: Some(texture),
+            ..self
+        }
+    }
+
+    fn with_normal_map(self, normal_map: TextureId) -> Self {
+        Self {
+            normal_map: Some(normal_map),
+            ..self
+        }
+    }
+
+    fn filtered(self, texture_filter: TextureFilter) -> Self {
+        Self {
+            texture_filter,
+            ..self
+        }
+    }
+
+    fn albedo_at(&self, hit: &Intersection, scene: &Scene) -> Vector3<f32> {
+        match self.texture {
+            Some(texture) => self
+                .diffuse_color
+                .component_mul(&scene.texture(texture).sample(hit.uv, self.texture_filter)),
+            None => self.diffuse_color,
+        }
+    }
+
+    fn normal_at(&self, hit: &Intersection, scene: &Scene) -> Vector3<f32> {
+        let Some(normal_map) = self.normal_map else {
+            return hit.normal;
+        };
+        // Texels hold tangent-space normals moved from [-1, 1] into [0, 1],
+        // x along the tangent, y along the bitangent and z along the normal.
+        // The frame is made orthonormal around the shading normal first, as
+        // interpolated normals lean away from the triangle's tangent
+        let texel = scene
+            .texture(normal_map)
+            .sample(hit.uv, self.texture_filter)
+            * 2.0
+            - Vector3::repeat(1.0);
+        let tangent = (hit.tangent - hit.normal * hit.normal.dot(&hit.tangent)).normalize();
+        let bitangent = hit.normal.cross(&tangent);
+        (tangent * texel.x + bitangent * texel.y + hit.normal * texel.z).normalize()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Material {
+    Surface(Surface),
+    // Both surfaces are shaded and the results mixed, factor 0 being all a
+    Blend { a: Surface, b: Surface, factor: f32 },
 }
 
 impl Material {
     fn new(diffuse_color: Vector3<f32>) -> Self {
-        Self { diffuse_color }
+        Material::Surface(Surface::new(diffuse_color))
+    }
+
+    // The same change made to every surface of the material
+    fn map_surfaces(self, change: impl Fn(Surface) -> Surface) -> Self {
+        match self {
+            Material::Surface(surface) => Material::Surface(change(surface)),
+            Material::Blend { a, b, factor } => Material::Blend {
+                a: change(a),
+                b: change(b),
+                factor,
+            },
+        }
+    }
+
+    fn unlit(self) -> Self {
+        self.map_surfaces(Surface::unlit)
+    }
+
+    fn single_sided(self) -> Self {
+        self.map_surfaces(Surface::single_sided)
+    }
+
+    fn with_texture(self, texture: TextureId) -> Self {
+        self.map_surfaces(|surface| surface.with_texture(texture))
+    }
+
+    fn with_normal_map(self, normal_map: TextureId) -> Self {
+        self.map_surfaces(|surface| surface.with_normal_map(normal_map))
+    }
+
+    fn filtered(self, texture_filter: TextureFilter) -> Self {
+        self.map_surfaces(|surface| surface.filtered(texture_filter))
+    }
+
+    fn blend(a: Surface, b: Surface, factor: f32) -> Self {
+        Material::Blend {
+            a,
+            b,
+            factor: factor.clamp(0.0, 1.0),
+        }
     }
 
     fn diffuse(&self) -> Vector3<f32> {
-        self.diffuse_color.clone()
+        match self {
+            Material::Surface(surface) => surface.diffuse_color,
+            Material::Blend { a, b, factor } => a.diffuse_color.lerp(&b.diffuse_color, *factor),
+        }
+    }
+
+    fn transmission(&self) -> Vector3<f32> {
+        match self {
+            Material::Surface(surface) => surface.transmission,
+            Material::Blend { a, b, factor } => a.transmission.lerp(&b.transmission, *factor),
+        }
+    }
+
+    fn two_sided(&self) -> bool {
+        // A blend shows a back as soon as either of its surfaces does
+        match self {
+            Material::Surface(surface) => surface.two_sided,
+            Material::Blend { a, b, .. } => a.two_sided || b.two_sided,
+        }
+    }
+}
+
+// Anything a ray can hit. Distances are along the normalized direction and
+// hits outside range are ignored
+trait Hittable {
+    fn ray_intersect(
+        &self,
+        origin: Vector3<f32>,
+        direction: Vector3<f32>,
+        range: Interval,
+    ) -> Option<Intersection>;
+
+    // How many primitive tests one ray_intersect call makes
+    fn primitive_count(&self) -> usize {
+        1
+    }
+
+    fn hash(&self, h: &mut Fnv);
+
+    // The object as written to scene files, tagged with its kind, see
+    // object_from_json
+    fn json(&self) -> String;
+
+    // A box containing everything the object can be hit on
+    fn bounds(&self) -> Bounds;
+}
+
+// An axis-aligned box, empty when min is past max
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Bounds {
+    min: Vector3<f32>,
+    max: Vector3<f32>,
+}
+
+impl Bounds {
+    fn empty() -> Self {
+        Self {
+            min: Vector3::repeat(f32::INFINITY),
+            max: Vector3::repeat(f32::NEG_INFINITY),
+        }
+    }
+
+    fn around(center: Vector3<f32>, radius: f32) -> Self {
+        Self {
+            min: center.add_scalar(-radius),
+            max: center.add_scalar(radius),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.min.x > self.max.x
+    }
+
+    fn union(self, other: Self) -> Self {
+        Self {
+            min: self.min.inf(&other.min),
+            max: self.max.sup(&other.max),
+        }
+    }
+
+    fn including(self, point: Vector3<f32>) -> Self {
+        self.union(Self {
+            min: point,
+            max: point,
+        })
+    }
+
+    fn corners(&self) -> impl Iterator<Item = Vector3<f32>> + '_ {
+        (0..8).map(|k| {
+            let pick = |bit: usize, axis: usize| {
+                if k & bit == 0 {
+                    self.min[axis]
+                } else {
+                    self.max[axis]
+                }
+            };
+            Vector3::new(pick(1, 0), pick(2, 1), pick(4, 2))
+        })
     }
 }
 
+// Fraction of the squared radius below which a grazing ray misses a sphere
+const SPHERE_TANGENT_EPSILON: f32 = 1e-5;
+
 struct Sphere {
     center: Vector3<f32>,
     radius: f32,
     material: Material,
 }
 
+fn perpendicular(v: Vector3<f32>) -> Vector3<f32> {
+    // Some unit vector at right angles to v, from whichever axis v is furthest from
+    let helper = if v.x.abs() < 0.9 {
+        Vector3::x()
+    } else {
+        Vector3::y()
+    };
+    v.cross(&helper).normalize()
+}
+
+fn sphere_uv(normal: Vector3<f32>) -> (Vector2<f32>, Vector3<f32>) {
+    // Longitude around +Y as u, starting at -X, and latitude from the bottom
+    // pole as v, with the tangent along increasing u. At the poles, where
+    // longitude is undefined, any tangent will do
+    let u = 0.5 - normal.z.atan2(normal.x) / (2.0 * PI);
+    let v = 1.0 - normal.y.clamp(-1.0, 1.0).acos() / PI;
+    let along_u = Vector3::new(normal.z, 0.0, -normal.x);
+    let tangent = if along_u.norm_squared() > 1e-12 {
+        along_u.normalize()
+    } else {
+        perpendicular(normal)
+    };
+    (Vector2::new(u, v), tangent)
+}
+
 impl Sphere {
     fn new(center: Vector3<f32>, radius: f32, material: Material) -> Self {
         Self {
@@ -58,151 +511,9050 @@ impl Sphere {
         }
     }
 
+    fn crossings(&self, origin: Vector3<f32>, dir_normalized: Vector3<f32>) -> Option<(f32, f32)> {
+        // Where the line enters and leaves the sphere, in order, range unchecked
+        // Calculate the vector from the line start to the sphere center
+        let start_to_center = self.center - origin;
+        // Calculate the projection of start_to_center onto the line direction
+        let projection = start_to_center.dot(&dir_normalized);
+        // Calculate the squared distance between the line and the sphere center
+        let distance_sq = start_to_center.norm_squared() - projection * projection;
+        // Check if the line passes within the sphere. Lines within a sliver of
+        // the radius are treated as misses: there the two crossings merge and
+        // f32 rounding would flip hit and miss from one ray to the next
+        let radius_sq = self.radius * self.radius;
+        let half_chord_sq = radius_sq - distance_sq;
+        if half_chord_sq <= radius_sq * SPHERE_TANGENT_EPSILON {
+            return None;
+        }
+        // Calculate the distance from the closest point to either surface crossing
+        let half_chord = half_chord_sq.sqrt();
+        Some((projection - half_chord, projection + half_chord))
+    }
+}
+
+impl Hittable for Sphere {
+    fn hash(&self, h: &mut Fnv) {
+        h.sphere(self);
+    }
+
+    fn json(&self) -> String {
+        json_object(&[("sphere", sphere_json(self))])
+    }
+
+    fn bounds(&self) -> Bounds {
+        Bounds::around(self.center, self.radius)
+    }
+
     fn ray_intersect(
         &self,
         origin: Vector3<f32>,
-        direction: Vector3<f32>, /* _distance: f32 */
+        direction: Vector3<f32>,
+        range: Interval,
     ) -> Option<Intersection> {
-        // Calculate the direction vector of the line segment
         let dir_normalized = direction.normalize();
-        // Calculate the vector from the line start to the sphere center
-        let start_to_center = self.center - origin;
-        // Calculate the projection of start_to_center onto the line direction
-        let projection = start_to_center.dot(&dir_normalized);
-        // Calculate the closest point on the line to the sphere center
-        let closest_point = origin + dir_normalized * projection;
-        // Calculate the distance between the closest point and the sphere center
-        let distance = (closest_point - self.center).norm();
-        // Check if the closest point is within the sphere
-        if distance <= self.radius {
-            Some(Intersection::new(closest_point, distance, self.material))
+        let (near, far) = self.crossings(origin, dir_normalized)?;
+        // Take the nearest crossing within the accepted range
+        let t = if range.contains(near) {
+            near
+        } else if range.contains(far) {
+            far
         } else {
-            None
-        }
+            return None;
+        };
+        let point = origin + dir_normalized * t;
+        let normal = (point - self.center).normalize();
+        let (uv, tangent) = sphere_uv(normal);
+        Some(Intersection {
+            uv,
+            tangent,
+            ..Intersection::new(point, t, dir_normalized, normal, normal, self.material)
+        })
     }
 }
 
-#[derive(Debug)]
-struct Intersection {
-    point: Vector3<f32>,
-    distance: f32,
+// A sphere with a spherical bite taken out of it, the CSG difference base - bite
+struct SphereDifference {
+    base: Sphere,
+    bite: Sphere,
+}
+
+impl SphereDifference {
+    fn new(base: Sphere, bite: Sphere) -> Self {
+        Self { base, bite }
+    }
+}
+
+impl Hittable for SphereDifference {
+    fn hash(&self, h: &mut Fnv) {
+        h.sphere(&self.base);
+        h.sphere(&self.bite);
+    }
+
+    fn json(&self) -> String {
+        json_object(&[("difference", difference_json(self))])
+    }
+
+    fn bounds(&self) -> Bounds {
+        // The bite only takes away
+        self.base.bounds()
+    }
+
+    fn ray_intersect(
+        &self,
+        origin: Vector3<f32>,
+        direction: Vector3<f32>,
+        range: Interval,
+    ) -> Option<Intersection> {
+        let dir_normalized = direction.normalize();
+        let (base_in, base_out) = self.base.crossings(origin, dir_normalized)?;
+        let inside_base = |t: f32| base_in < t && t < base_out;
+        let (bite_in, bite_out) = self
+            .bite
+            .crossings(origin, dir_normalized)
+            .unwrap_or((f32::INFINITY, f32::INFINITY));
+        let inside_bite = |t: f32| bite_in <= t && t <= bite_out;
+
+        // The solid's surface is the base surface outside the bite, plus the
+        // bite surface inside the base. The bite's outward normal points into
+        // the solid, so the carved surface uses it reversed
+        let candidates = [
+            (base_in, &self.base, 1.0, !inside_bite(base_in)),
+            (bite_in, &self.bite, -1.0, inside_base(bite_in)),
+            (bite_out, &self.bite, -1.0, inside_base(bite_out)),
+            (base_out, &self.base, 1.0, !inside_bite(base_out)),
+        ];
+        let (t, sphere, sign, _) = candidates
+            .into_iter()
+            .find(|&(t, _, _, on_surface)| on_surface && range.contains(t))?;
+        let point = origin + dir_normalized * t;
+        let (uv, tangent) = sphere_uv((point - sphere.center).normalize());
+        let normal = (point - sphere.center).normalize() * sign;
+        Some(Intersection {
+            uv,
+            tangent,
+            ..Intersection::new(point, t, dir_normalized, normal, normal, self.base.material)
+        })
+    }
+}
+
+// Texture coordinates of triangles that don't give their own: the first
+// vertex at the origin and the other two at the ends of the unit axes
+const DEFAULT_TRIANGLE_UVS: [Vector2<f32>; 3] = [
+    Vector2::new(0.0, 0.0),
+    Vector2::new(1.0, 0.0),
+    Vector2::new(0.0, 1.0),
+];
+
+struct Triangle {
+    vertices: [Vector3<f32>; 3],
+    normal: Vector3<f32>,
+    vertex_normals: Option<[Vector3<f32>; 3]>,
+    uvs: [Vector2<f32>; 3],
+    // Direction of increasing u across the triangle
+    tangent: Vector3<f32>,
     material: Material,
 }
 
-impl Intersection {
-    fn new(point: Vector3<f32>, distance: f32, material: Material) -> Self {
+impl Triangle {
+    fn new(vertices: [Vector3<f32>; 3], material: Material) -> Self {
+        // Counter-clockwise winding faces the normal towards the viewer
+        let normal = (vertices[1] - vertices[0])
+            .cross(&(vertices[2] - vertices[0]))
+            .normalize();
         Self {
-            point,
-            distance,
+            vertices,
+            normal,
+            vertex_normals: None,
+            uvs: DEFAULT_TRIANGLE_UVS,
+            tangent: (vertices[1] - vertices[0]).normalize(),
             material,
         }
     }
-}
 
-fn scene_intersect(
-    origin: Vector3<f32>,
-    direction: Vector3<f32>,
-    spheres: &Vec<Sphere>,
-) -> Option<Intersection> {
-    // Find the nearest intersection if it exists and return it
-    let nearest = spheres
-        .iter()
-        .fold(None, |nearest: Option<Intersection>, sphere| {
-            match sphere.ray_intersect(origin, direction) {
-                Some(intersection) => match nearest {
-                    Some(nearest) if intersection.distance < nearest.distance => Some(intersection),
-                    _ => Some(intersection),
-                },
-                None => nearest,
-            }
-        });
+    fn with_uvs(self, uvs: [Vector2<f32>; 3]) -> Self {
+        // Solve edge = tangent * du + bitangent * dv for both edges. Where the
+        // UVs are degenerate, the first edge stands in
+        let (edge1, edge2) = (
+            self.vertices[1] - self.vertices[0],
+            self.vertices[2] - self.vertices[0],
+        );
+        let (duv1, duv2) = (uvs[1] - uvs[0], uvs[2] - uvs[0]);
+        let determinant = duv1.x * duv2.y - duv2.x * duv1.y;
+        let tangent = (edge1 * duv2.y - edge2 * duv1.y) / determinant;
+        Self {
+            uvs,
+            tangent: if determinant.abs() > f32::EPSILON && tangent.norm() > 0.0 {
+                tangent.normalize()
+            } else {
+                edge1.normalize()
+            },
+            ..self
+        }
+    }
 
-    match nearest {
-        Some(intersection) if intersection.distance < 1000.0 => Some(intersection),
-        _ => None,
+    fn with_vertex_normals(self, normals: [Vector3<f32>; 3]) -> Self {
+        Self {
+            vertex_normals: Some(normals.map(|normal| normal.normalize())),
+            ..self
+        }
     }
 }
 
-fn cast_ray(
-    origin: Vector3<f32>,
-    direction: Vector3<f32>,
-    spheres: &Vec<Sphere>,
-    lights: &Vec<Light>,
-) -> Option<Vector3<f32>> {
-    let intersection = match scene_intersect(origin, direction, spheres) {
-        Some(intersection) => intersection,
-        _ => return None,
-    };
+impl Hittable for Triangle {
+    fn hash(&self, h: &mut Fnv) {
+        self.vertices.iter().for_each(|&v| h.vec(v));
+        h.vec(self.normal);
+        match self.vertex_normals {
+            Some(normals) => normals.iter().for_each(|&n| h.vec(n)),
+            None => h.u64(0),
+        }
+        self.uvs.iter().for_each(|uv| {
+            h.f32(uv.x);
+            h.f32(uv.y);
+        });
+        h.material(&self.material);
+    }
 
-    let diffuse_intensity: f32 = lights.iter().fold(0.0, |acc: f32, light: &Light| {
-        acc + light.diffuse_for_intersection(&intersection)
-    });
+    fn json(&self) -> String {
+        json_object(&[("triangle", triangle_json(self))])
+    }
+
+    fn bounds(&self) -> Bounds {
+        self.vertices
+            .iter()
+            .fold(Bounds::empty(), |bounds, &v| bounds.including(v))
+    }
 
-    Some(intersection.material.diffuse() as Vector3<f32> + Vector3::repeat(diffuse_intensity))
+    fn ray_intersect(
+        &self,
+        origin: Vector3<f32>,
+        direction: Vector3<f32>,
+        range: Interval,
+    ) -> Option<Intersection> {
+        // Moller-Trumbore: solve for the ray distance and barycentric coordinates at once
+        let dir_normalized = direction.normalize();
+        let edge1 = self.vertices[1] - self.vertices[0];
+        let edge2 = self.vertices[2] - self.vertices[0];
+        let p = dir_normalized.cross(&edge2);
+        let determinant = edge1.dot(&p);
+        // A zero determinant means the ray runs parallel to the triangle
+        if determinant.abs() < f32::EPSILON {
+            return None;
+        }
+        let inverse = 1.0 / determinant;
+        let to_origin = origin - self.vertices[0];
+        let u = to_origin.dot(&p) * inverse;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = to_origin.cross(&edge1);
+        let v = dir_normalized.dot(&q) * inverse;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let t = edge2.dot(&q) * inverse;
+        if !range.contains(t) {
+            return None;
+        }
+        let point = origin + dir_normalized * t;
+        // Interpolate vertex normals for smooth shading when the mesh provides them
+        let normal = match self.vertex_normals {
+            Some([n0, n1, n2]) => (n0 * (1.0 - u - v) + n1 * u + n2 * v).normalize(),
+            None => self.normal,
+        };
+        let [uv0, uv1, uv2] = self.uvs;
+        Some(Intersection {
+            barycentric: Some(Vector3::new(1.0 - u - v, u, v)),
+            uv: uv0 * (1.0 - u - v) + uv1 * u + uv2 * v,
+            tangent: self.tangent,
+            ..Intersection::new(point, t, dir_normalized, self.normal, normal, self.material)
+        })
+    }
 }
 
-fn write_ppm_image(filename: &str, width: u32, height: u32, pixels: &[u8]) -> io::Result<()> {
-    let mut file = File::create(filename)?;
+// A mesh as one object, the nearest hit among its parts
+impl<T: Hittable> Hittable for Vec<T> {
+    fn ray_intersect(
+        &self,
+        origin: Vector3<f32>,
+        direction: Vector3<f32>,
+        range: Interval,
+    ) -> Option<Intersection> {
+        self.iter()
+            .filter_map(|part| part.ray_intersect(origin, direction, range))
+            .fold(None, |nearest: Option<Intersection>, hit| match nearest {
+                Some(nearest) if hit.distance >= nearest.distance => Some(nearest),
+                _ => Some(hit),
+            })
+    }
 
-    // Write the PPM header
-    writeln!(file, "P6 {} {} 255", width, height)?;
+    fn primitive_count(&self) -> usize {
+        self.iter().map(Hittable::primitive_count).sum()
+    }
 
-    // Write the pixel data
-    file.write_all(pixels)?;
+    fn hash(&self, h: &mut Fnv) {
+        h.u64(self.len() as u64);
+        self.iter().for_each(|part| part.hash(h));
+    }
 
-    Ok(())
-}
+    fn json(&self) -> String {
+        json_object(&[("group", json_array(self.iter().map(T::json)))])
+    }
 
-fn vec_to_rgb(v: Vector3<f32>) -> (u8, u8, u8) {
-    // 255 * (max of 0.0 or (min of 1.0 or v[d]))
-    // for each channel
-    (
-        ((255.0 * v.x.clamp(0.0, 1.0)) as u8),
-        ((255.0 * v.y.clamp(0.0, 1.0)) as u8),
-        ((255.0 * v.z.clamp(0.0, 1.0)) as u8),
-    )
+    fn bounds(&self) -> Bounds {
+        self.iter()
+            .map(Hittable::bounds)
+            .fold(Bounds::empty(), Bounds::union)
+    }
 }
 
-fn render(spheres: &Vec<Sphere>, lights: &Vec<Light>) -> io::Result<()> {
-    let width = 256;
-    let height = 256;
-    let fov = PI / 3.0;
-    let filename = "output.ppm";
+// Objects read back from a scene file, whose kinds are only known at run time
+impl Hittable for Box<dyn Hittable + Sync> {
+    fn ray_intersect(
+        &self,
+        origin: Vector3<f32>,
+        direction: Vector3<f32>,
+        range: Interval,
+    ) -> Option<Intersection> {
+        self.as_ref().ray_intersect(origin, direction, range)
+    }
 
-    let mut pixels = Vec::new();
-    for j in 0..height {
-        for i in 0..width {
-            let x = (2.0 * (i as f32 + 0.5) / width as f32 - 1.0)
-                * (fov / 2.0).tan()
-                * (width as f32 / height as f32);
-            let y = -(2.0 * (j as f32 + 0.5) / height as f32 - 1.0) * (fov / 2.0).tan();
-            let dir = Vector3::new(x, y, -1.0).normalize();
-            let (r, g, b) = match cast_ray(Vector3::zeros(), dir, &spheres, &lights) {
-                Some(v) => vec_to_rgb(v),
-                _ => vec_to_rgb(Vector3::new(
-                    j as f32 / height as f32,
-                    i as f32 / width as f32,
-                    (i + j) as f32 / (height + width) as f32,
-                )),
-            };
-            pixels.push(r);
-            pixels.push(g);
-            pixels.push(b);
-        }
+    fn primitive_count(&self) -> usize {
+        self.as_ref().primitive_count()
     }
 
-    write_ppm_image(filename, width, height, &pixels)
-}
+    fn hash(&self, h: &mut Fnv) {
+        self.as_ref().hash(h)
+    }
 
-fn main() -> io::Result<()> {
-    let chartreuse = Material::new(Vector3::new(0.5, 0.8, 0.3));
-    let red = Material::new(Vector3::new(1.0, 0.5, 0.5));
-    let spheres = vec![
-        Sphere::new(Vector3::new(2.0, 1.0, -16.0), 5.0, red),
-        Sphere::new(Vector3::new(2.0, 3.0, -11.0), 1.0, chartreuse),
-        Sphere::new(Vector3::new(-3.0, 0.0, -16.0), 2.0, chartreuse),
-    ];
-    let lights = vec![Light::new(Vector3::new(-20.0, 20.0, 20.0), 0.2)];
-    render(&spheres, &lights)?;
+    fn json(&self) -> String {
+        self.as_ref().json()
+    }
+
+    fn bounds(&self) -> Bounds {
+        self.as_ref().bounds()
+    }
+}
+
+// An object placed in the world by an affine transform. Rays are taken into
+// the object's own space and hits brought back out, so a unit sphere can be
+// stretched into an ellipsoid or one mesh placed several times
+struct Transformed {
+    object: Box<dyn Hittable + Sync>,
+    to_world: Affine3<f32>,
+    to_local: Affine3<f32>,
+    // Normals transform by the inverse transpose to stay perpendicular to the surface
+    normal_matrix: Matrix3<f32>,
+}
+
+impl Transformed {
+    fn new(object: impl Hittable + Sync + 'static, to_world: Affine3<f32>) -> Self {
+        Self::boxed(Box::new(object), to_world)
+    }
+
+    fn boxed(object: Box<dyn Hittable + Sync>, to_world: Affine3<f32>) -> Self {
+        // A transform that flattens a direction to nothing has no inverse,
+        // and the object would come out as NaN
+        let to_local = to_world.inverse();
+        Self {
+            object,
+            to_world,
+            to_local,
+            normal_matrix: to_local.matrix().fixed_view::<3, 3>(0, 0).transpose(),
+        }
+    }
+}
+
+fn placement(
+    translation: Vector3<f32>,
+    rotation: Vector3<f32>,
+    scale: Vector3<f32>,
+) -> Affine3<f32> {
+    // Scale, then rotate by Euler angles about X, Y and Z, then translate
+    Affine3::from_matrix_unchecked(
+        Matrix4::new_translation(&translation)
+            * Rotation3::from_euler_angles(rotation.x, rotation.y, rotation.z).to_homogeneous()
+            * Matrix4::new_nonuniform_scaling(&scale),
+    )
+}
+
+impl Hittable for Transformed {
+    fn ray_intersect(
+        &self,
+        origin: Vector3<f32>,
+        direction: Vector3<f32>,
+        range: Interval,
+    ) -> Option<Intersection> {
+        let local_origin = self.to_local.transform_point(&Point3::from(origin)).coords;
+        let local_direction = self.to_local.transform_vector(&direction.normalize());
+        // The transform stretches distances along the ray by the length of
+        // the local direction, in both the range and the hit
+        let stretch = local_direction.norm();
+        let local_range = Interval::new(range.min * stretch, range.max * stretch);
+        let hit = self
+            .object
+            .ray_intersect(local_origin, local_direction, local_range)?;
+        let normal = |n: Vector3<f32>| (self.normal_matrix * n).normalize();
+        Some(Intersection {
+            point: self
+                .to_world
+                .transform_point(&Point3::from(hit.point))
+                .coords,
+            distance: hit.distance / stretch,
+            normal: normal(hit.normal),
+            geometric_normal: normal(hit.geometric_normal),
+            // Tangents lie in the surface and transform like any direction
+            tangent: self.to_world.transform_vector(&hit.tangent).normalize(),
+            ..hit
+        })
+    }
+
+    fn primitive_count(&self) -> usize {
+        self.object.primitive_count()
+    }
+
+    fn hash(&self, h: &mut Fnv) {
+        self.to_world.matrix().iter().for_each(|&v| h.f32(v));
+        self.object.hash(h);
+    }
+
+    fn json(&self) -> String {
+        json_object(&[("instance", instance_json(self))])
+    }
+
+    fn bounds(&self) -> Bounds {
+        // The box around the placed corners of the local box
+        let local = self.object.bounds();
+        if local.is_empty() {
+            return local;
+        }
+        local.corners().fold(Bounds::empty(), |bounds, corner| {
+            bounds.including(self.to_world.transform_point(&Point3::from(corner)).coords)
+        })
+    }
+}
+
+fn quad_facing(
+    corners: [Vector3<f32>; 4],
+    toward: Vector3<f32>,
+    material: Material,
+) -> [Triangle; 2] {
+    // Split a planar quad into two triangles wound so their normal faces
+    // `toward`, with the corners at UVs (0, 0), (1, 0), (1, 1) and (0, 1)
+    let [a, b, c, d] = corners;
+    let uv = |u: f32, v: f32| Vector2::new(u, v);
+    let (uv_a, uv_b, uv_c, uv_d) = (uv(0.0, 0.0), uv(1.0, 0.0), uv(1.0, 1.0), uv(0.0, 1.0));
+    let normal = (b - a).cross(&(c - a));
+    if normal.dot(&(toward - a)) >= 0.0 {
+        [
+            Triangle::new([a, b, c], material).with_uvs([uv_a, uv_b, uv_c]),
+            Triangle::new([a, c, d], material).with_uvs([uv_a, uv_c, uv_d]),
+        ]
+    } else {
+        [
+            Triangle::new([a, c, b], material).with_uvs([uv_a, uv_c, uv_b]),
+            Triangle::new([a, d, c], material).with_uvs([uv_a, uv_d, uv_c]),
+        ]
+    }
+}
+
+fn box_triangles(
+    center: Vector3<f32>,
+    size: Vector3<f32>,
+    rotation_y: f32,
+    material: Material,
+) -> Vec<Triangle> {
+    // Corner k has its x, y and z on the positive side when bit 0, 1 and 2 are set
+    let (sin, cos) = rotation_y.sin_cos();
+    let corner = |k: usize| {
+        let half = Vector3::new(
+            if k & 1 != 0 { 0.5 } else { -0.5 },
+            if k & 2 != 0 { 0.5 } else { -0.5 },
+            if k & 4 != 0 { 0.5 } else { -0.5 },
+        )
+        .component_mul(&size);
+        center
+            + Vector3::new(
+                half.x * cos + half.z * sin,
+                half.y,
+                -half.x * sin + half.z * cos,
+            )
+    };
+    // Faces as corner indices in perimeter order, all oriented to face outwards
+    let faces = [
+        [0, 1, 3, 2],
+        [4, 5, 7, 6],
+        [0, 1, 5, 4],
+        [2, 3, 7, 6],
+        [0, 2, 6, 4],
+        [1, 3, 7, 5],
+    ];
+    faces
+        .iter()
+        .flat_map(|face| {
+            let corners = face.map(corner);
+            let face_center = corners.iter().sum::<Vector3<f32>>() / 4.0;
+            quad_facing(corners, face_center * 2.0 - center, material)
+        })
+        .collect()
+}
+
+fn parse_obj(source: &str, material: Material) -> io::Result<Vec<Triangle>> {
+    let invalid = |line: usize, message: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("line {}: {}", line + 1, message),
+        )
+    };
+    // OBJ indices are 1-based, negative ones count back from the end
+    let resolve = |index: i64, len: usize| {
+        let resolved = if index < 0 {
+            len as i64 + index
+        } else {
+            index - 1
+        };
+        (0..len as i64)
+            .contains(&resolved)
+            .then_some(resolved as usize)
+    };
+    let parse_vector = |line: usize, fields: std::str::SplitWhitespace| {
+        let coords = fields
+            .take(3)
+            .map(|field| field.parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid(line, "invalid coordinate"))?;
+        if coords.len() != 3 {
+            return Err(invalid(line, "expected three coordinates"));
+        }
+        Ok(Vector3::new(coords[0], coords[1], coords[2]))
+    };
+
+    let mut vertices = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    let mut triangles = Vec::new();
+    for (line_number, line) in source.lines().enumerate() {
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            Some("v") => vertices.push(parse_vector(line_number, fields)?),
+            Some("vn") => normals.push(parse_vector(line_number, fields)?),
+            Some("vt") => {
+                // Only u and v are used, a third coordinate is ignored
+                let coords = fields
+                    .take(2)
+                    .map(|field| field.parse::<f32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| invalid(line_number, "invalid texture coordinate"))?;
+                match coords[..] {
+                    [u, v] => uvs.push(Vector2::new(u, v)),
+                    [u] => uvs.push(Vector2::new(u, 0.0)),
+                    _ => return Err(invalid(line_number, "expected a texture coordinate")),
+                }
+            }
+            Some("f") => {
+                // Face entries look like v, v/vt, v//vn or v/vt/vn
+                let corners = fields
+                    .map(|field| {
+                        let mut parts = field.split('/');
+                        let vertex = parts
+                            .next()
+                            .and_then(|index| index.parse().ok())
+                            .and_then(|index| resolve(index, vertices.len()))
+                            .map(|index| vertices[index])
+                            .ok_or_else(|| invalid(line_number, "invalid vertex index"))?;
+                        let uv = match parts.next().filter(|index| !index.is_empty()) {
+                            Some(index) => index
+                                .parse()
+                                .ok()
+                                .and_then(|index| resolve(index, uvs.len()))
+                                .map(|index| Some(uvs[index]))
+                                .ok_or_else(|| {
+                                    invalid(line_number, "invalid texture coordinate index")
+                                })?,
+                            None => None,
+                        };
+                        let normal = match parts.next().filter(|index| !index.is_empty()) {
+                            Some(index) => index
+                                .parse()
+                                .ok()
+                                .and_then(|index| resolve(index, normals.len()))
+                                .map(|index| Some(normals[index]))
+                                .ok_or_else(|| invalid(line_number, "invalid normal index"))?,
+                            None => None,
+                        };
+                        Ok((vertex, uv, normal))
+                    })
+                    .collect::<io::Result<Vec<_>>>()?;
+                if corners.len() < 3 {
+                    return Err(invalid(line_number, "face needs at least three vertices"));
+                }
+                // Triangulate polygons as a fan around the first vertex
+                for pair in corners[1..].windows(2) {
+                    let [a, b, c] = [corners[0], pair[0], pair[1]];
+                    let triangle = Triangle::new([a.0, b.0, c.0], material);
+                    // Texture coordinates and smooth shading only apply when
+                    // every corner has them
+                    let triangle = match (a.1, b.1, c.1) {
+                        (Some(ta), Some(tb), Some(tc)) => triangle.with_uvs([ta, tb, tc]),
+                        _ => triangle,
+                    };
+                    triangles.push(match (a.2, b.2, c.2) {
+                        (Some(na), Some(nb), Some(nc)) => {
+                            triangle.with_vertex_normals([na, nb, nc])
+                        }
+                        _ => triangle,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(triangles)
+}
+
+fn load_obj(filename: &str, material: Material) -> io::Result<Vec<Triangle>> {
+    parse_obj(&std::fs::read_to_string(filename)?, material)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Interval {
+    min: f32,
+    max: f32,
+}
+
+impl Interval {
+    fn new(min: f32, max: f32) -> Self {
+        Self { min, max }
+    }
+
+    fn contains(&self, t: f32) -> bool {
+        self.min <= t && t < self.max
+    }
+}
+
+struct Image {
+    width: u32,
+    height: u32,
+    pixels: Vec<Vector3<f32>>,
+}
+
+impl Image {
+    fn texel(&self, x: i64, y: i64) -> Vector3<f32> {
+        // Textures repeat, so any texel index wraps around onto the image
+        let x = x.rem_euclid(self.width as i64) as u32;
+        let y = y.rem_euclid(self.height as i64) as u32;
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    fn sample(&self, uv: Vector2<f32>, filter: TextureFilter) -> Vector3<f32> {
+        // The texture at a texture coordinate, with v running up from the
+        // bottom row as in OBJ files
+        let x = uv.x * self.width as f32;
+        let y = (1.0 - uv.y) * self.height as f32;
+        match filter {
+            TextureFilter::Nearest => self.texel(x.floor() as i64, y.floor() as i64),
+            TextureFilter::Bilinear => {
+                // Between the centers of the four nearest texels, which sit
+                // at half-integer positions
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (x0, y0) = (x.floor(), y.floor());
+                let (fx, fy) = (x - x0, y - y0);
+                let (x0, y0) = (x0 as i64, y0 as i64);
+                let top = self.texel(x0, y0).lerp(&self.texel(x0 + 1, y0), fx);
+                let bottom = self.texel(x0, y0 + 1).lerp(&self.texel(x0 + 1, y0 + 1), fx);
+                top.lerp(&bottom, fy)
+            }
+        }
+    }
+}
+
+// How textures are looked up between texel centers
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TextureFilter {
+    // The texel under the point, blocky when magnified
+    Nearest,
+    // Interpolated from the four nearest texels
+    Bilinear,
+}
+
+// A texture in Scene::textures, so materials can refer to one and stay Copy
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TextureId(usize);
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn header_fields(data: &[u8], count: usize, format: &str) -> io::Result<(Vec<String>, usize)> {
+    // Read whitespace separated header fields, skipping # comments, and return
+    // them with the position of the pixel data after them
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while fields.len() < count {
+        while pos < data.len() && (data[pos].is_ascii_whitespace() || data[pos] == b'#') {
+            if data[pos] == b'#' {
+                while pos < data.len() && data[pos] != b'\n' {
+                    pos += 1;
+                }
+            } else {
+                pos += 1;
+            }
+        }
+        let start = pos;
+        while pos < data.len() && !data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if start == pos {
+            return Err(invalid_data(&format!("truncated {} header", format)));
+        }
+        fields.push(String::from_utf8_lossy(&data[start..pos]).into_owned());
+    }
+    // A single whitespace byte separates the header from the pixel data
+    Ok((fields, pos + 1))
+}
+
+fn parse_ppm(data: &[u8]) -> io::Result<Image> {
+    let (fields, pos) = header_fields(data, 4, "PPM")?;
+    if fields[0] != "P6" {
+        return Err(invalid_data("only binary (P6) PPM images are supported"));
+    }
+    let parse = |field: &str| {
+        field
+            .parse::<u32>()
+            .map_err(|_| invalid_data("invalid PPM header value"))
+    };
+    let (width, height, max_value) = (parse(&fields[1])?, parse(&fields[2])?, parse(&fields[3])?);
+    if max_value == 0 || max_value > 255 {
+        return Err(invalid_data("only 8-bit PPM images are supported"));
+    }
+
+    // Sizes come from the file, so a bogus header must not overflow
+    let size = (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|pixels| pixels.checked_mul(3))
+        .ok_or_else(|| invalid_data("PPM image is too large"))?;
+    let bytes = data
+        .get(pos..)
+        .and_then(|pixels| pixels.get(..size))
+        .ok_or_else(|| invalid_data("truncated PPM pixel data"))?;
+    let pixels = bytes
+        .chunks(3)
+        .map(|rgb| Vector3::new(rgb[0] as f32, rgb[1] as f32, rgb[2] as f32) / max_value as f32)
+        .collect();
+    Ok(Image {
+        width,
+        height,
+        pixels,
+    })
+}
+
+fn load_ppm(filename: &str) -> io::Result<Image> {
+    parse_ppm(&std::fs::read(filename)?)
+}
+
+fn parse_pfm(data: &[u8]) -> io::Result<Image> {
+    let (fields, pos) = header_fields(data, 4, "PFM")?;
+    if fields[0] != "PF" {
+        return Err(invalid_data("only color (PF) PFM images are supported"));
+    }
+    let parse = |field: &str| {
+        field
+            .parse::<u32>()
+            .map_err(|_| invalid_data("invalid PFM header value"))
+    };
+    let (width, height) = (parse(&fields[1])?, parse(&fields[2])?);
+    // The sign of the scale gives the byte order, negative for little-endian
+    let little_endian = match fields[3].parse::<f32>() {
+        Ok(scale) if scale < 0.0 => true,
+        Ok(scale) if scale > 0.0 => false,
+        _ => return Err(invalid_data("invalid PFM scale")),
+    };
+    if width == 0 || height == 0 {
+        return Err(invalid_data("PFM image is empty"));
+    }
+
+    let row_size = (width as usize)
+        .checked_mul(12)
+        .ok_or_else(|| invalid_data("PFM image is too large"))?;
+    let size = row_size
+        .checked_mul(height as usize)
+        .ok_or_else(|| invalid_data("PFM image is too large"))?;
+    let bytes = data
+        .get(pos..)
+        .and_then(|pixels| pixels.get(..size))
+        .ok_or_else(|| invalid_data("truncated PFM pixel data"))?;
+    let channel = |bytes: &[u8]| {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if little_endian {
+            f32::from_le_bytes(bytes)
+        } else {
+            f32::from_be_bytes(bytes)
+        }
+    };
+    // PFM stores rows bottom to top
+    let pixels = bytes
+        .chunks(row_size)
+        .rev()
+        .flat_map(|row| row.chunks(12))
+        .map(|rgb| {
+            Vector3::new(
+                channel(&rgb[0..4]),
+                channel(&rgb[4..8]),
+                channel(&rgb[8..12]),
+            )
+        })
+        .collect();
+    Ok(Image {
+        width,
+        height,
+        pixels,
+    })
+}
+
+fn load_pfm(filename: &str) -> io::Result<Image> {
+    parse_pfm(&std::fs::read(filename)?)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BackplateFit {
+    // Scale each axis independently to cover the frame exactly
+    Stretch,
+    // Keep the aspect ratio and show the whole image, misses outside it see the sky
+    Fit,
+    // Keep the aspect ratio and cover the whole frame, cropping the image
+    Fill,
+}
+
+struct Backplate {
+    image: Image,
+    fit: BackplateFit,
+}
+
+impl Backplate {
+    fn new(image: Image, fit: BackplateFit) -> Self {
+        Self { image, fit }
+    }
+
+    fn pixel_for(&self, i: u32, j: u32, config: &RenderConfig) -> Option<Vector3<f32>> {
+        let (frame_w, frame_h) = (config.width as f32, config.height as f32);
+        let (image_w, image_h) = (self.image.width as f32, self.image.height as f32);
+        // Frame size of one image pixel along each axis
+        let (scale_x, scale_y) = match self.fit {
+            BackplateFit::Stretch => (frame_w / image_w, frame_h / image_h),
+            BackplateFit::Fit => {
+                let scale = f32::min(frame_w / image_w, frame_h / image_h);
+                (scale, scale)
+            }
+            BackplateFit::Fill => {
+                let scale = f32::max(frame_w / image_w, frame_h / image_h);
+                (scale, scale)
+            }
+        };
+        // Center the scaled image in the frame and map the pixel center back onto it
+        let x = (i as f32 + 0.5 - (frame_w - image_w * scale_x) / 2.0) / scale_x;
+        let y = (j as f32 + 0.5 - (frame_h - image_h * scale_y) / 2.0) / scale_y;
+        if x < 0.0 || y < 0.0 || x >= image_w || y >= image_h {
+            return None;
+        }
+        Some(self.image.pixels[(y as u32 * self.image.width + x as u32) as usize])
+    }
+}
+
+// What a ray that misses everything sees
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Background {
+    // The horizon to zenith gradient of the config
+    Sky,
+    Color(Vector3<f32>),
+    // The scene's environment map, see Scene::environment
+    Environment,
+}
+
+impl Background {
+    fn color(&self, direction: Vector3<f32>, scene: &Scene, config: &RenderConfig) -> Vector3<f32> {
+        match (self, &scene.environment) {
+            (Background::Sky, _) => sky(direction, config),
+            (Background::Color(color), _) => *color,
+            (Background::Environment, Some(environment)) => environment.radiance(direction),
+            (Background::Environment, None) => Vector3::zeros(),
+        }
+    }
+}
+
+// An equirectangular map of the light from every direction, wrapped around
+// the scene the way sphere_uv wraps a texture around a sphere, with +Y up.
+// Directions are importance sampled by how much light their texel adds
+struct Environment {
+    image: Image,
+    // Cumulative probabilities of picking each row, then a column within a
+    // row, each ending at 1
+    row_cdf: Vec<f32>,
+    column_cdf: Vec<f32>,
+    // Probability of picking each texel at all
+    texel_probability: Vec<f32>,
+}
+
+impl Environment {
+    fn new(image: Image) -> Self {
+        // Rows nearer the poles cover less solid angle, so each texel is
+        // weighted by its luminance times sin(theta). A map with no light at
+        // all is sampled by solid angle alone
+        let (width, height) = (image.width as usize, image.height as usize);
+        let sin_theta = |y: usize| (PI * (y as f32 + 0.5) / height as f32).sin();
+        let mut weights: Vec<f32> = image
+            .pixels
+            .iter()
+            .enumerate()
+            .map(|(k, &pixel)| luminance(pixel).max(0.0) * sin_theta(k / width))
+            .collect();
+        if weights.iter().sum::<f32>() <= 0.0 {
+            weights = (0..width * height).map(|k| sin_theta(k / width)).collect();
+        }
+        let total: f32 = weights.iter().sum();
+        let mut column_cdf = Vec::with_capacity(width * height);
+        let mut row_cdf = Vec::with_capacity(height);
+        let mut rows_so_far = 0.0;
+        for row in weights.chunks(width) {
+            let row_total: f32 = row.iter().sum();
+            let mut so_far = 0.0;
+            for &weight in row {
+                so_far += weight;
+                // Rows never picked still need a usable distribution
+                column_cdf.push(match row_total {
+                    0.0 => 1.0,
+                    _ => so_far / row_total,
+                });
+            }
+            rows_so_far += row_total;
+            row_cdf.push(rows_so_far / total);
+        }
+        Self {
+            texel_probability: weights.iter().map(|weight| weight / total).collect(),
+            image,
+            row_cdf,
+            column_cdf,
+        }
+    }
+
+    fn radiance(&self, direction: Vector3<f32>) -> Vector3<f32> {
+        let (uv, _) = sphere_uv(direction.normalize());
+        self.image.sample(uv, TextureFilter::Nearest)
+    }
+
+    fn texel_index(&self, direction: Vector3<f32>) -> usize {
+        let (uv, _) = sphere_uv(direction.normalize());
+        let (width, height) = (self.image.width as usize, self.image.height as usize);
+        let x = ((uv.x * width as f32) as usize).min(width - 1);
+        let y = (((1.0 - uv.y) * height as f32) as usize).min(height - 1);
+        y * width + x
+    }
+
+    fn pdf(&self, direction: Vector3<f32>) -> f32 {
+        // Per unit solid angle: a texel spans 2pi/width by pi/height of
+        // longitude and polar angle, scaled by sin(theta)
+        let (width, height) = (self.image.width as f32, self.image.height as f32);
+        let sin_theta = direction.normalize().y.clamp(-1.0, 1.0).acos().sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        self.texel_probability[self.texel_index(direction)] * width * height
+            / (2.0 * PI * PI * sin_theta)
+    }
+
+    fn sample(&self, u: f32, v: f32) -> (Vector3<f32>, f32) {
+        // A row by the marginal distribution, then a column in it, then a
+        // uniform point within the texel
+        let width = self.image.width as usize;
+        let pick = |cdf: &[f32], u: f32| {
+            let index = cdf.partition_point(|&p| p <= u).min(cdf.len() - 1);
+            let below = if index == 0 { 0.0 } else { cdf[index - 1] };
+            let fraction = ((u - below) / (cdf[index] - below)).clamp(0.0, 1.0);
+            (index, if fraction.is_finite() { fraction } else { 0.5 })
+        };
+        let (y, fy) = pick(&self.row_cdf, v);
+        let (x, fx) = pick(&self.column_cdf[y * width..(y + 1) * width], u);
+        let theta = PI * (y as f32 + fy) / self.image.height as f32;
+        let phi = 2.0 * PI * (0.5 - (x as f32 + fx) / width as f32);
+        let direction = Vector3::new(
+            theta.sin() * phi.cos(),
+            theta.cos(),
+            theta.sin() * phi.sin(),
+        );
+        (direction, self.pdf(direction))
+    }
+}
+
+struct Scene {
+    spheres: Vec<Sphere>,
+    triangles: Vec<Triangle>,
+    // Which add_mesh call each triangle came from, meshes are single objects
+    triangle_meshes: Vec<usize>,
+    differences: Vec<SphereDifference>,
+    instances: Vec<Transformed>,
+    lights: Vec<Light>,
+    backplate: Option<Backplate>,
+    textures: Vec<Image>,
+    // What camera rays see where they miss, behind any backplate
+    background: Background,
+    // What reflected and refracted rays see instead, None for the same
+    reflection_background: Option<Background>,
+    environment: Option<Environment>,
+}
+
+impl Scene {
+    fn new(spheres: Vec<Sphere>, lights: Vec<Light>) -> Self {
+        Self {
+            spheres,
+            triangles: Vec::new(),
+            triangle_meshes: Vec::new(),
+            differences: Vec::new(),
+            instances: Vec::new(),
+            lights,
+            backplate: None,
+            textures: Vec::new(),
+            background: Background::Sky,
+            reflection_background: None,
+            environment: None,
+        }
+    }
+
+    fn spheres_demo() -> Self {
+        let chartreuse = Material::new(Vector3::new(0.5, 0.8, 0.3));
+        let red = Material::new(Vector3::new(1.0, 0.5, 0.5));
+        let spheres = vec![
+            Sphere::new(Vector3::new(2.0, 1.0, -16.0), 5.0, red),
+            Sphere::new(Vector3::new(2.0, 3.0, -11.0), 1.0, chartreuse),
+            Sphere::new(Vector3::new(-3.0, 0.0, -16.0), 2.0, chartreuse),
+        ];
+        let lights = vec![Light::new(Vector3::new(-20.0, 20.0, 20.0), 1.0).in_group("key")];
+        Self::new(spheres, lights)
+    }
+
+    fn sphere_grid(n: u32, spacing: f32) -> Self {
+        // An n x n x n block of spheres centred on the view axis, far enough
+        // back that the whole block fits the default field of view
+        let radius = spacing * 0.35;
+        let extent = (n.max(1) - 1) as f32 * spacing;
+        let center = Vector3::new(0.0, 0.0, -(extent * 1.5 + spacing * 4.0));
+        // Fade from cool to warm across the block, corner to opposite corner
+        let cool = Surface::new(Vector3::new(0.3, 0.5, 0.9));
+        let warm = Surface::new(Vector3::new(0.9, 0.5, 0.3));
+        let steps = (3 * (n.max(1) - 1)).max(1) as f32;
+        let mut spheres = Vec::with_capacity((n * n * n) as usize);
+        for z in 0..n {
+            for y in 0..n {
+                for x in 0..n {
+                    let cell = Vector3::new(x as f32, y as f32, z as f32);
+                    spheres.push(Sphere::new(
+                        center + cell * spacing - Vector3::repeat(extent / 2.0),
+                        radius,
+                        Material::blend(cool, warm, cell.sum() / steps),
+                    ));
+                }
+            }
+        }
+        let lights = vec![Light::new(Vector3::new(-20.0, 20.0, 20.0), 1.0).in_group("key")];
+        Self::new(spheres, lights)
+    }
+
+    fn carved_demo() -> Self {
+        // The default spheres, with a bite taken out of the large red one
+        // facing the camera so its hollow is visible
+        let mut scene = Self::spheres_demo();
+        let base = scene.spheres.remove(0);
+        let bite = Sphere::new(Vector3::new(3.5, 2.0, -11.5), 3.0, base.material);
+        scene.differences.push(SphereDifference::new(base, bite));
+        scene
+    }
+
+    fn instances_demo() -> Self {
+        // One unit sphere and one unit cube, each placed twice: stretched into
+        // ellipsoids, and turned and scaled into a block and a slab
+        let red = Material::new(Vector3::new(1.0, 0.5, 0.5));
+        let chartreuse = Material::new(Vector3::new(0.5, 0.8, 0.3));
+        let white = Material::new(Vector3::new(0.73, 0.73, 0.73));
+        let unit_sphere = |material| Sphere::new(Vector3::zeros(), 1.0, material);
+        let unit_cube = || box_triangles(Vector3::zeros(), Vector3::repeat(1.0), 0.0, white);
+        let lights = vec![Light::new(Vector3::new(-20.0, 20.0, 20.0), 1.0).in_group("key")];
+        let mut scene = Self::new(Vec::new(), lights);
+        scene.instances = vec![
+            Transformed::new(
+                unit_sphere(red),
+                placement(
+                    Vector3::new(-2.5, 1.0, -14.0),
+                    Vector3::new(0.0, 0.0, 0.4),
+                    Vector3::new(3.0, 1.2, 1.2),
+                ),
+            ),
+            Transformed::new(
+                unit_sphere(chartreuse),
+                placement(
+                    Vector3::new(3.0, 2.5, -12.0),
+                    Vector3::zeros(),
+                    Vector3::new(1.5, 0.5, 1.5),
+                ),
+            ),
+            Transformed::new(
+                unit_cube(),
+                placement(
+                    Vector3::new(2.0, -2.0, -11.0),
+                    Vector3::new(0.4, 0.6, 0.0),
+                    Vector3::repeat(1.8),
+                ),
+            ),
+            Transformed::new(
+                unit_cube(),
+                placement(
+                    Vector3::new(-2.0, -3.0, -12.0),
+                    Vector3::new(0.0, -0.3, 0.0),
+                    Vector3::new(4.0, 0.4, 2.0),
+                ),
+            ),
+        ];
+        scene
+    }
+
+    fn shadows_demo() -> Self {
+        // Spheres over a floor at different heights, so their shadows fall
+        // at different distances from the occluder
+        let floor = Material::new(Vector3::new(0.8, 0.8, 0.8));
+        let red = Material::new(Vector3::new(1.0, 0.5, 0.5));
+        let chartreuse = Material::new(Vector3::new(0.5, 0.8, 0.3));
+        let y = -2.0;
+        // Tinted glass lets the red through, for a red shadow
+        let glass = Material::Surface(
+            Surface::new(Vector3::new(0.9, 0.3, 0.3)).tinted(Vector3::new(0.9, 0.15, 0.1)),
+        );
+        let spheres = vec![
+            Sphere::new(Vector3::new(-2.5, y + 1.0, -10.0), 1.0, red),
+            Sphere::new(Vector3::new(2.5, y + 4.0, -12.0), 1.0, chartreuse),
+            Sphere::new(Vector3::new(0.5, y + 1.2, -8.0), 1.2, glass),
+        ];
+        let lights = vec![Light::new(Vector3::new(4.0, 20.0, -4.0), 1.0).in_group("key")];
+        let mut scene = Self::new(spheres, lights);
+        scene.add_mesh(
+            quad_facing(
+                [
+                    Vector3::new(-12.0, y, -2.0),
+                    Vector3::new(12.0, y, -2.0),
+                    Vector3::new(12.0, y, -30.0),
+                    Vector3::new(-12.0, y, -30.0),
+                ],
+                Vector3::new(0.0, 0.0, -10.0),
+                floor,
+            )
+            .into(),
+        );
+        scene
+    }
+
+    fn materials_demo() -> Self {
+        // A gold ball, a glass ball and a brushed one on a checkered floor,
+        // which the first two reflect and refract
+        let gold = Material::Surface(
+            Surface::new(Vector3::new(1.0, 0.78, 0.34))
+                .metal()
+                .rough(0.15),
+        );
+        let glass = Material::Surface(
+            Surface::new(Vector3::repeat(1.0))
+                .dielectric(1.5)
+                .with_dispersion(0.04)
+                .tinted(Vector3::repeat(0.9)),
+        );
+        // Brushed around its equator, for a highlight drawn out sideways
+        let red = Material::Surface(
+            Surface::new(Vector3::new(1.0, 0.5, 0.5))
+                .shiny(0.4, 80.0)
+                .brushed(0.3, 0.05),
+        );
+        let y = -2.0;
+        let spheres = vec![
+            Sphere::new(Vector3::new(-3.0, y + 1.5, -12.0), 1.5, gold),
+            Sphere::new(Vector3::new(0.5, y + 1.5, -10.0), 1.5, glass),
+            Sphere::new(Vector3::new(3.5, y + 1.5, -14.0), 1.5, red),
+        ];
+        let lights = vec![Light::new(Vector3::new(-20.0, 20.0, 20.0), 1.0).in_group("key")];
+        let mut scene = Self::new(spheres, lights);
+        let squares = 12;
+        let checker = scene.add_texture(Image {
+            width: squares,
+            height: squares,
+            pixels: (0..squares * squares)
+                .map(|k| match (k % squares + k / squares) % 2 {
+                    0 => Vector3::repeat(0.9),
+                    _ => Vector3::repeat(0.2),
+                })
+                .collect(),
+        });
+        scene.add_mesh(
+            quad_facing(
+                [
+                    Vector3::new(-12.0, y, -2.0),
+                    Vector3::new(12.0, y, -2.0),
+                    Vector3::new(12.0, y, -30.0),
+                    Vector3::new(-12.0, y, -30.0),
+                ],
+                Vector3::new(0.0, 0.0, -10.0),
+                Material::new(Vector3::repeat(1.0)).with_texture(checker),
+            )
+            .into(),
+        );
+        scene
+    }
+
+    fn cornell_box() -> Self {
+        let white = Material::new(Vector3::new(0.73, 0.73, 0.73));
+        let red = Material::new(Vector3::new(0.65, 0.05, 0.05));
+        let green = Material::new(Vector3::new(0.12, 0.45, 0.15));
+
+        // A 5.5 unit room with its open side towards the camera at the origin
+        let (half, near, far) = (2.75, -5.0, -10.5);
+        let inside = Vector3::new(0.0, 0.0, (near + far) / 2.0);
+        let corner = |x: f32, y: f32, z: f32| Vector3::new(x * half, y * half, z);
+        let walls = [
+            // Left, right, floor, ceiling and back
+            (
+                [
+                    corner(-1.0, -1.0, near),
+                    corner(-1.0, 1.0, near),
+                    corner(-1.0, 1.0, far),
+                    corner(-1.0, -1.0, far),
+                ],
+                red,
+            ),
+            (
+                [
+                    corner(1.0, -1.0, near),
+                    corner(1.0, 1.0, near),
+                    corner(1.0, 1.0, far),
+                    corner(1.0, -1.0, far),
+                ],
+                green,
+            ),
+            (
+                [
+                    corner(-1.0, -1.0, near),
+                    corner(1.0, -1.0, near),
+                    corner(1.0, -1.0, far),
+                    corner(-1.0, -1.0, far),
+                ],
+                white,
+            ),
+            (
+                [
+                    corner(-1.0, 1.0, near),
+                    corner(1.0, 1.0, near),
+                    corner(1.0, 1.0, far),
+                    corner(-1.0, 1.0, far),
+                ],
+                white,
+            ),
+            (
+                [
+                    corner(-1.0, -1.0, far),
+                    corner(1.0, -1.0, far),
+                    corner(1.0, 1.0, far),
+                    corner(-1.0, 1.0, far),
+                ],
+                white,
+            ),
+        ];
+
+        let mut scene = Self::new(
+            Vec::new(),
+            vec![
+                Light::new(Vector3::new(0.0, half - 0.1, (near + far) / 2.0), 1.0)
+                    .in_group("ceiling"),
+            ],
+        );
+        for (corners, material) in walls {
+            scene.add_mesh(quad_facing(corners, inside, material).into());
+        }
+        // An unlit panel just under the ceiling stands in for the area light
+        let (panel, y) = (0.65, half - 0.01);
+        let z = (near + far) / 2.0;
+        scene.add_mesh(
+            quad_facing(
+                [
+                    Vector3::new(-panel, y, z - panel),
+                    Vector3::new(panel, y, z - panel),
+                    Vector3::new(panel, y, z + panel),
+                    Vector3::new(-panel, y, z + panel),
+                ],
+                inside,
+                Material::new(Vector3::repeat(1.0)).unlit(),
+            )
+            .into(),
+        );
+        // A short box front right and a tall box back left, turned towards each other
+        scene.add_mesh(box_triangles(
+            Vector3::new(0.9, -half + 0.825, -6.9),
+            Vector3::new(1.65, 1.65, 1.65),
+            -0.3,
+            white,
+        ));
+        scene.add_mesh(box_triangles(
+            Vector3::new(-0.9, -half + 1.65, -8.3),
+            Vector3::new(1.65, 3.3, 1.65),
+            0.3,
+            white,
+        ));
+        scene
+    }
+
+    fn add_mesh(&mut self, triangles: Vec<Triangle>) {
+        // An empty mesh would be an object nothing can hit, so it adds none
+        if triangles.is_empty() {
+            return;
+        }
+        let mesh = self.mesh_count();
+        self.triangle_meshes
+            .extend(std::iter::repeat_n(mesh, triangles.len()));
+        self.triangles.extend(triangles);
+    }
+
+    fn add_texture(&mut self, image: Image) -> TextureId {
+        self.textures.push(image);
+        TextureId(self.textures.len() - 1)
+    }
+
+    fn texture(&self, texture: TextureId) -> &Image {
+        &self.textures[texture.0]
+    }
+
+    fn reflection_background(&self) -> Background {
+        self.reflection_background.unwrap_or(self.background)
+    }
+
+    fn primitive_count(&self) -> usize {
+        let instances: usize = self.instances.iter().map(Hittable::primitive_count).sum();
+        self.spheres.len() + self.triangles.len() + self.differences.len() + instances
+    }
+
+    fn meshes(&self) -> impl Iterator<Item = &[Triangle]> {
+        // The triangles of a mesh are contiguous, added by one add_mesh call
+        let mut start = 0;
+        self.triangle_meshes
+            .chunk_by(|a, b| a == b)
+            .map(move |mesh| {
+                let triangles = &self.triangles[start..start + mesh.len()];
+                start += mesh.len();
+                triangles
+            })
+    }
+
+    fn mesh_count(&self) -> usize {
+        self.triangle_meshes.last().map_or(0, |&mesh| mesh + 1)
+    }
+
+    // Objects are numbered spheres first, then meshes, differences and instances
+    fn object_count(&self) -> usize {
+        self.spheres.len() + self.mesh_count() + self.differences.len() + self.instances.len()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Intersection {
+    point: Vector3<f32>,
+    distance: f32,
+    // Shading normal, turned to face the incoming ray on two-sided materials
+    normal: Vector3<f32>,
+    // Outward surface normal as the primitive defines it, never flipped
+    geometric_normal: Vector3<f32>,
+    // Whether the ray arrived on the outward side of the surface
+    front_face: bool,
+    material: Material,
+    // Barycentric coordinates of triangle hits, None for other primitives
+    barycentric: Option<Vector3<f32>>,
+    // Texture coordinates, and the direction in the surface along which u grows
+    uv: Vector2<f32>,
+    tangent: Vector3<f32>,
+    // Object the hit belongs to, see Scene::object_count, filled in by scene_intersect
+    object: usize,
+}
+
+impl Intersection {
+    fn new(
+        point: Vector3<f32>,
+        distance: f32,
+        direction: Vector3<f32>,
+        geometric_normal: Vector3<f32>,
+        shading_normal: Vector3<f32>,
+        material: Material,
+    ) -> Self {
+        // Primitives report outward normals and the flip happens only here,
+        // so back faces and hits from inside a sphere shade like front faces
+        // unless the material is single-sided
+        let front_face = direction.dot(&geometric_normal) < 0.0;
+        Self {
+            point,
+            distance,
+            normal: if front_face || !material.two_sided() {
+                shading_normal
+            } else {
+                -shading_normal
+            },
+            geometric_normal,
+            front_face,
+            material,
+            barycentric: None,
+            uv: Vector2::zeros(),
+            tangent: perpendicular(geometric_normal),
+            object: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RenderMode {
+    Shaded,
+    // Direct lighting only, every material treated as white
+    Lighting,
+    ObjectId,
+}
+
+// Largest sphere grid side, a million spheres, tested one by one per ray
+const SPHERE_GRID_MAX: u32 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DemoScene {
+    Spheres,
+    CornellBox,
+    Carved,
+    Instances,
+    Shadows,
+    Materials,
+    // Spheres per side of the grid
+    SphereGrid(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PreviewFilter {
+    Nearest,
+    Bilinear,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ToneMap {
+    None,
+    // c / (1 + c), compressing any radiance into [0, 1)
+    Reinhard,
+}
+
+// How colors outside the displayable [0, 1] cube are brought into it
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GamutMap {
+    // Clamp each channel on its own, which shifts the hue of bright colors
+    Clip,
+    // Keep luminance and hue, pulling the color towards white until it fits
+    Desaturate,
+}
+
+impl GamutMap {
+    fn apply(self, color: Vector3<f32>) -> Vector3<f32> {
+        let clipped = color.map(|c| c.clamp(0.0, 1.0));
+        if self == GamutMap::Clip {
+            return clipped;
+        }
+        let color = color.map(|c| c.max(0.0));
+        let brightest = color.max();
+        if brightest <= 1.0 {
+            return color;
+        }
+        let luminance = luminance(color);
+        if luminance >= 1.0 {
+            return Vector3::repeat(1.0);
+        }
+        // Scale the offset from the gray of equal luminance, so the largest
+        // channel lands exactly on 1
+        let gray = Vector3::repeat(luminance);
+        (gray + (color - gray) * ((1.0 - luminance) / (brightest - luminance)))
+            .map(|c| c.clamp(0.0, 1.0))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Quality {
+    Draft,
+    Medium,
+    Final,
+}
+
+// How the summed diffuse intensity of all lights is limited before it
+// multiplies the albedo
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DiffuseLimit {
+    // Physically additive, any number of lights can push past 1
+    Unclamped,
+    // Hard cap at the given intensity
+    Clamp(f32),
+    // Rolls off smoothly towards the given intensity, never quite reaching it
+    SoftKnee(f32),
+}
+
+impl DiffuseLimit {
+    fn apply(self, intensity: f32) -> f32 {
+        match self {
+            DiffuseLimit::Unclamped => intensity,
+            DiffuseLimit::Clamp(max) => intensity.min(max),
+            // Slope 1 at zero like the unclamped sum, with max as the asymptote
+            DiffuseLimit::SoftKnee(max) => max * (1.0 - (-intensity / max).exp()),
+        }
+    }
+}
+
+// Auxiliary per-pixel outputs written next to the beauty image
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Aov {
+    Albedo,
+    Normal,
+    Depth,
+}
+
+impl Aov {
+    fn name(self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+        }
+    }
+}
+
+// Replaces cast_ray for primary rays, given the ray origin and direction,
+// the nearest hit if there was one, and the scene
+type Shader =
+    dyn Fn(Vector3<f32>, Vector3<f32>, Option<&Intersection>, &Scene) -> Vector3<f32> + Send + Sync;
+
+#[derive(Clone)]
+struct RenderConfig {
+    width: u32,
+    height: u32,
+    fov: f32,
+    // Camera bank angle in radians, counterclockwise about the view axis
+    roll: f32,
+    // Lens radius for depth of field, 0 for a pinhole, and the distance along
+    // the view axis that stays sharp
+    aperture: f32,
+    focus_distance: f32,
+    // How far the camera travels while the shutter is open, centered on
+    // where it stands
+    camera_motion: Vector3<f32>,
+    // Up for the camera, the ambient hemisphere and the sky, +Y or +Z
+    world_up: Vector3<f32>,
+    filename: String,
+    ambient_sky: Vector3<f32>,
+    ambient_ground: Vector3<f32>,
+    fog_color: Vector3<f32>,
+    fog_density: f32,
+    sky_horizon: Vector3<f32>,
+    sky_zenith: Vector3<f32>,
+    legacy_background: bool,
+    // Backgrounds for the scene, see Scene::background
+    background: Background,
+    reflection_background: Option<Background>,
+    // PFM image for Background::Environment
+    environment: Option<String>,
+    mode: RenderMode,
+    edge_aa: u32,
+    light_groups: HashMap<String, f32>,
+    // Lights that can contribute less than this are skipped while shading
+    light_cutoff: f32,
+    diffuse_limit: DiffuseLimit,
+    // Primary hits closer than near, measured along the ray, are clipped away
+    near: f32,
+    far: f32,
+    meshes: Vec<String>,
+    // A scene file to render instead of the demo scene, whose camera replaces
+    // the command line's, and one to save the scene to, see Scene::to_json_file
+    scene_file: Option<String>,
+    save_scene: Option<String>,
+    // Load meshes single-sided, so their backs aren't lit
+    single_sided: bool,
+    // PPM images for the meshes' diffuse texture and tangent-space normal map
+    texture: Option<String>,
+    normal_map: Option<String>,
+    texture_filter: TextureFilter,
+    // A PFM render of the scene with previous_meshes in place of meshes, to
+    // update by re-rendering only the pixels the change between them reaches
+    previous_image: Option<String>,
+    previous_meshes: Vec<String>,
+    timeout: Option<Duration>,
+    samples: u32,
+    // Per-pixel sample counts in output pixel order, overriding samples
+    sample_map: Option<Arc<[u32]>>,
+    seed: u64,
+    progressive: bool,
+    // Refine the image frame by frame for an interactive viewer, see Viewer
+    viewer: bool,
+    debug_pixels: Vec<(u32, u32)>,
+    backplate: Option<String>,
+    backplate_fit: BackplateFit,
+    stats: bool,
+    shader: Option<Arc<Shader>>,
+    threads: usize,
+    // Display transform stages, applied in this order after auto-exposure,
+    // with exposure in stops
+    exposure: f32,
+    tone_map: ToneMap,
+    gamut: GamutMap,
+    gamma: f32,
+    dither: bool,
+    demo: DemoScene,
+    // Render at 1/preview_scale of the output size and upsample the result
+    preview_scale: u32,
+    preview_filter: PreviewFilter,
+    // Show pixels with NaN or infinite radiance in magenta instead of black
+    flag_nonfinite: bool,
+    // Scale the image so its log-average luminance lands on exposure_target
+    auto_exposure: bool,
+    exposure_target: f32,
+    // Silhouette glow of rim_color * (1 - N.V)^rim_power, off while black
+    rim_color: Vector3<f32>,
+    rim_power: f32,
+    // Store rows bottom-up, for tools that put the pixel origin at the bottom left
+    flip_y: bool,
+    // Test every light for occluders with a shadow ray
+    shadows: bool,
+    // Penumbra width per unit of distance from receiver to occluder, 0 for hard shadows
+    shadow_softness: f32,
+    // Radius of the disc each light is sampled over, 0 for point lights
+    light_radius: f32,
+    // Shadow rays per light, spread over its disc. Independent of the pixel samples
+    shadow_samples: u32,
+    // Reflection and refraction rays a camera ray can spawn in a row
+    max_depth: u32,
+    // Rays averaged for each bounce off a rough surface. One keeps every
+    // surface sharp
+    glossy_samples: u32,
+    // Paths whose throughput falls below this stop early, within max_depth
+    min_throughput: f32,
+    // Rays traced for the light diffuse surfaces get from the rest of the
+    // scene, once per path. 0 keeps the ambient approximation
+    indirect_samples: u32,
+    // Unnormalized Phong highlights, which can reflect more than the light
+    // arriving
+    classic_specular: bool,
+    // Trace three wavelengths per camera ray instead of RGB, see Spectrum
+    spectral: bool,
+    // Radius of the glowing spheres drawn at light positions, None to hide them
+    light_markers: Option<f32>,
+    // Shade and write PPM rows one at a time instead of buffering the image
+    stream: bool,
+    // PPM whose red channel scales samples per pixel, loaded into sample_map
+    sample_map_file: Option<String>,
+    aovs: Vec<Aov>,
+    // Also write a mask per object and one for the background
+    masks: bool,
+    // Print the render hash of the scene and config instead of rendering
+    print_hash: bool,
+    // JSON file recording how the render was made, written once it finishes
+    manifest: Option<String>,
+    // Darken triangle hits within this barycentric distance of an edge
+    wireframe: Option<f32>,
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        Self {
+            width: 256,
+            height: 256,
+            fov: PI / 3.0,
+            roll: 0.0,
+            world_up: Vector3::y(),
+            filename: "output.ppm".to_string(),
+            ambient_sky: Vector3::new(0.2, 0.2, 0.25),
+            ambient_ground: Vector3::new(0.05, 0.04, 0.03),
+            fog_color: Vector3::new(0.6, 0.6, 0.7),
+            fog_density: 0.0,
+            sky_horizon: Vector3::new(0.8, 0.85, 0.9),
+            sky_zenith: Vector3::new(0.2, 0.4, 0.8),
+            legacy_background: false,
+            background: Background::Sky,
+            reflection_background: None,
+            environment: None,
+            mode: RenderMode::Shaded,
+            edge_aa: 1,
+            light_groups: HashMap::new(),
+            light_cutoff: 0.0,
+            diffuse_limit: DiffuseLimit::Unclamped,
+            near: 1e-4,
+            far: 1000.0,
+            meshes: Vec::new(),
+            scene_file: None,
+            save_scene: None,
+            single_sided: false,
+            texture: None,
+            normal_map: None,
+            texture_filter: TextureFilter::Nearest,
+            previous_image: None,
+            previous_meshes: Vec::new(),
+            timeout: None,
+            samples: 1,
+            sample_map: None,
+            seed: 0,
+            progressive: false,
+            viewer: false,
+            debug_pixels: Vec::new(),
+            backplate: None,
+            backplate_fit: BackplateFit::Stretch,
+            stats: false,
+            shader: None,
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            exposure: 0.0,
+            tone_map: ToneMap::None,
+            gamut: GamutMap::Clip,
+            gamma: 1.0,
+            dither: false,
+            demo: DemoScene::Spheres,
+            preview_scale: 1,
+            preview_filter: PreviewFilter::Nearest,
+            flag_nonfinite: false,
+            auto_exposure: false,
+            exposure_target: 0.18,
+            rim_color: Vector3::zeros(),
+            rim_power: 4.0,
+            flip_y: false,
+            shadows: false,
+            shadow_softness: 0.0,
+            light_radius: 0.0,
+            aperture: 0.0,
+            focus_distance: 10.0,
+            camera_motion: Vector3::zeros(),
+            shadow_samples: 1,
+            max_depth: 5,
+            glossy_samples: 1,
+            min_throughput: 0.0,
+            indirect_samples: 0,
+            classic_specular: false,
+            spectral: false,
+            light_markers: None,
+            stream: false,
+            sample_map_file: None,
+            aovs: Vec::new(),
+            masks: false,
+            print_hash: false,
+            manifest: None,
+            wireframe: None,
+        }
+    }
+}
+
+impl RenderConfig {
+    fn preset(quality: Quality) -> Self {
+        // Draft previews at half size with no antialiasing, medium antialiases
+        // silhouettes only, final jitters many samples over every pixel
+        let (samples, edge_aa, preview_scale) = match quality {
+            Quality::Draft => (1, 1, 2),
+            Quality::Medium => (1, 3, 1),
+            Quality::Final => (16, 1, 1),
+        };
+        Self {
+            samples,
+            edge_aa,
+            preview_scale,
+            ..Self::default()
+        }
+    }
+
+    fn data_output(&self, filename: String) -> Self {
+        // The same view written to another file with every display transform
+        // off, for outputs that hold data rather than a picture
+        Self {
+            filename,
+            auto_exposure: false,
+            exposure: 0.0,
+            tone_map: ToneMap::None,
+            gamut: GamutMap::Clip,
+            gamma: 1.0,
+            dither: false,
+            ..self.clone()
+        }
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        // The half-angle tangent in camera_ray blows up at 0 and at or past PI
+        if !(self.fov > 0.0 && self.fov < PI) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "field of view must be between 0 and 180 degrees (exclusive), got {}",
+                    self.fov.to_degrees()
+                ),
+            ));
+        }
+        if !(self.gamma > 0.0 && self.gamma.is_finite()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("gamma must be positive, got {}", self.gamma),
+            ));
+        }
+        if !(self.near >= 0.0 && self.near < self.far) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "near clip must be at least 0 and less than the far clip, got {}",
+                    self.near
+                ),
+            ));
+        }
+        if !(self.exposure_target > 0.0 && self.exposure_target.is_finite()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "exposure target must be positive, got {}",
+                    self.exposure_target
+                ),
+            ));
+        }
+        // Anything that needs the whole image before the first row goes out
+        // can't be streamed
+        if self.stream
+            && (self.samples > 1
+                || self.sample_map_file.is_some()
+                || self.preview_scale > 1
+                || self.auto_exposure
+                || !self.filename.ends_with(".ppm"))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "streaming needs .ppm output, one sample and no preview or auto-exposure",
+            ));
+        }
+        // The previous image has exactly the radiance before display, but no
+        // alpha, so it can't go to PNG
+        if let Some(previous) = &self.previous_image {
+            if !previous.ends_with(".pfm")
+                || self.stream
+                || self.preview_scale > 1
+                || self.auto_exposure
+                || self.filename.ends_with(".png")
+                || self.filename == "-"
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "updating needs a .pfm previous image, no streaming, preview or \
+                     auto-exposure, and output other than .png or -",
+                ));
+            }
+        } else if !self.previous_meshes.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--previous-obj needs --previous-image",
+            ));
+        }
+        if (!self.aovs.is_empty() || self.masks) && self.filename == "-" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "AOVs and masks are written next to the output file and need a filename",
+            ));
+        }
+        if let DiffuseLimit::Clamp(max) | DiffuseLimit::SoftKnee(max) = self.diffuse_limit {
+            if !(max > 0.0 && max.is_finite()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("diffuse limit must be positive, got {}", max),
+                ));
+            }
+        }
+        if self.sample_map_file.is_some() && self.preview_scale > 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a sample map is given at output resolution and can't be used with a preview",
+            ));
+        }
+        // Pixel counts are computed in u32 all through the renderer
+        if !(1..=MAX_IMAGE_SIDE).contains(&self.width)
+            || !(1..=MAX_IMAGE_SIDE).contains(&self.height)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "image size must be between 1x1 and {}x{}, got {}x{}",
+                    MAX_IMAGE_SIDE, MAX_IMAGE_SIDE, self.width, self.height
+                ),
+            ));
+        }
+        if self.filename.ends_with(".csv")
+            && self.width as u64 * self.height as u64 > CSV_MAX_PIXELS as u64
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "CSV output is limited to {} pixels, got {}x{}",
+                    CSV_MAX_PIXELS, self.width, self.height
+                ),
+            ));
+        }
+        if !(self.shadow_softness >= 0.0 && self.shadow_softness.is_finite()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "shadow softness must be at least 0, got {}",
+                    self.shadow_softness
+                ),
+            ));
+        }
+        if !(self.light_radius >= 0.0 && self.light_radius.is_finite()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("light radius must be at least 0, got {}", self.light_radius),
+            ));
+        }
+        let environment_background = self.background == Background::Environment
+            || self.reflection_background == Some(Background::Environment);
+        if environment_background && self.environment.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "an environment background needs an --environment map",
+            ));
+        }
+        if !(self.aperture >= 0.0 && self.aperture.is_finite()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("aperture must be at least 0, got {}", self.aperture),
+            ));
+        }
+        if !(self.focus_distance > 0.0 && self.focus_distance.is_finite()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "focus distance must be greater than 0, got {}",
+                    self.focus_distance
+                ),
+            ));
+        }
+        if self.shadow_samples == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "shadow samples must be at least 1",
+            ));
+        }
+        if !(self.min_throughput >= 0.0 && self.min_throughput.is_finite()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "minimum throughput must be at least 0, got {}",
+                    self.min_throughput
+                ),
+            ));
+        }
+        if self.glossy_samples == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "glossy samples must be at least 1",
+            ));
+        }
+        if !(self.fog_density >= 0.0 && self.fog_density.is_finite()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("fog density must be at least 0, got {}", self.fog_density),
+            ));
+        }
+        if let DemoScene::SphereGrid(n) = self.demo {
+            if n > SPHERE_GRID_MAX {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "sphere grid is limited to {} per side, got {}",
+                        SPHERE_GRID_MAX, n
+                    ),
+                ));
+            }
+        }
+        #[cfg(not(feature = "viewer"))]
+        if self.viewer {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--viewer needs a build with the viewer feature",
+            ));
+        }
+        #[cfg(feature = "wasm")]
+        if self.timeout.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "timeouts need a watchdog thread, which the wasm build can't spawn",
+            ));
+        }
+        if self.preview_scale == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "preview scale must be at least 1",
+            ));
+        }
+        Ok(())
+    }
+
+    fn preview(&self) -> Option<Self> {
+        // The same view at the reduced internal resolution, None at full size
+        if self.preview_scale <= 1 {
+            return None;
+        }
+        Some(Self {
+            width: self.width.div_ceil(self.preview_scale),
+            height: self.height.div_ceil(self.preview_scale),
+            ..self.clone()
+        })
+    }
+
+    fn camera_basis(&self) -> (Vector3<f32>, Vector3<f32>, Vector3<f32>) {
+        // Right is world X, forward is level and perpendicular to it: -Z when
+        // +Y is up and +Y when +Z is up. Roll then turns right and up about forward
+        let up = self.world_up;
+        let right = (Vector3::x() - up * up.x).normalize();
+        let forward = up.cross(&right);
+        let (sin, cos) = self.roll.sin_cos();
+        (right * cos + up * sin, up * cos - right * sin, forward)
+    }
+
+    fn pixel_samples(&self, i: u32, j: u32) -> u32 {
+        // The map is in output rows, which run bottom-up when flipped
+        let Some(map) = &self.sample_map else {
+            return self.samples;
+        };
+        let row = if self.flip_y { self.height - 1 - j } else { j };
+        map[(row * self.width + i) as usize]
+    }
+
+    fn max_samples(&self) -> u32 {
+        match &self.sample_map {
+            Some(map) => map.iter().copied().max().unwrap_or(1),
+            None => self.samples,
+        }
+    }
+
+    fn primary_range(&self) -> Interval {
+        Interval::new(self.near, self.far)
+    }
+
+    fn light_contributes(&self, light: &Light) -> bool {
+        // Lights have no falloff and N.L is at most 1, so the multiplied
+        // intensity bounds what a light can add anywhere in the scene. Lights
+        // at or below zero never add anything, whatever the cutoff
+        let intensity = light.intensity * self.light_multiplier(light);
+        intensity > 0.0 && intensity >= self.light_cutoff
+    }
+
+    fn light_multiplier(&self, light: &Light) -> f32 {
+        // Lights outside a group, or in a group without a multiplier, are unscaled
+        light
+            .group
+            .as_ref()
+            .and_then(|group| self.light_groups.get(group))
+            .copied()
+            .unwrap_or(1.0)
+    }
+}
+
+fn normal_shader(
+    _origin: Vector3<f32>,
+    _direction: Vector3<f32>,
+    intersection: Option<&Intersection>,
+    _scene: &Scene,
+) -> Vector3<f32> {
+    // Map the surface normal from [-1, 1] into displayable [0, 1] colors
+    match intersection {
+        Some(intersection) => (intersection.normal + Vector3::repeat(1.0)) / 2.0,
+        None => Vector3::zeros(),
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(arg: &str, value: &str) -> io::Result<T> {
+    value.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid value for {}: {}", arg, value),
+        )
+    })
+}
+
+fn parse_components(arg: &str, value: &str, names: &str) -> io::Result<Vector3<f32>> {
+    let components = value
+        .split(',')
+        .map(|component| parse_value(arg, component))
+        .collect::<io::Result<Vec<f32>>>()?;
+    match components[..] {
+        [x, y, z] => Ok(Vector3::new(x, y, z)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("expected {} for {}, got {}", names, arg, value),
+        )),
+    }
+}
+
+fn parse_color(arg: &str, value: &str) -> io::Result<Vector3<f32>> {
+    parse_components(arg, value, "R,G,B")
+}
+
+fn parse_vector(arg: &str, value: &str) -> io::Result<Vector3<f32>> {
+    parse_components(arg, value, "X,Y,Z")
+}
+
+fn parse_background(arg: &str, value: &str) -> io::Result<Background> {
+    match value {
+        "sky" => Ok(Background::Sky),
+        "environment" => Ok(Background::Environment),
+        color => Ok(Background::Color(parse_color(arg, color)?)),
+    }
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> io::Result<RenderConfig> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let mut config = RenderConfig::default();
+    // Full-frame 35mm sensor height, only used to turn a focal length into a FOV
+    let mut sensor_height: f32 = 24.0;
+    let mut focal_length: Option<f32> = None;
+    // Knobs a quality preset sets, unless they are given explicitly
+    let mut quality: Option<Quality> = None;
+    let mut samples: Option<u32> = None;
+    let mut edge_aa: Option<u32> = None;
+    let mut preview_scale: Option<u32> = None;
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| invalid(format!("missing value for {}", arg)))
+        };
+        match arg.as_str() {
+            "--shader" => {
+                config.shader = match value()?.as_str() {
+                    "default" => None,
+                    "normals" => Some(Arc::new(normal_shader)),
+                    other => return Err(invalid(format!("unknown shader: {}", other))),
+                }
+            }
+            "--scene" => {
+                config.demo = match value()?.as_str() {
+                    "spheres" => DemoScene::Spheres,
+                    "cornell" => DemoScene::CornellBox,
+                    "carved" => DemoScene::Carved,
+                    "instances" => DemoScene::Instances,
+                    "shadows" => DemoScene::Shadows,
+                    "materials" => DemoScene::Materials,
+                    "grid" => DemoScene::SphereGrid(4),
+                    other if other.starts_with("grid:") => {
+                        DemoScene::SphereGrid(parse_value(&arg, &other["grid:".len()..])?)
+                    }
+                    other => return Err(invalid(format!("unknown scene: {}", other))),
+                }
+            }
+            "--exposure" => config.exposure = parse_value(&arg, &value()?)?,
+            "--tone-map" => {
+                config.tone_map = match value()?.as_str() {
+                    "none" => ToneMap::None,
+                    "reinhard" => ToneMap::Reinhard,
+                    other => return Err(invalid(format!("unknown tone map: {}", other))),
+                }
+            }
+            "--dither" => config.dither = true,
+            "--gamut" => {
+                config.gamut = match value()?.as_str() {
+                    "clip" => GamutMap::Clip,
+                    "desaturate" => GamutMap::Desaturate,
+                    other => return Err(invalid(format!("unknown gamut mapping: {}", other))),
+                }
+            }
+            "--gamma" => config.gamma = parse_value(&arg, &value()?)?,
+            "--preview" => preview_scale = Some(parse_value(&arg, &value()?)?),
+            "--preview-filter" => {
+                config.preview_filter = match value()?.as_str() {
+                    "nearest" => PreviewFilter::Nearest,
+                    "bilinear" => PreviewFilter::Bilinear,
+                    other => return Err(invalid(format!("unknown preview filter: {}", other))),
+                }
+            }
+            "--threads" => config.threads = parse_value(&arg, &value()?)?,
+            "--fov" => config.fov = parse_value::<f32>(&arg, &value()?)?.to_radians(),
+            "--focal-length" => focal_length = Some(parse_value(&arg, &value()?)?),
+            "--sensor-height" => sensor_height = parse_value(&arg, &value()?)?,
+            "--world-up" => {
+                config.world_up = match value()?.as_str() {
+                    "y" => Vector3::y(),
+                    "z" => Vector3::z(),
+                    other => return Err(invalid(format!("unknown up axis: {}", other))),
+                }
+            }
+            "--near" => config.near = parse_value(&arg, &value()?)?,
+            "--roll" => config.roll = parse_value::<f32>(&arg, &value()?)?.to_radians(),
+            "--mode" => {
+                config.mode = match value()?.as_str() {
+                    "shaded" => RenderMode::Shaded,
+                    "lighting" => RenderMode::Lighting,
+                    "id" => RenderMode::ObjectId,
+                    other => return Err(invalid(format!("unknown render mode: {}", other))),
+                }
+            }
+            "--output" => config.filename = value()?,
+            "--width" => config.width = parse_value(&arg, &value()?)?,
+            "--height" => config.height = parse_value(&arg, &value()?)?,
+            "--edge-aa" => edge_aa = Some(parse_value(&arg, &value()?)?),
+            "--obj" => config.meshes.push(value()?),
+            "--scene-file" => config.scene_file = Some(value()?),
+            "--save-scene" => config.save_scene = Some(value()?),
+            "--single-sided" => config.single_sided = true,
+            "--texture" => config.texture = Some(value()?),
+            "--normal-map" => config.normal_map = Some(value()?),
+            "--texture-filter" => {
+                config.texture_filter = match value()?.as_str() {
+                    "nearest" => TextureFilter::Nearest,
+                    "bilinear" => TextureFilter::Bilinear,
+                    other => return Err(invalid(format!("unknown texture filter: {}", other))),
+                }
+            }
+            "--previous-image" => config.previous_image = Some(value()?),
+            "--previous-obj" => config.previous_meshes.push(value()?),
+            "--samples" => samples = Some(parse_value(&arg, &value()?)?),
+            "--sample-map" => config.sample_map_file = Some(value()?),
+            "--seed" => config.seed = parse_value(&arg, &value()?)?,
+            "--progressive" => config.progressive = true,
+            "--viewer" => config.viewer = true,
+            "--stats" => config.stats = true,
+            "--flag-nonfinite" => config.flag_nonfinite = true,
+            "--auto-exposure" => config.auto_exposure = true,
+            "--flip-y" => config.flip_y = true,
+            "--shadows" => config.shadows = true,
+            "--shadow-softness" => config.shadow_softness = parse_value(&arg, &value()?)?,
+            "--light-radius" => config.light_radius = parse_value(&arg, &value()?)?,
+            "--aperture" => config.aperture = parse_value(&arg, &value()?)?,
+            "--focus-distance" => config.focus_distance = parse_value(&arg, &value()?)?,
+            "--camera-motion" => config.camera_motion = parse_vector(&arg, &value()?)?,
+            "--shadow-samples" => config.shadow_samples = parse_value(&arg, &value()?)?,
+            "--max-depth" => config.max_depth = parse_value(&arg, &value()?)?,
+            "--glossy-samples" => config.glossy_samples = parse_value(&arg, &value()?)?,
+            "--min-throughput" => config.min_throughput = parse_value(&arg, &value()?)?,
+            "--indirect-samples" => config.indirect_samples = parse_value(&arg, &value()?)?,
+            "--classic-specular" => config.classic_specular = true,
+            "--spectral" => config.spectral = true,
+            "--wireframe" => config.wireframe = Some(parse_value(&arg, &value()?)?),
+            "--quality" => {
+                quality = Some(match value()?.as_str() {
+                    "draft" => Quality::Draft,
+                    "medium" => Quality::Medium,
+                    "final" => Quality::Final,
+                    other => return Err(invalid(format!("unknown quality preset: {}", other))),
+                });
+            }
+            "--stream" => config.stream = true,
+            "--masks" => config.masks = true,
+            "--hash" => config.print_hash = true,
+            "--manifest" => config.manifest = Some(value()?),
+            "--aov" => {
+                let aov = match value()?.as_str() {
+                    "albedo" => Aov::Albedo,
+                    "normal" => Aov::Normal,
+                    "depth" => Aov::Depth,
+                    other => return Err(invalid(format!("unknown AOV: {}", other))),
+                };
+                config.aovs.push(aov);
+            }
+            "--diffuse-clamp" => {
+                config.diffuse_limit = DiffuseLimit::Clamp(parse_value(&arg, &value()?)?)
+            }
+            "--diffuse-knee" => {
+                config.diffuse_limit = DiffuseLimit::SoftKnee(parse_value(&arg, &value()?)?)
+            }
+            "--light-cutoff" => config.light_cutoff = parse_value(&arg, &value()?)?,
+            "--light-markers" => config.light_markers = Some(parse_value(&arg, &value()?)?),
+            "--legacy-background" => config.legacy_background = true,
+            "--background" => config.background = parse_background(&arg, &value()?)?,
+            "--reflection-background" => {
+                config.reflection_background = Some(parse_background(&arg, &value()?)?)
+            }
+            "--sky-horizon" => config.sky_horizon = parse_color(&arg, &value()?)?,
+            "--sky-zenith" => config.sky_zenith = parse_color(&arg, &value()?)?,
+            "--fog-density" => config.fog_density = parse_value(&arg, &value()?)?,
+            "--fog-color" => config.fog_color = parse_color(&arg, &value()?)?,
+            "--ambient-sky" => config.ambient_sky = parse_color(&arg, &value()?)?,
+            "--ambient-ground" => config.ambient_ground = parse_color(&arg, &value()?)?,
+            "--rim" => config.rim_color = parse_color(&arg, &value()?)?,
+            "--rim-power" => config.rim_power = parse_value(&arg, &value()?)?,
+            "--exposure-target" => config.exposure_target = parse_value(&arg, &value()?)?,
+            "--backplate" => config.backplate = Some(value()?),
+            "--environment" => config.environment = Some(value()?),
+            "--backplate-fit" => {
+                config.backplate_fit = match value()?.as_str() {
+                    "stretch" => BackplateFit::Stretch,
+                    "fit" => BackplateFit::Fit,
+                    "fill" => BackplateFit::Fill,
+                    other => return Err(invalid(format!("unknown backplate fit: {}", other))),
+                }
+            }
+            "--debug-pixel" => {
+                let value = value()?;
+                let (x, y) = value
+                    .split_once(',')
+                    .ok_or_else(|| invalid(format!("expected X,Y for {}", arg)))?;
+                let pixel = (parse_value(&arg, x)?, parse_value(&arg, y)?);
+                config.debug_pixels.push(pixel);
+            }
+            "--timeout" => {
+                let seconds: f32 = parse_value(&arg, &value()?)?;
+                config.timeout = Some(
+                    Duration::try_from_secs_f32(seconds)
+                        .map_err(|_| invalid(format!("invalid value for {}: {}", arg, seconds)))?,
+                );
+            }
+            "--light-group" => {
+                let value = value()?;
+                let (group, multiplier) = value
+                    .split_once('=')
+                    .ok_or_else(|| invalid(format!("expected GROUP=MULTIPLIER for {}", arg)))?;
+                let multiplier = parse_value(&arg, multiplier)?;
+                config.light_groups.insert(group.to_string(), multiplier);
+            }
+            _ => return Err(invalid(format!("unknown argument: {}", arg))),
+        }
+    }
+    // Explicit flags take over from --quality, whichever order they came in
+    let preset = quality.map_or_else(RenderConfig::default, RenderConfig::preset);
+    config.samples = samples.unwrap_or(preset.samples);
+    config.edge_aa = edge_aa.unwrap_or(preset.edge_aa);
+    config.preview_scale = preview_scale.unwrap_or(preset.preview_scale);
+    // A focal length takes over from --fov, whichever order they came in
+    if let Some(focal_length) = focal_length {
+        if !(focal_length > 0.0 && sensor_height > 0.0) {
+            return Err(invalid(format!(
+                "focal length and sensor height must be positive, got {}mm and {}mm",
+                focal_length, sensor_height
+            )));
+        }
+        config.fov = fov_for_focal_length(focal_length, sensor_height);
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+fn fov_for_focal_length(focal_length: f32, sensor_height: f32) -> f32 {
+    // Vertical field of view of a pinhole camera, the sensor spans the image height
+    2.0 * (sensor_height / (2.0 * focal_length)).atan()
+}
+
+fn hemisphere_ambient(normal: Vector3<f32>, config: &RenderConfig) -> Vector3<f32> {
+    // Blend from the ground color (facing down) to the sky color (facing up)
+    let t = 0.5 * (normal.dot(&config.world_up) + 1.0);
+    config.ambient_ground.lerp(&config.ambient_sky, t)
+}
+
+fn apply_fog(
+    color: Vector3<f32>,
+    distance: f32,
+    fog_color: Vector3<f32>,
+    config: &RenderConfig,
+) -> Vector3<f32> {
+    // Exponential falloff, so a density of zero leaves the color untouched
+    let amount = 1.0 - (-config.fog_density * distance).exp();
+    color.lerp(&fog_color, amount)
+}
+
+fn background(
+    direction: Vector3<f32>,
+    i: u32,
+    j: u32,
+    scene: &Scene,
+    config: &RenderConfig,
+) -> Vector3<f32> {
+    if let Some(color) = scene
+        .backplate
+        .as_ref()
+        .and_then(|backplate| backplate.pixel_for(i, j, config))
+    {
+        return color;
+    }
+    if config.legacy_background {
+        // Gradient over the pixel indices, independent of where the ray points
+        let (width, height) = (config.width, config.height);
+        return Vector3::new(
+            j as f32 / height as f32,
+            i as f32 / width as f32,
+            (i + j) as f32 / (height + width) as f32,
+        );
+    }
+    scene.background.color(direction, scene, config)
+}
+
+fn sky(direction: Vector3<f32>, config: &RenderConfig) -> Vector3<f32> {
+    // Elevation above the horizon is the component along world up
+    let t = direction.normalize().dot(&config.world_up).clamp(0.0, 1.0);
+    config.sky_horizon.lerp(&config.sky_zenith, t)
+}
+
+fn camera_ray(px: f32, py: f32, config: &RenderConfig) -> (Vector3<f32>, Vector3<f32>) {
+    // Map a point in pixel space (pixel centers at +0.5) onto the image plane
+    // one unit along the camera's forward axis
+    let (width, height) = (config.width as f32, config.height as f32);
+    let half_fov_tan = (config.fov / 2.0).tan();
+    let x = (2.0 * px / width - 1.0) * half_fov_tan * (width / height);
+    let y = -(2.0 * py / height - 1.0) * half_fov_tan;
+    let (right, up, forward) = config.camera_basis();
+    (Vector3::zeros(), (right * x + up * y + forward).normalize())
+}
+
+// Where and when one primary ray samples the camera: a point in pixel space,
+// one on the unit lens disc and a moment in the shutter interval, from 0 to 1.
+// Spectral renders also take the first wavelength from it, see cast_ray
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct CameraSample {
+    pixel: Vector2<f32>,
+    lens: Vector2<f32>,
+    time: f32,
+    wavelength: f32,
+}
+
+impl CameraSample {
+    // Through the lens center at mid-shutter, where the camera is a pinhole
+    // standing still
+    fn at(px: f32, py: f32) -> Self {
+        Self {
+            pixel: Vector2::new(px, py),
+            lens: Vector2::zeros(),
+            time: 0.5,
+            wavelength: 0.5,
+        }
+    }
+}
+
+// Generators of Roberts' R6 sequence, powers of the inverse of the number
+// whose seventh power is itself plus 1. Successive points fill the unit cube
+// evenly in every dimension and every pair of them
+const R6_RATIO: f64 = 1.112_775_684_278_705_5;
+
+fn camera_sample(config: &RenderConfig, i: u32, j: u32, sample: u32, count: u32) -> CameraSample {
+    // Pixel jitter, lens position, shutter time and wavelength share one
+    // budget: sample k of a pixel is point k of the R6 sequence, shifted by a
+    // random offset per pixel so neighbours don't repeat one pattern. A lone
+    // sample sits at the center of them all, as in the one-sample renders
+    if count <= 1 {
+        return CameraSample::at(i as f32 + 0.5, j as f32 + 0.5);
+    }
+    let [jx, jy, lu, lv, time, wavelength] = std::array::from_fn(|d| {
+        let alpha = R6_RATIO.powi(-(d as i32 + 1));
+        let point = (0.5 + sample as f64 * alpha).fract() as f32;
+        (point + sample_random(config.seed, i, j, 0, d as u32)).fract()
+    });
+    // Even area on the disc, so stratified points stay stratified
+    let (sin, cos) = (2.0 * PI * lv).sin_cos();
+    CameraSample {
+        pixel: Vector2::new(i as f32 + jx, j as f32 + jy),
+        lens: Vector2::new(cos, sin) * lu.sqrt(),
+        time,
+        wavelength,
+    }
+}
+
+fn lens_ray(sample: &CameraSample, config: &RenderConfig) -> (Vector3<f32>, Vector3<f32>) {
+    // The pinhole ray, moved along with the camera over the shutter and
+    // bent through a point on the lens towards where it meets the plane of
+    // focus. The lens center and mid-shutter give the pinhole ray exactly
+    let (origin, direction) = camera_ray(sample.pixel.x, sample.pixel.y, config);
+    let origin = origin + config.camera_motion * (sample.time - 0.5);
+    if config.aperture == 0.0 || sample.lens == Vector2::zeros() {
+        return (origin, direction);
+    }
+    let (right, up, forward) = config.camera_basis();
+    let focus = origin + direction * (config.focus_distance / direction.dot(&forward));
+    let lens = origin + (right * sample.lens.x + up * sample.lens.y) * config.aperture;
+    (lens, (focus - lens).normalize())
+}
+
+// Tracing work done on one thread, summed up per render by render_with
+#[derive(Debug, Clone, Copy, Default)]
+struct TraceCounts {
+    intersection_tests: u64,
+    shadow_rays: u64,
+    reflection_rays: u64,
+    refraction_rays: u64,
+    indirect_rays: u64,
+}
+
+impl TraceCounts {
+    const ZERO: Self = Self {
+        intersection_tests: 0,
+        shadow_rays: 0,
+        reflection_rays: 0,
+        refraction_rays: 0,
+        indirect_rays: 0,
+    };
+}
+
+impl std::ops::Add for TraceCounts {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            intersection_tests: self.intersection_tests + other.intersection_tests,
+            shadow_rays: self.shadow_rays + other.shadow_rays,
+            reflection_rays: self.reflection_rays + other.reflection_rays,
+            refraction_rays: self.refraction_rays + other.refraction_rays,
+            indirect_rays: self.indirect_rays + other.indirect_rays,
+        }
+    }
+}
+
+impl std::ops::Sub for TraceCounts {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self {
+            intersection_tests: self.intersection_tests - other.intersection_tests,
+            shadow_rays: self.shadow_rays - other.shadow_rays,
+            reflection_rays: self.reflection_rays - other.reflection_rays,
+            refraction_rays: self.refraction_rays - other.refraction_rays,
+            indirect_rays: self.indirect_rays - other.indirect_rays,
+        }
+    }
+}
+
+thread_local! {
+    static TRACE_COUNTS: Cell<TraceCounts> = const { Cell::new(TraceCounts::ZERO) };
+}
+
+fn count_trace(update: impl FnOnce(&mut TraceCounts)) {
+    let mut counts = TRACE_COUNTS.get();
+    update(&mut counts);
+    TRACE_COUNTS.set(counts);
+}
+
+fn count_intersection_tests(tests: u64) {
+    count_trace(|counts| counts.intersection_tests += tests);
+}
+
+fn scene_intersect(
+    origin: Vector3<f32>,
+    direction: Vector3<f32>,
+    scene: &Scene,
+    range: Interval,
+) -> Option<Intersection> {
+    // Every primitive is tested, there is no acceleration structure
+    count_intersection_tests(scene.primitive_count() as u64);
+    // Find the nearest intersection within range if it exists and return it,
+    // tagged with the object it belongs to
+    let spheres = scene
+        .spheres
+        .iter()
+        .enumerate()
+        .map(|(index, sphere)| (index, sphere.ray_intersect(origin, direction, range)));
+    let first_mesh = scene.spheres.len();
+    let triangles = scene
+        .triangles
+        .iter()
+        .zip(&scene.triangle_meshes)
+        .map(|(triangle, &mesh)| {
+            (
+                first_mesh + mesh,
+                triangle.ray_intersect(origin, direction, range),
+            )
+        });
+    let first_difference = first_mesh + scene.mesh_count();
+    let differences = scene
+        .differences
+        .iter()
+        .enumerate()
+        .map(|(index, difference)| {
+            (
+                first_difference + index,
+                difference.ray_intersect(origin, direction, range),
+            )
+        });
+    let first_instance = first_difference + scene.differences.len();
+    let instances = scene.instances.iter().enumerate().map(|(index, instance)| {
+        (
+            first_instance + index,
+            instance.ray_intersect(origin, direction, range),
+        )
+    });
+    spheres
+        .chain(triangles)
+        .chain(differences)
+        .chain(instances)
+        .fold(None, |nearest: Option<Intersection>, (object, hit)| {
+            let hit = hit.map(|hit| Intersection { object, ..hit });
+            // Primitives are visited in scene order and an equally distant hit
+            // never replaces the current one, so ties go to the first primitive
+            match (nearest, hit) {
+                (Some(nearest), Some(hit)) if hit.distance >= nearest.distance => Some(nearest),
+                (nearest, None) => nearest,
+                (_, hit) => hit,
+            }
+        })
+}
+
+// Shadow rays start this far from the surface, so it can't shadow itself
+const SHADOW_BIAS: f32 = 1e-3;
+
+fn any_hit(
+    origin: Vector3<f32>,
+    direction: Vector3<f32>,
+    scene: &Scene,
+    range: Interval,
+    mut blocks: impl FnMut(&Intersection) -> bool,
+) -> bool {
+    // Whether some object hit within range blocks the ray. Unlike
+    // scene_intersect, objects are tried in no particular order and the search
+    // stops at the first one that blocks
+    let spheres = scene.spheres.iter().map(|sphere| sphere as &dyn Hittable);
+    let differences = scene
+        .differences
+        .iter()
+        .map(|difference| difference as &dyn Hittable);
+    let instances = scene
+        .instances
+        .iter()
+        .map(|instance| instance as &dyn Hittable);
+    let mut tests = 0;
+    let mut test = |object: &dyn Hittable| {
+        tests += object.primitive_count();
+        object.ray_intersect(origin, direction, range)
+    };
+    // A mesh is one object, crossed once whichever of its triangles is hit
+    let blocked = scene.meshes().any(|mesh| {
+        mesh.iter()
+            .find_map(|triangle| test(triangle))
+            .is_some_and(|hit| blocks(&hit))
+    }) || spheres
+        .chain(differences)
+        .chain(instances)
+        .any(|object| test(object).is_some_and(|hit| blocks(&hit)));
+    count_intersection_tests(tests as u64);
+    blocked
+}
+
+fn light_visibility(
+    point: Vector3<f32>,
+    light: &Light,
+    scene: &Scene,
+    config: &RenderConfig,
+) -> Vector3<f32> {
+    // The fraction of the light reaching the point per channel, 1 when nothing
+    // lies in between and 0 in the shadow of an opaque object
+    if !config.shadows {
+        return Vector3::repeat(1.0);
+    }
+    if config.shadow_samples <= 1 || config.light_radius == 0.0 {
+        return visibility_toward(point, light.pos(), scene, config);
+    }
+    // Lights are discs of light_radius facing the point, sampled on a Vogel
+    // spiral. The same points everywhere, so penumbras are free of noise
+    let to_light = (light.pos() - point).normalize();
+    let helper = if to_light.x.abs() < 0.9 {
+        Vector3::x()
+    } else {
+        Vector3::y()
+    };
+    let u = to_light.cross(&helper).normalize();
+    let v = to_light.cross(&u);
+    let golden_angle = std::f32::consts::PI * (3.0 - 5.0_f32.sqrt());
+    let n = config.shadow_samples;
+    (0..n)
+        .map(|k| {
+            let radius = config.light_radius * ((k as f32 + 0.5) / n as f32).sqrt();
+            let (sin, cos) = (k as f32 * golden_angle).sin_cos();
+            let target = light.pos() + (u * cos + v * sin) * radius;
+            visibility_toward(point, target, scene, config)
+        })
+        .sum::<Vector3<f32>>()
+        / n as f32
+}
+
+fn visibility_toward(
+    point: Vector3<f32>,
+    target: Vector3<f32>,
+    scene: &Scene,
+    config: &RenderConfig,
+) -> Vector3<f32> {
+    let to_light = target - point;
+    let distance = to_light.norm();
+    let direction = to_light / distance;
+    let range = Interval::new(SHADOW_BIAS, distance);
+    count_trace(|counts| counts.shadow_rays += 1);
+    // Each object in the way filters the light by its transmission
+    let mut transmittance = Vector3::repeat(1.0);
+    let blocked = any_hit(point, direction, scene, range, |hit| {
+        transmittance.component_mul_assign(&hit.material.transmission());
+        transmittance == Vector3::zeros()
+    });
+    if blocked || config.shadow_softness == 0.0 {
+        return transmittance;
+    }
+    // Contact hardening from the one ray: passing close by a sphere leaves the
+    // point partly shadowed, out to a distance that grows with how far along
+    // the ray the sphere is. Shadows stay sharp where the occluder touches the
+    // receiver and spread out away from it. Other shapes cast hard shadows
+    let penumbra = scene
+        .spheres
+        .iter()
+        .filter(|sphere| sphere.material.transmission() == Vector3::zeros())
+        .map(|sphere| {
+            let along = (sphere.center - point).dot(&direction).clamp(0.0, distance);
+            let gap = (point + direction * along - sphere.center).norm() - sphere.radius;
+            let penumbra = config.shadow_softness * along;
+            if penumbra <= 0.0 {
+                return 1.0;
+            }
+            let t = (gap / penumbra).clamp(0.0, 1.0);
+            t * t * (3.0 - 2.0 * t)
+        })
+        .fold(1.0, f32::min);
+    transmittance * penumbra
+}
+
+// Dielectrics a path can be inside of at once, deeper nesting is ignored
+const MAX_NESTED_MEDIA: usize = 4;
+
+// The dielectric objects a path is inside of, innermost last, each with its
+// index of refraction. Where objects overlap the innermost one fills the overlap
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Media {
+    entries: [(usize, Vector3<f32>); MAX_NESTED_MEDIA],
+    len: usize,
+}
+
+impl Media {
+    const NONE: Self = Self {
+        entries: [(0, Vector3::new(1.0, 1.0, 1.0)); MAX_NESTED_MEDIA],
+        len: 0,
+    };
+
+    fn ior(&self) -> Vector3<f32> {
+        // Outside every object is air
+        match self.len {
+            0 => Vector3::repeat(1.0),
+            len => self.entries[len - 1].1,
+        }
+    }
+
+    fn contains(&self, object: usize) -> bool {
+        self.entries[..self.len].iter().any(|&(o, _)| o == object)
+    }
+
+    fn entered(mut self, object: usize, ior: Vector3<f32>) -> Self {
+        if self.len < MAX_NESTED_MEDIA {
+            self.entries[self.len] = (object, ior);
+            self.len += 1;
+        }
+        self
+    }
+
+    fn left(mut self, object: usize) -> Self {
+        // Objects can be left in any order, not just innermost first
+        if let Some(k) = self.entries[..self.len]
+            .iter()
+            .position(|&(o, _)| o == object)
+        {
+            self.entries.copy_within(k + 1..self.len, k);
+            self.len -= 1;
+            self.entries[self.len] = Self::NONE.entries[self.len];
+        }
+        self
+    }
+}
+
+// Where a ray is along the path from the camera, which the rays it spawns
+// carry on
+#[derive(Debug, Clone, Copy)]
+struct Path {
+    // Reflections and refractions since the camera
+    depth: u32,
+    // The one color channel the path carries since dispersion split it, if any
+    channel: Option<usize>,
+    // Seeds the random numbers along the path, different for every branch
+    key: u64,
+    // What the path is passing through
+    media: Media,
+    // Product of the bounce weights since the camera, the most the light
+    // brought back can still add to the pixel
+    throughput: Vector3<f32>,
+    // Whether the path has bounced off a diffuse surface, which it does once
+    diffuse: bool,
+    // The wavelengths a spectral path carries in place of red, green and
+    // blue, None for RGB
+    wavelengths: Option<Vector3<f32>>,
+}
+
+impl Path {
+    const CAMERA: Self = Self {
+        depth: 0,
+        channel: None,
+        key: 0,
+        media: Media::NONE,
+        throughput: Vector3::new(1.0, 1.0, 1.0),
+        diffuse: false,
+        wavelengths: None,
+    };
+
+    fn deeper(self) -> Self {
+        Self {
+            depth: self.depth + 1,
+            ..self
+        }
+    }
+
+    fn branch(self, branch: u64) -> Self {
+        Self {
+            key: splitmix(self.key, branch),
+            ..self
+        }
+    }
+
+    fn random(&self, dimension: u32) -> f32 {
+        unit_random(splitmix(self.key, dimension as u64))
+    }
+
+    // An RGB color as the path carries it: as is, or as the upsampled
+    // reflectance at each of its wavelengths
+    fn spectrum(&self, rgb: Vector3<f32>) -> Vector3<f32> {
+        match self.wavelengths {
+            Some(wavelengths) => wavelengths.map(|wavelength| rgb.dot(&rgb_basis(wavelength))),
+            None => rgb,
+        }
+    }
+
+    // Per channel indices of refraction at the path's wavelengths, from
+    // Cauchy's n = A + B / wavelength^2 through the red and blue indices
+    fn ior(&self, ior: Vector3<f32>) -> Vector3<f32> {
+        let Some(wavelengths) = self.wavelengths else {
+            return ior;
+        };
+        let [red, _, blue] = RGB_WAVELENGTHS.map(|wavelength| wavelength.powi(-2));
+        let b = (ior.z - ior.x) / (blue - red);
+        wavelengths.map(|wavelength| ior.x + b * (wavelength.powi(-2) - red))
+    }
+}
+
+// Spectral paths sample the visible range in nanometres, three wavelengths
+// at a time a third of it apart, the first one random. Materials keep their
+// RGB colors, upsampled to smooth reflectance spectra by rgb_basis, and the
+// spectral result goes back to RGB through the CIE 1931 color matching
+// functions. Dispersion then spreads light into a continuous rainbow rather
+// than three red, green and blue images
+const VISIBLE_WAVELENGTHS: (f32, f32) = (380.0, 730.0);
+
+// Where per channel indices of refraction are taken to be measured
+const RGB_WAVELENGTHS: [f32; 3] = [610.0, 550.0, 465.0];
+
+fn rgb_basis(wavelength: f32) -> Vector3<f32> {
+    // How much of a color's red, green and blue reflect at a wavelength:
+    // blue below about 495nm, red above about 585nm and green between, with
+    // smooth crossings. They sum to 1 everywhere, so white stays flat
+    let step = |from: f32, to: f32| {
+        let t = ((wavelength - from) / (to - from)).clamp(0.0, 1.0);
+        t * t * (3.0 - 2.0 * t)
+    };
+    let (red, blue) = (step(570.0, 600.0), 1.0 - step(480.0, 510.0));
+    Vector3::new(red, 1.0 - red - blue, blue)
+}
+
+fn cie_xyz(wavelength: f32) -> Vector3<f32> {
+    // Wyman, Sloan and Shirley's multi-lobe fit to the CIE 1931 2 degree
+    // observer, each lobe a Gaussian with its own width either side
+    let lobe = |mean: f32, below: f32, above: f32| {
+        let width = if wavelength < mean { below } else { above };
+        (-0.5 * ((wavelength - mean) / width).powi(2)).exp()
+    };
+    Vector3::new(
+        1.056 * lobe(599.8, 37.9, 31.0) + 0.362 * lobe(442.0, 16.0, 26.7)
+            - 0.065 * lobe(501.1, 20.4, 26.2),
+        0.821 * lobe(568.8, 46.9, 40.5) + 0.286 * lobe(530.9, 16.3, 31.1),
+        1.217 * lobe(437.0, 11.8, 36.0) + 0.681 * lobe(459.0, 26.0, 13.8),
+    )
+}
+
+fn wavelength_rgb(wavelength: f32) -> Vector3<f32> {
+    // Linear Rec. 709 primaries with a D65 white
+    let xyz_to_rgb = Matrix3::new(
+        3.2406, -1.5372, -0.4986, -0.9689, 1.8758, 0.0415, 0.0557, -0.2040, 1.0570,
+    );
+    xyz_to_rgb * cie_xyz(wavelength)
+}
+
+fn spectral_to_rgb(radiance: Vector3<f32>, wavelengths: Vector3<f32>) -> Vector3<f32> {
+    // The three samples estimate the integral of the spectrum against the
+    // matching functions. The fixed matrix undoes what upsampling and
+    // projecting do to an RGB color, so a flat-lit surface comes back as its
+    // own color on average and white stays white
+    static UNMIX: OnceLock<Matrix3<f32>> = OnceLock::new();
+    let unmix = UNMIX.get_or_init(|| {
+        let (first, last) = VISIBLE_WAVELENGTHS;
+        let steps = 1000;
+        let step = (last - first) / steps as f32;
+        let mix: Matrix3<f32> = (0..steps)
+            .map(|k| {
+                let wavelength = first + (k as f32 + 0.5) * step;
+                wavelength_rgb(wavelength) * rgb_basis(wavelength).transpose() * step
+            })
+            .sum();
+        mix.try_inverse().unwrap_or_else(Matrix3::identity)
+    });
+    let width = (VISIBLE_WAVELENGTHS.1 - VISIBLE_WAVELENGTHS.0) / 3.0;
+    let projected: Vector3<f32> = (0..3)
+        .map(|k| wavelength_rgb(wavelengths[k]) * (radiance[k] * width))
+        .sum();
+    unmix * projected
+}
+
+fn glossy_direction(
+    direction: Vector3<f32>,
+    normal: Vector3<f32>,
+    roughness: f32,
+    path: Path,
+) -> Vector3<f32> {
+    // Nudge the direction by a random point in a ball of radius roughness.
+    // A nudge that would cross the surface keeps the sharp direction
+    let z = 1.0 - 2.0 * path.random(0);
+    let (sin, cos) = (2.0 * PI * path.random(1)).sin_cos();
+    let radius = roughness * path.random(2).cbrt();
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let nudged = (direction + Vector3::new(r * cos, r * sin, z) * radius).normalize();
+    if (nudged.dot(&normal) > 0.0) == (direction.dot(&normal) > 0.0) {
+        nudged
+    } else {
+        direction
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BounceKind {
+    Reflection,
+    Refraction,
+    // Off a diffuse surface, into the hemisphere above it
+    Indirect,
+}
+
+// A ray a surface sends on, and the fraction of the light it brings back
+// that the surface passes towards the viewer, per channel
+#[derive(Debug, Clone, Copy)]
+struct Bounce {
+    kind: BounceKind,
+    direction: Vector3<f32>,
+    weight: Vector3<f32>,
+    // The channel the ray carries on its own, see Path::channel
+    channel: Option<usize>,
+    // What the ray passes through, changed by refraction only
+    media: Media,
+}
+
+fn reflect(direction: Vector3<f32>, normal: Vector3<f32>) -> Vector3<f32> {
+    direction - normal * (2.0 * direction.dot(&normal))
+}
+
+fn schlick(cos: f32, f0: Vector3<f32>) -> Vector3<f32> {
+    // Schlick's approximation of the Fresnel reflectance, f0 at normal incidence
+    f0 + (Vector3::repeat(1.0) - f0) * (1.0 - cos).clamp(0.0, 1.0).powi(5)
+}
+
+fn specular_bounces(
+    surface: &Surface,
+    albedo: Vector3<f32>,
+    hit: &Intersection,
+    direction: Vector3<f32>,
+    path: Path,
+) -> Vec<Bounce> {
+    // The normal on the side the ray arrives from, also on single-sided backs
+    let normal = if direction.dot(&hit.normal) < 0.0 {
+        hit.normal
+    } else {
+        -hit.normal
+    };
+    let cos_in = -direction.dot(&normal);
+    let reflection = |weight| Bounce {
+        kind: BounceKind::Reflection,
+        direction: reflect(direction, normal),
+        weight,
+        channel: path.channel,
+        media: path.media,
+    };
+    match surface.shading {
+        // Metals reflect with their own color, most strongly head-on
+        ShadingModel::Metal => vec![reflection(schlick(cos_in, albedo))],
+        ShadingModel::Dielectric => {
+            // Each channel bends by its own index. Where the indices differ
+            // the channels refract as rays of their own, and stay apart
+            let ior = path.ior(surface.ior);
+            let channels = match path.channel {
+                Some(channel) => vec![Some(channel)],
+                None if ior == Vector3::repeat(ior.x) => vec![None],
+                None => vec![Some(0), Some(1), Some(2)],
+            };
+            // The ray enters through the front and leaves through the back,
+            // into whatever it was in before. Leaving an object the path
+            // never entered, it started out inside it
+            let outside = path.media.left(hit.object);
+            let (from, to, media) = if hit.front_face {
+                let inside = outside.entered(hit.object, ior);
+                (path.media.ior(), ior, inside)
+            } else if path.media.contains(hit.object) {
+                (path.media.ior(), outside.ior(), outside)
+            } else {
+                (ior, path.media.ior(), path.media)
+            };
+            let mut fresnel = Vector3::zeros();
+            let mut refractions = Vec::new();
+            for channel in channels {
+                let mask = channel.map_or(Vector3::repeat(1.0), |c| Vector3::ith(c, 1.0));
+                let (from, to) = (from[channel.unwrap_or(0)], to[channel.unwrap_or(0)]);
+                let eta = from / to;
+                let sin_out_sq = eta * eta * (1.0 - cos_in * cos_in);
+                // Past the critical angle everything is reflected
+                if sin_out_sq >= 1.0 {
+                    fresnel += mask;
+                    continue;
+                }
+                let cos_out = (1.0 - sin_out_sq).sqrt();
+                // Fresnel goes by the angle on the optically thinner side, and
+                // between equal indices there is nothing to reflect
+                let f0 = ((from - to) / (from + to)).powi(2);
+                let reflectance = match from == to {
+                    true => 0.0,
+                    false => schlick(cos_in.min(cos_out), Vector3::repeat(f0)).x,
+                };
+                fresnel += mask * reflectance;
+                refractions.push(Bounce {
+                    kind: BounceKind::Refraction,
+                    direction: direction * eta + normal * (eta * cos_in - cos_out),
+                    weight: albedo.component_mul(&mask) * (1.0 - reflectance),
+                    channel,
+                    media,
+                });
+            }
+            std::iter::once(reflection(fresnel))
+                .chain(refractions)
+                .collect()
+        }
+        ShadingModel::Lambert | ShadingModel::Unlit => Vec::new(),
+    }
+}
+
+fn trace_bounce(
+    point: Vector3<f32>,
+    bounce: &Bounce,
+    scene: &Scene,
+    config: &RenderConfig,
+    path: Path,
+) -> Vector3<f32> {
+    // Paths that reach the depth cap, or could add too little to be worth a
+    // ray, bring back no more light
+    let path = Path {
+        channel: bounce.channel,
+        media: bounce.media,
+        throughput: path.throughput.component_mul(&bounce.weight),
+        ..path.deeper()
+    };
+    if path.depth > config.max_depth || path.throughput.max() < config.min_throughput {
+        return Vector3::zeros();
+    }
+    count_trace(|counts| match bounce.kind {
+        BounceKind::Reflection => counts.reflection_rays += 1,
+        BounceKind::Refraction => counts.refraction_rays += 1,
+        BounceKind::Indirect => counts.indirect_rays += 1,
+    });
+    // Secondary rays see the reflection background where they miss, the
+    // backplate only fits behind the camera's view
+    let range = Interval::new(SHADOW_BIAS, config.far);
+    trace_ray(point, bounce.direction, scene, range, config, path).unwrap_or_else(|| {
+        path.spectrum(
+            scene
+                .reflection_background()
+                .color(bounce.direction, scene, config),
+        )
+    })
+}
+
+fn indirect_light(
+    hit: &Intersection,
+    albedo: Vector3<f32>,
+    scene: &Scene,
+    config: &RenderConfig,
+    path: Path,
+) -> Vector3<f32> {
+    // The light arriving from other surfaces and the background, averaged over
+    // cosine-weighted directions about the normal, which makes the average the
+    // diffuse estimate as is
+    let tangent = perpendicular(hit.normal);
+    let bitangent = hit.normal.cross(&tangent);
+    // Under an environment map each sample also takes a direction towards
+    // its bright parts, and the two are weighted by the balance heuristic so
+    // whichever strategy is likelier to pick a direction counts for most of it
+    let environment = match scene.reflection_background() {
+        Background::Environment => scene.environment.as_ref(),
+        _ => None,
+    };
+    let cosine_pdf = |direction: Vector3<f32>| hit.normal.dot(&direction).max(0.0) / PI;
+    let n = config.indirect_samples;
+    (0..n)
+        .map(|k| {
+            let path = Path {
+                diffuse: true,
+                ..path.branch(k as u64)
+            };
+            let bounce = |direction| Bounce {
+                kind: BounceKind::Indirect,
+                direction,
+                weight: albedo,
+                channel: path.channel,
+                media: path.media,
+            };
+            let (r_sq, angle) = (path.random(0), 2.0 * PI * path.random(1));
+            let (sin, cos) = angle.sin_cos();
+            let r = r_sq.sqrt();
+            let direction =
+                tangent * (r * cos) + bitangent * (r * sin) + hit.normal * (1.0 - r_sq).sqrt();
+            let light = trace_bounce(hit.point, &bounce(direction), scene, config, path);
+            let Some(environment) = environment else {
+                return light;
+            };
+            let cosine = cosine_pdf(direction);
+            let mut sum = light * (cosine / (cosine + environment.pdf(direction)));
+            let (direction, pdf) = environment.sample(path.random(2), path.random(3));
+            let cosine = cosine_pdf(direction);
+            if cosine > 0.0 {
+                let light = trace_bounce(hit.point, &bounce(direction), scene, config, path);
+                sum += light * (cosine / (cosine + pdf));
+            }
+            sum
+        })
+        .sum::<Vector3<f32>>()
+        / n as f32
+}
+
+fn cast_ray(
+    origin: Vector3<f32>,
+    direction: Vector3<f32>,
+    scene: &Scene,
+    range: Interval,
+    config: &RenderConfig,
+    wavelength: f32,
+) -> Option<Vector3<f32>> {
+    // Every camera ray starts paths of its own, reproducible from the seed
+    let key = direction
+        .iter()
+        .fold(config.seed, |key, c| splitmix(key, c.to_bits() as u64));
+    let path = Path::CAMERA.branch(key);
+    if !config.spectral {
+        return trace_ray(origin, direction, scene, range, config, path);
+    }
+    // Spectral rays carry three wavelengths a third of the visible range
+    // apart, the first at the given fraction into the lowest third
+    let (first, last) = VISIBLE_WAVELENGTHS;
+    let width = (last - first) / 3.0;
+    let hero = first + width * wavelength;
+    let wavelengths = Vector3::new(hero, hero + width, hero + 2.0 * width);
+    let path = Path {
+        wavelengths: Some(wavelengths),
+        ..path
+    };
+    trace_ray(origin, direction, scene, range, config, path)
+        .map(|radiance| spectral_to_rgb(radiance, wavelengths))
+}
+
+fn trace_ray(
+    origin: Vector3<f32>,
+    direction: Vector3<f32>,
+    scene: &Scene,
+    range: Interval,
+    config: &RenderConfig,
+    path: Path,
+) -> Option<Vector3<f32>> {
+    let intersection = match scene_intersect(origin, direction, scene, range) {
+        Some(intersection) => intersection,
+        _ => return None,
+    };
+
+    let shade = |surface: &Surface| {
+        // Each surface of a blend has its own textures, so its own normal
+        let intersection = Intersection {
+            normal: surface.normal_at(&intersection, scene),
+            ..intersection
+        };
+        // The lighting mode swaps in a white albedo to show the raw illumination
+        let albedo = match config.mode {
+            RenderMode::Lighting => Vector3::repeat(1.0),
+            _ => path.spectrum(surface.albedo_at(&intersection, scene)),
+        };
+        match surface.shading {
+            ShadingModel::Lambert => {
+                // Per channel, as light filtered through tinted objects is colored
+                let to_viewer = -direction.normalize();
+                let (diffuse_intensity, specular) = scene
+                    .lights
+                    .iter()
+                    .filter(|light| config.light_contributes(light))
+                    .fold(
+                        (Vector3::zeros(), Vector3::zeros()),
+                        |(diffuse_acc, specular_acc), light: &Light| {
+                            let diffuse = light.diffuse_for_intersection(&intersection);
+                            // Only lights that would add anything are worth a shadow ray
+                            if diffuse <= 0.0 {
+                                return (diffuse_acc, specular_acc);
+                            }
+                            // Filters are multiplied in RGB before the spectrum is
+                            // taken, exact for one tinted object in the way
+                            let visibility = path.spectrum(light_visibility(
+                                intersection.point,
+                                light,
+                                scene,
+                                config,
+                            ));
+                            let multiplier = config.light_multiplier(light);
+                            let specular = match surface.specular {
+                                0.0 => 0.0,
+                                _ => light.specular_for_intersection(
+                                    &intersection,
+                                    to_viewer,
+                                    surface,
+                                    config,
+                                ),
+                            };
+                            (
+                                diffuse_acc + visibility * (multiplier * diffuse),
+                                specular_acc + visibility * (multiplier * specular),
+                            )
+                        },
+                    );
+                let diffuse_intensity =
+                    diffuse_intensity.map(|intensity| config.diffuse_limit.apply(intensity));
+                // Traced indirect light takes over from the ambient guess at
+                // it, for the first diffuse bounce of a path
+                let ambient = if config.indirect_samples > 0 && !path.diffuse {
+                    indirect_light(&intersection, albedo, scene, config, path)
+                } else {
+                    path.spectrum(hemisphere_ambient(intersection.normal, config))
+                };
+                // Highlights are white and not limited like the diffuse sum
+                albedo.component_mul(&(ambient + diffuse_intensity)) + specular
+            }
+            ShadingModel::Unlit => albedo,
+            ShadingModel::Metal | ShadingModel::Dielectric => {
+                // Rough surfaces average glossy_samples rays strayed from each
+                // bounce, or stay sharp with just the one
+                let rays = match surface.roughness > 0.0 {
+                    true => config.glossy_samples,
+                    false => 1,
+                };
+                let bounces =
+                    specular_bounces(surface, albedo, &intersection, direction.normalize(), path);
+                let mut color = Vector3::zeros();
+                for (k, bounce) in bounces.iter().enumerate() {
+                    for n in 0..rays {
+                        let path = path.branch((k as u32 * rays + n) as u64);
+                        let bounce = match rays {
+                            1 => *bounce,
+                            _ => Bounce {
+                                direction: glossy_direction(
+                                    bounce.direction,
+                                    intersection.normal,
+                                    surface.roughness,
+                                    path,
+                                ),
+                                ..*bounce
+                            },
+                        };
+                        let light = trace_bounce(intersection.point, &bounce, scene, config, path);
+                        color += bounce.weight.component_mul(&light) / rays as f32;
+                    }
+                }
+                color
+            }
+        }
+    };
+    let mut color = match &intersection.material {
+        Material::Surface(surface) => shade(surface),
+        Material::Blend { a, b, factor } => shade(a).lerp(&shade(b), *factor),
+    };
+
+    // Rim light is added on top of the albedo, brightest where the surface turns away
+    if config.rim_color != Vector3::zeros() {
+        let facing = intersection.normal.dot(&-direction).clamp(0.0, 1.0);
+        color += path.spectrum(config.rim_color) * (1.0 - facing).powf(config.rim_power);
+    }
+
+    // The smallest barycentric coordinate is the distance to the nearest edge,
+    // relative to the triangle's size
+    if let (Some(thickness), Some(barycentric)) = (config.wireframe, intersection.barycentric) {
+        if barycentric.min() < thickness {
+            color = Vector3::zeros();
+        }
+    }
+
+    let fog_color = path.spectrum(config.fog_color);
+    Some(apply_fog(color, intersection.distance, fog_color, config))
+}
+
+fn write_ppm(mut out: impl Write, width: u32, height: u32, pixels: &[u8]) -> io::Result<()> {
+    // Write the PPM header
+    writeln!(out, "P6 {} {} 255", width, height)?;
+
+    // Write the pixel data
+    out.write_all(pixels)?;
+
+    Ok(())
+}
+
+fn write_ppm_image(filename: &str, width: u32, height: u32, pixels: &[u8]) -> io::Result<()> {
+    write_ppm(File::create(filename)?, width, height, pixels)
+}
+
+fn write_pfm_image(
+    filename: &str,
+    width: u32,
+    height: u32,
+    radiance: &[Vector3<f32>],
+) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(filename)?);
+
+    // Write the PFM header, a negative scale marks little-endian data
+    write!(file, "PF\n{} {}\n-1.0\n", width, height)?;
+
+    // Write the unclamped pixel data, PFM stores rows bottom to top
+    for row in radiance.chunks(width as usize).rev() {
+        for v in row {
+            for channel in v.iter() {
+                file.write_all(&channel.to_le_bytes())?;
+            }
+        }
+    }
+
+    file.flush()
+}
+
+// Largest output width or height, small enough that a pixel count fits in a u32
+const MAX_IMAGE_SIDE: u32 = 16384;
+
+// CSV output is for inspecting individual values, a line per pixel, so it is
+// limited to images small enough to read through
+const CSV_MAX_PIXELS: u32 = 64 * 64;
+
+fn write_csv_image(
+    filename: &str,
+    width: u32,
+    height: u32,
+    radiance: &[Vector3<f32>],
+) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(filename)?);
+    writeln!(file, "x,y,r,g,b")?;
+
+    // Write the unclamped pixel data top to bottom. Display prints the
+    // shortest decimal that parses back to the same f32
+    for (j, row) in (0..height).zip(radiance.chunks(width as usize)) {
+        for (i, v) in (0..width).zip(row) {
+            writeln!(file, "{},{},{},{},{}", i, j, v.x, v.y, v.z)?;
+        }
+    }
+
+    file.flush()
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    // Bitwise CRC-32 (IEEE), plenty fast for a handful of PNG chunks
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // Wrap the data in uncompressed deflate blocks, which every PNG reader accepts
+    let mut out = vec![0x78, 0x01];
+    let chunks: Vec<&[u8]> = data.chunks(0xFFFF).collect();
+    for (index, chunk) in chunks.iter().enumerate() {
+        let last = index + 1 == chunks.len();
+        out.push(last as u8);
+        out.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(!(chunk.len() as u16)).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+    if chunks.is_empty() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    // Adler-32 checksum of the uncompressed data
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    out.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    out
+}
+
+fn write_png_image(filename: &str, width: u32, height: u32, rgba: &[u8]) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(filename)?);
+
+    // Write the PNG signature
+    file.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+    let mut write_chunk = |kind: &[u8], data: &[u8]| -> io::Result<()> {
+        file.write_all(&(data.len() as u32).to_be_bytes())?;
+        let mut body = kind.to_vec();
+        body.extend_from_slice(data);
+        file.write_all(&body)?;
+        file.write_all(&crc32(&body).to_be_bytes())
+    };
+
+    // Write the header, 8-bit RGBA without interlacing
+    let mut header = Vec::new();
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+    write_chunk(b"IHDR", &header)?;
+
+    // Write the pixel data, every row prefixed with filter type 0 (none)
+    let mut scanlines = Vec::with_capacity(rgba.len() + height as usize);
+    for row in rgba.chunks(width as usize * 4) {
+        scanlines.push(0);
+        scanlines.extend_from_slice(row);
+    }
+    write_chunk(b"IDAT", &zlib_stored(&scanlines))?;
+    write_chunk(b"IEND", &[])?;
+
+    file.flush()
+}
+
+fn display_transform(radiance: Vector3<f32>, (i, j): (u32, u32), config: &RenderConfig) -> [u8; 3] {
+    // Linear radiance to display bytes, one stage after another: exposure,
+    // tone map, gamut mapping into [0, 1], gamma, then quantize with optional dither.
+    // This is the only place radiance leaves linear space, so anything
+    // averaged before it (AA samples) is averaged linearly
+    if config.flag_nonfinite && !radiance.iter().all(|c| c.is_finite()) {
+        return [255, 0, 255];
+    }
+    // A 4x4 ordered dither threshold, truncating without dither is a threshold of 0
+    const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+    let threshold = if config.dither {
+        (BAYER[(j % 4) as usize][(i % 4) as usize] as f32 + 0.5) / 16.0
+    } else {
+        0.0
+    };
+    let scale = if config.exposure == 0.0 {
+        1.0
+    } else {
+        config.exposure.exp2()
+    };
+    let linear = radiance.map(|c| {
+        // NaN and infinities from degenerate math come out black
+        if !c.is_finite() {
+            return 0.0;
+        }
+        let c = c * scale;
+        match config.tone_map {
+            ToneMap::None => c,
+            ToneMap::Reinhard => c.max(0.0) / (1.0 + c.max(0.0)),
+        }
+    });
+    let display = config.gamut.apply(linear);
+    let encode = |c: f32| {
+        let c = if config.gamma == 1.0 {
+            c
+        } else {
+            c.powf(1.0 / config.gamma)
+        };
+        (255.0 * c + threshold) as u8
+    };
+    [encode(display.x), encode(display.y), encode(display.z)]
+}
+
+fn object_id_color(object: usize) -> Vector3<f32> {
+    // Scramble the index so neighbouring objects get clearly different colors,
+    // keeping every channel off zero so no object matches the black background
+    let hash = (object as u32 + 1).wrapping_mul(0x9E37_79B9);
+    let channel = |shift: u32| ((hash >> shift) as u8 | 0x20) as f32 / 255.0;
+    Vector3::new(channel(24), channel(16), channel(8))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    color: Vector3<f32>,
+    // Whether the primary ray hit geometry rather than the background
+    hit: bool,
+}
+
+fn sample_pixel(
+    scene: &Scene,
+    config: &RenderConfig,
+    i: u32,
+    j: u32,
+    camera: CameraSample,
+) -> Sample {
+    let (origin, dir) = lens_ray(&camera, config);
+    let (color, hit) = match config.mode {
+        RenderMode::Shaded | RenderMode::Lighting => match &config.shader {
+            Some(shader) => {
+                let intersection = scene_intersect(origin, dir, scene, config.primary_range());
+                let color = shader(origin, dir, intersection.as_ref(), scene);
+                (color, intersection.is_some())
+            }
+            None => match cast_ray(
+                origin,
+                dir,
+                scene,
+                config.primary_range(),
+                config,
+                camera.wavelength,
+            ) {
+                Some(v) => (v, true),
+                _ => (background(dir, i, j, scene, config), false),
+            },
+        },
+        RenderMode::ObjectId => match scene_intersect(origin, dir, scene, config.primary_range()) {
+            Some(intersection) => (object_id_color(intersection.object), true),
+            _ => (Vector3::zeros(), false),
+        },
+    };
+    match light_marker(origin, dir, scene, config) {
+        Some(color) => Sample { color, hit: true },
+        None => Sample { color, hit },
+    }
+}
+
+fn light_marker(
+    origin: Vector3<f32>,
+    direction: Vector3<f32>,
+    scene: &Scene,
+    config: &RenderConfig,
+) -> Option<Vector3<f32>> {
+    // Markers are only drawn for primary rays and are not part of the scene,
+    // so they never block or change the lighting
+    let radius = config.light_markers?;
+    let range = config.primary_range();
+    let nearest = scene_intersect(origin, direction, scene, range)
+        .map_or(range.max, |intersection| intersection.distance);
+    let marker_range = Interval::new(range.min, nearest);
+    count_intersection_tests(scene.lights.len() as u64);
+    scene
+        .lights
+        .iter()
+        .filter_map(|light| {
+            let marker = Sphere::new(light.pos(), radius, Material::new(Vector3::zeros()));
+            let hit = marker.ray_intersect(origin, direction, marker_range)?;
+            Some((hit.distance, light))
+        })
+        .min_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map(|(_, light)| Vector3::repeat(light.intensity * config.light_multiplier(light)))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PixelSum {
+    radiance: Vector3<f32>,
+    foreground: Vector3<f32>,
+    hits: u32,
+    samples: u32,
+}
+
+impl PixelSum {
+    fn new() -> Self {
+        Self {
+            radiance: Vector3::zeros(),
+            foreground: Vector3::zeros(),
+            hits: 0,
+            samples: 0,
+        }
+    }
+
+    fn add(&mut self, sample: Sample) {
+        self.radiance += sample.color;
+        if sample.hit {
+            self.foreground += sample.color;
+            self.hits += 1;
+        }
+        self.samples += 1;
+    }
+}
+
+// A rectangle of pixels, from x0, y0 up to but not including x1, y1
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Region {
+    x0: u32,
+    y0: u32,
+    x1: u32,
+    y1: u32,
+}
+
+impl Region {
+    fn full(config: &RenderConfig) -> Self {
+        Self {
+            x0: 0,
+            y0: 0,
+            x1: config.width,
+            y1: config.height,
+        }
+    }
+
+    fn width(&self) -> u32 {
+        self.x1 - self.x0
+    }
+
+    fn height(&self) -> u32 {
+        self.y1 - self.y0
+    }
+
+    fn is_empty(&self) -> bool {
+        self.x0 >= self.x1 || self.y0 >= self.y1
+    }
+
+    fn union(self, other: Self) -> Self {
+        Self {
+            x0: self.x0.min(other.x0),
+            y0: self.y0.min(other.y0),
+            x1: self.x1.max(other.x1),
+            y1: self.y1.max(other.y1),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Framebuffer {
+    width: u32,
+    height: u32,
+    radiance: Vec<Vector3<f32>>,
+    // Average color of only the samples that hit geometry, for straight alpha
+    foreground: Vec<Vector3<f32>>,
+    // Fraction of samples that hit geometry
+    coverage: Vec<f32>,
+}
+
+impl Framebuffer {
+    fn from_sums(width: u32, height: u32, sums: &[PixelSum]) -> Self {
+        Self {
+            width,
+            height,
+            radiance: sums
+                .iter()
+                .map(|sum| sum.radiance / sum.samples.max(1) as f32)
+                .collect(),
+            foreground: sums
+                .iter()
+                .map(|sum| sum.foreground / sum.hits.max(1) as f32)
+                .collect(),
+            coverage: sums
+                .iter()
+                .map(|sum| sum.hits as f32 / sum.samples.max(1) as f32)
+                .collect(),
+        }
+    }
+}
+
+fn upsample<T>(
+    source: &[T],
+    (source_width, source_height): (u32, u32),
+    (width, height): (u32, u32),
+    filter: PreviewFilter,
+) -> Vec<T>
+where
+    T: Copy + std::ops::Add<Output = T> + std::ops::Mul<f32, Output = T>,
+{
+    let at = |x: u32, y: u32| source[(x + y * source_width) as usize];
+    let mut pixels = Vec::with_capacity((width * height) as usize);
+    for j in 0..height {
+        for i in 0..width {
+            // Map output pixel centers onto the source, clamped to its edge pixels
+            let x = ((i as f32 + 0.5) * source_width as f32 / width as f32 - 0.5)
+                .clamp(0.0, (source_width - 1) as f32);
+            let y = ((j as f32 + 0.5) * source_height as f32 / height as f32 - 0.5)
+                .clamp(0.0, (source_height - 1) as f32);
+            pixels.push(match filter {
+                PreviewFilter::Nearest => at(x.round() as u32, y.round() as u32),
+                PreviewFilter::Bilinear => {
+                    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+                    let (x1, y1) = (
+                        (x0 + 1).min(source_width - 1),
+                        (y0 + 1).min(source_height - 1),
+                    );
+                    let (fx, fy) = (x.fract(), y.fract());
+                    let top = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+                    let bottom = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+                    top * (1.0 - fy) + bottom * fy
+                }
+            });
+        }
+    }
+    pixels
+}
+
+impl Framebuffer {
+    fn upsample(&self, width: u32, height: u32, filter: PreviewFilter) -> Self {
+        let source = (self.width, self.height);
+        Self {
+            width,
+            height,
+            radiance: upsample(&self.radiance, source, (width, height), filter),
+            foreground: upsample(&self.foreground, source, (width, height), filter),
+            coverage: upsample(&self.coverage, source, (width, height), filter),
+        }
+    }
+}
+
+fn luminance(color: Vector3<f32>) -> f32 {
+    // Rec. 709 weights for linear RGB
+    color.dot(&Vector3::new(0.2126, 0.7152, 0.0722))
+}
+
+fn exposure_scale(radiance: &[Vector3<f32>], target: f32) -> f32 {
+    // The log-average keeps a few very bright pixels from dominating the mean,
+    // and the small offset keeps black pixels from sending the log to -inf
+    const DELTA: f32 = 1e-4;
+    let finite = radiance.iter().filter(|v| v.iter().all(|c| c.is_finite()));
+    let (sum, count) = finite.fold((0.0, 0), |(sum, count), &v| {
+        (sum + (DELTA + luminance(v).max(0.0)).ln(), count + 1)
+    });
+    if count == 0 {
+        return 1.0;
+    }
+    target / (sum / count as f32).exp()
+}
+
+impl Framebuffer {
+    fn flipped_y(&self) -> Self {
+        fn flip<T: Copy>(pixels: &[T], width: u32) -> Vec<T> {
+            pixels
+                .chunks(width as usize)
+                .rev()
+                .flatten()
+                .copied()
+                .collect()
+        }
+        Self {
+            width: self.width,
+            height: self.height,
+            radiance: flip(&self.radiance, self.width),
+            foreground: flip(&self.foreground, self.width),
+            coverage: flip(&self.coverage, self.width),
+        }
+    }
+
+    fn exposed(&self, target: f32) -> Self {
+        let scale = exposure_scale(&self.radiance, target);
+        Self {
+            width: self.width,
+            height: self.height,
+            radiance: self.radiance.iter().map(|v| v * scale).collect(),
+            foreground: self.foreground.iter().map(|v| v * scale).collect(),
+            coverage: self.coverage.clone(),
+        }
+    }
+}
+
+fn corner_row(
+    scene: &Scene,
+    config: &RenderConfig,
+    columns: Range<u32>,
+    j: u32,
+) -> Vec<Option<usize>> {
+    // The object under each pixel corner along row edge j, one more than columns
+    (columns.start..=columns.end)
+        .map(|i| {
+            let (origin, dir) = camera_ray(i as f32, j as f32, config);
+            let hit = scene_intersect(origin, dir, scene, config.primary_range());
+            hit.map(|hit| hit.object)
+        })
+        .collect()
+}
+
+fn corner_objects(scene: &Scene, config: &RenderConfig, region: Region) -> Vec<Option<usize>> {
+    // The object under every pixel corner of the region, as a
+    // (width + 1) x (height + 1) grid
+    (region.y0..=region.y1)
+        .flat_map(|j| corner_row(scene, config, region.x0..region.x1, j))
+        .collect()
+}
+
+fn edge_aa_factor(config: &RenderConfig) -> u32 {
+    // Object IDs must stay flat, so edge antialiasing only applies when shading
+    if config.mode != RenderMode::ObjectId {
+        config.edge_aa.max(1)
+    } else {
+        1
+    }
+}
+
+// Rows handed to a worker thread at a time
+const TILE_ROWS: usize = 8;
+
+fn for_each_row<F>(
+    // Ignored by wasm32, which runs everything on the calling thread
+    #[cfg_attr(feature = "wasm", allow(unused_variables))] threads: usize,
+    region: Region,
+    sums: &mut [PixelSum],
+    cancel: &AtomicBool,
+    shade_row: F,
+) -> io::Result<u64>
+where
+    F: Fn(u32, &mut [PixelSum]) -> u64 + Sync,
+{
+    // Workers pull tiles of rows off a shared queue until it runs dry. Sampling
+    // only depends on the pixel and sample index, never on which thread or in
+    // what order a tile is rendered, so the output is the same for any thread count
+    let width = region.width() as usize;
+    let tiles = Mutex::new(sums.chunks_mut(width * TILE_ROWS).enumerate());
+    let rays = AtomicU64::new(0);
+    let work = || loop {
+        let Some((tile, rows)) = tiles.lock().unwrap().next() else {
+            return;
+        };
+        for (offset, row) in rows.chunks_mut(width).enumerate() {
+            // Check for cancellation once per row so aborting stays cheap
+            if cancel.load(Ordering::Relaxed) {
+                return;
+            }
+            let j = region.y0 + (tile * TILE_ROWS + offset) as u32;
+            rays.fetch_add(shade_row(j, row), Ordering::Relaxed);
+        }
+    };
+    // wasm32 cannot spawn threads, so the calling thread drains the queue itself
+    #[cfg(feature = "wasm")]
+    work();
+    #[cfg(not(feature = "wasm"))]
+    {
+        let counts = Mutex::new(TraceCounts::ZERO);
+        thread::scope(|scope| {
+            for _ in 0..threads.max(1) {
+                scope.spawn(|| {
+                    work();
+                    // Workers are fresh threads, so their counts are all this render's
+                    let mut total = counts.lock().unwrap();
+                    *total = *total + TRACE_COUNTS.get();
+                });
+            }
+        });
+        let total = counts.into_inner().unwrap();
+        count_trace(|counts| *counts = *counts + total);
+    }
+
+    if cancel.load(Ordering::Relaxed) {
+        return Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "render cancelled",
+        ));
+    }
+    Ok(rays.into_inner())
+}
+
+// Corner objects along the top and bottom edges of one row of pixels
+type CornerRows<'a> = (&'a [Option<usize>], &'a [Option<usize>]);
+
+fn shade_row(
+    scene: &Scene,
+    config: &RenderConfig,
+    j: u32,
+    columns: Range<u32>,
+    row: &mut [PixelSum],
+    corners: Option<CornerRows>,
+) -> u64 {
+    // corners is None when edge antialiasing is off, and starts at columns.start
+    let edge_aa = edge_aa_factor(config);
+    let mut rays = 0;
+    for (i, sum) in columns.clone().zip(row) {
+        // A pixel is on a silhouette edge when its corners don't all see the same object
+        let on_edge = corners.is_some_and(|(top, bottom)| {
+            let i = (i - columns.start) as usize;
+            [top[i + 1], bottom[i], bottom[i + 1]]
+                .iter()
+                .any(|&corner| corner != top[i])
+        });
+
+        if !on_edge {
+            rays += 1;
+            sum.add(sample_pixel(
+                scene,
+                config,
+                i,
+                j,
+                CameraSample::at(i as f32 + 0.5, j as f32 + 0.5),
+            ));
+            continue;
+        }
+
+        // Average a regular grid of sub-pixel samples, weighting by coverage
+        rays += (edge_aa * edge_aa) as u64;
+        for sj in 0..edge_aa {
+            for si in 0..edge_aa {
+                let px = i as f32 + (si as f32 + 0.5) / edge_aa as f32;
+                let py = j as f32 + (sj as f32 + 0.5) / edge_aa as f32;
+                sum.add(sample_pixel(scene, config, i, j, CameraSample::at(px, py)));
+            }
+        }
+    }
+    rays
+}
+
+fn render_radiance(
+    scene: &Scene,
+    config: &RenderConfig,
+    region: Region,
+    cancel: &AtomicBool,
+    stats: &mut RenderStats,
+) -> io::Result<Framebuffer> {
+    // The pixels of region only, at one sample per pixel
+    let edge_aa = edge_aa_factor(config) > 1;
+    let corners = if edge_aa {
+        stats.primary_rays += ((region.width() + 1) * (region.height() + 1)) as u64;
+        corner_objects(scene, config, region)
+    } else {
+        Vec::new()
+    };
+    let stride = (region.width() + 1) as usize;
+
+    let mut sums = vec![PixelSum::new(); (region.width() * region.height()) as usize];
+    stats.primary_rays += for_each_row(config.threads, region, &mut sums, cancel, |j, row| {
+        let j_corners = edge_aa.then(|| {
+            let top = (j - region.y0) as usize * stride;
+            (
+                &corners[top..top + stride],
+                &corners[top + stride..top + 2 * stride],
+            )
+        });
+        shade_row(scene, config, j, region.x0..region.x1, row, j_corners)
+    })?;
+    Ok(Framebuffer::from_sums(
+        region.width(),
+        region.height(),
+        &sums,
+    ))
+}
+
+fn stream_ppm(
+    scene: &Scene,
+    config: &RenderConfig,
+    cancel: &AtomicBool,
+    stats: &mut RenderStats,
+) -> io::Result<()> {
+    // Shade and write one row at a time on this thread, so only a row of
+    // pixels (and two rows of edge corners) is ever held in memory
+    let edge_aa = edge_aa_factor(config) > 1;
+    let mut out = BufWriter::new(File::create(&config.filename)?);
+    writeln!(out, "P6 {} {} 255", config.width, config.height)?;
+    let mut row = vec![PixelSum::new(); config.width as usize];
+    let mut carried: Option<(u32, Vec<Option<usize>>)> = None;
+    for output_row in 0..config.height {
+        if cancel.load(Ordering::Relaxed) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "render cancelled",
+            ));
+        }
+        let j = if config.flip_y {
+            config.height - 1 - output_row
+        } else {
+            output_row
+        };
+        let corners = edge_aa.then(|| {
+            // Consecutive rows share an edge, so one row of corners carries over
+            let mut edge_row = |edge: u32| match &carried {
+                Some((carried_edge, corners)) if *carried_edge == edge => corners.clone(),
+                _ => {
+                    stats.primary_rays += (config.width + 1) as u64;
+                    corner_row(scene, config, 0..config.width, edge)
+                }
+            };
+            let rows = (edge_row(j), edge_row(j + 1));
+            carried = Some(if config.flip_y {
+                (j, rows.0.clone())
+            } else {
+                (j + 1, rows.1.clone())
+            });
+            rows
+        });
+        row.fill(PixelSum::new());
+        stats.primary_rays += shade_row(
+            scene,
+            config,
+            j,
+            0..config.width,
+            &mut row,
+            corners
+                .as_ref()
+                .map(|(top, bottom)| (top.as_slice(), bottom.as_slice())),
+        );
+        let radiance: Vec<_> = row
+            .iter()
+            .map(|sum| sum.radiance / sum.samples.max(1) as f32)
+            .collect();
+        out.write_all(&quantize(&radiance, output_row, config))?;
+    }
+    out.flush()
+}
+
+fn debug_pixel(scene: &Scene, config: &RenderConfig, i: u32, j: u32) -> String {
+    // Describe the exact primary ray for a pixel center and what it hits,
+    // with j counted in output rows
+    let row = if config.flip_y {
+        config.height.saturating_sub(j + 1)
+    } else {
+        j
+    };
+    let (origin, dir) = camera_ray(i as f32 + 0.5, row as f32 + 0.5, config);
+    let mut report = format!(
+        "pixel ({}, {})\n  ray origin {:?} direction {:?}\n",
+        i,
+        j,
+        origin.as_slice(),
+        dir.as_slice()
+    );
+    match scene_intersect(origin, dir, scene, config.primary_range()) {
+        Some(hit) => {
+            report += &format!(
+                "  hit object {} at {:?} distance {}\n  normal {:?} geometric {:?} {}\n  diffuse {:?}\n",
+                hit.object,
+                hit.point.as_slice(),
+                hit.distance,
+                hit.normal.as_slice(),
+                hit.geometric_normal.as_slice(),
+                if hit.front_face { "front" } else { "back" },
+                hit.material.diffuse().as_slice()
+            )
+        }
+        None => report += "  miss\n",
+    }
+    let sample = sample_pixel(
+        scene,
+        config,
+        i,
+        row,
+        CameraSample::at(i as f32 + 0.5, row as f32 + 0.5),
+    );
+    report += &format!("  radiance {:?}", sample.color.as_slice());
+    report
+}
+
+fn splitmix(x: u64, value: u64) -> u64 {
+    // One SplitMix64 step, folding value into the state x
+    let x = (x ^ value).wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    let x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+fn unit_random(x: u64) -> f32 {
+    // The top 24 bits as a float in [0, 1)
+    (x >> 40) as f32 / (1u64 << 24) as f32
+}
+
+fn sample_random(seed: u64, i: u32, j: u32, sample: u32, dimension: u32) -> f32 {
+    // Hash the sample coordinates with SplitMix64 so every value is reproducible
+    // from the seed alone, independent of the order samples are taken in
+    unit_random(
+        [i, j, sample, dimension]
+            .into_iter()
+            .fold(seed, |x, value| splitmix(x, value as u64)),
+    )
+}
+
+struct Accumulator {
+    sums: Vec<PixelSum>,
+    samples: u32,
+}
+
+impl Accumulator {
+    fn new(region: Region) -> Self {
+        Self {
+            sums: vec![PixelSum::new(); (region.width() * region.height()) as usize],
+            samples: 0,
+        }
+    }
+
+    fn framebuffer(&self, region: Region) -> Framebuffer {
+        Framebuffer::from_sums(region.width(), region.height(), &self.sums)
+    }
+}
+
+// The display bytes of a whole-frame accumulation so far, as render_passes
+// would write them: finished at the output size, then quantized
+fn resolve(state: &Accumulator, config: &RenderConfig) -> Vec<u8> {
+    let preview = config.preview();
+    let internal = preview.as_ref().unwrap_or(config);
+    let framebuffer = finish(config, state.framebuffer(Region::full(internal)));
+    quantize(&framebuffer.radiance, 0, config)
+}
+
+fn accumulate_samples(
+    state: &mut Accumulator,
+    scene: &Scene,
+    config: &RenderConfig,
+    region: Region,
+    samples: u32,
+    cancel: &AtomicBool,
+    stats: &mut RenderStats,
+) -> io::Result<()> {
+    // Sample indices continue from the previous call, so accumulating in
+    // batches adds exactly the same samples as one call with the total count
+    let offset = state.samples;
+    stats.primary_rays +=
+        for_each_row(config.threads, region, &mut state.sums, cancel, |j, row| {
+            let mut rays = 0;
+            for (i, sum) in (region.x0..region.x1).zip(row) {
+                // Pixels with a lower count in the sample map stop early
+                let count = config.pixel_samples(i, j);
+                let end = (offset + samples).min(count);
+                rays += end.saturating_sub(offset) as u64;
+                for sample in offset..end {
+                    // Spread over the pixel, lens and shutter, but keep ID
+                    // renders flat at the pixel center
+                    let camera = match config.mode {
+                        RenderMode::Shaded | RenderMode::Lighting => {
+                            camera_sample(config, i, j, sample, count)
+                        }
+                        RenderMode::ObjectId => CameraSample::at(i as f32 + 0.5, j as f32 + 0.5),
+                    };
+                    sum.add(sample_pixel(scene, config, i, j, camera));
+                }
+            }
+            rays
+        })?;
+    state.samples += samples;
+    Ok(())
+}
+
+fn suffixed_filename(filename: &str, suffix: &str) -> String {
+    // output.ppm becomes output.albedo.ppm, keeping the extension and so the format
+    match filename.rsplit_once('.') {
+        Some((stem, extension)) => format!("{}.{}.{}", stem, suffix, extension),
+        None => format!("{}.{}", filename, suffix),
+    }
+}
+
+fn write_aov(
+    scene: &Scene,
+    config: &RenderConfig,
+    aov: Aov,
+    cancel: &AtomicBool,
+    stats: &mut RenderStats,
+) -> io::Result<()> {
+    // AOVs are data, written as is: one unjittered ray per pixel center at
+    // the beauty's resolution and camera, with no exposure or gamma
+    let output = config.data_output(suffixed_filename(&config.filename, aov.name()));
+    // PFM keeps the raw values. 8-bit formats get normals remapped from
+    // [-1, 1] and depth scaled by the farthest hit so they stay visible
+    let raw = output.filename.ends_with(".pfm");
+    let preview = output.preview();
+    let internal = preview.as_ref().unwrap_or(&output);
+    let mut sums = vec![PixelSum::new(); (internal.width * internal.height) as usize];
+    stats.primary_rays += for_each_row(
+        internal.threads,
+        Region::full(internal),
+        &mut sums,
+        cancel,
+        |j, row| {
+            for (i, sum) in (0..internal.width).zip(row) {
+                let (origin, dir) = camera_ray(i as f32 + 0.5, j as f32 + 0.5, internal);
+                let hit = scene_intersect(origin, dir, scene, internal.primary_range());
+                let color = hit.as_ref().map_or(Vector3::zeros(), |hit| match aov {
+                    Aov::Albedo => hit.material.diffuse(),
+                    Aov::Normal if raw => hit.normal,
+                    Aov::Normal => (hit.normal + Vector3::repeat(1.0)) / 2.0,
+                    Aov::Depth => Vector3::repeat(hit.distance),
+                });
+                sum.add(Sample {
+                    color,
+                    hit: hit.is_some(),
+                });
+            }
+            internal.width as u64
+        },
+    )?;
+    let mut framebuffer = finish(
+        &output,
+        Framebuffer::from_sums(internal.width, internal.height, &sums),
+    );
+    if aov == Aov::Depth && !raw {
+        let farthest = framebuffer
+            .radiance
+            .iter()
+            .fold(0.0, |max: f32, v| max.max(v.x));
+        let scale = 1.0 / farthest.max(f32::EPSILON);
+        for v in framebuffer
+            .radiance
+            .iter_mut()
+            .chain(&mut framebuffer.foreground)
+        {
+            *v *= scale;
+        }
+    }
+    write_image(&output, &framebuffer)
+}
+
+fn write_masks(scene: &Scene, config: &RenderConfig, stats: &mut RenderStats) -> io::Result<()> {
+    // One single-sample ray per pixel center decides the nearest object, so
+    // every pixel is white in exactly one of the object and background masks
+    let preview = config.preview();
+    let internal = preview.as_ref().unwrap_or(config);
+    let objects: Vec<Option<usize>> = (0..internal.height)
+        .flat_map(|j| {
+            (0..internal.width).map(move |i| {
+                let (origin, dir) = camera_ray(i as f32 + 0.5, j as f32 + 0.5, internal);
+                scene_intersect(origin, dir, scene, internal.primary_range()).map(|hit| hit.object)
+            })
+        })
+        .collect();
+    stats.primary_rays += objects.len() as u64;
+
+    let masks = (0..scene.object_count())
+        .map(|object| (Some(object), format!("mask{}", object)))
+        .chain([(None, "background".to_string())]);
+    for (object, suffix) in masks {
+        let coverage: Vec<f32> = objects
+            .iter()
+            .map(|&hit| if hit == object { 1.0 } else { 0.0 })
+            .collect();
+        let white: Vec<_> = coverage.iter().map(|&c| Vector3::repeat(c)).collect();
+        let mask = Framebuffer {
+            width: internal.width,
+            height: internal.height,
+            radiance: white.clone(),
+            foreground: white,
+            coverage,
+        };
+        let output = config.data_output(suffixed_filename(&config.filename, &suffix));
+        write_image(&output, &finish(&output, mask))?;
+    }
+    Ok(())
+}
+
+fn quantize(radiance: &[Vector3<f32>], first_row: u32, config: &RenderConfig) -> Vec<u8> {
+    // radiance holds whole output rows starting at first_row, which places
+    // each pixel for the dither
+    let mut pixels = Vec::with_capacity(radiance.len() * 3);
+    for (index, &v) in radiance.iter().enumerate() {
+        let pixel = pixel_at(index, first_row, config.width);
+        pixels.extend_from_slice(&display_transform(v, pixel, config));
+    }
+    pixels
+}
+
+fn quantize_rgba(framebuffer: &Framebuffer, config: &RenderConfig) -> Vec<u8> {
+    // Misses are fully transparent instead of showing the background,
+    // silhouette pixels get partial alpha from their coverage
+    let mut pixels = Vec::with_capacity(framebuffer.foreground.len() * 4);
+    let colors = framebuffer.foreground.iter().zip(&framebuffer.coverage);
+    for (index, (&v, &coverage)) in colors.enumerate() {
+        let [r, g, b] = display_transform(v, pixel_at(index, 0, framebuffer.width), config);
+        pixels.extend_from_slice(&[r, g, b, (255.0 * coverage.clamp(0.0, 1.0)) as u8]);
+    }
+    pixels
+}
+
+fn pixel_at(index: usize, first_row: u32, width: u32) -> (u32, u32) {
+    let index = index as u32;
+    (index % width, first_row + index / width)
+}
+
+fn finish(config: &RenderConfig, framebuffer: Framebuffer) -> Framebuffer {
+    // Preview renders come in at reduced size and are scaled up to the output size
+    let framebuffer = if (framebuffer.width, framebuffer.height) != (config.width, config.height) {
+        framebuffer.upsample(config.width, config.height, config.preview_filter)
+    } else {
+        framebuffer
+    };
+    let framebuffer = if config.flip_y {
+        framebuffer.flipped_y()
+    } else {
+        framebuffer
+    };
+    // Exposure is the last step before the radiance is written out or encoded
+    if config.auto_exposure {
+        framebuffer.exposed(config.exposure_target)
+    } else {
+        framebuffer
+    }
+}
+
+fn write_image(config: &RenderConfig, framebuffer: &Framebuffer) -> io::Result<()> {
+    let (width, height) = (framebuffer.width, framebuffer.height);
+    if config.filename.ends_with(".pfm") {
+        return write_pfm_image(&config.filename, width, height, &framebuffer.radiance);
+    }
+    if config.filename.ends_with(".csv") {
+        return write_csv_image(&config.filename, width, height, &framebuffer.radiance);
+    }
+    if config.filename.ends_with(".png") {
+        return write_png_image(
+            &config.filename,
+            width,
+            height,
+            &quantize_rgba(framebuffer, config),
+        );
+    }
+    write_ppm_image(
+        &config.filename,
+        width,
+        height,
+        &quantize(&framebuffer.radiance, 0, config),
+    )
+}
+
+#[derive(Debug, Default)]
+struct RenderStats {
+    primary_rays: u64,
+    shadow_rays: u64,
+    reflection_rays: u64,
+    refraction_rays: u64,
+    indirect_rays: u64,
+    intersection_tests: u64,
+    elapsed: Duration,
+}
+
+impl fmt::Display for RenderStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "primary rays:       {}", self.primary_rays)?;
+        writeln!(f, "shadow rays:        {}", self.shadow_rays)?;
+        writeln!(f, "reflection rays:    {}", self.reflection_rays)?;
+        writeln!(f, "refraction rays:    {}", self.refraction_rays)?;
+        writeln!(f, "indirect rays:      {}", self.indirect_rays)?;
+        writeln!(f, "intersection tests: {}", self.intersection_tests)?;
+        write!(f, "elapsed:            {:.3?}", self.elapsed)
+    }
+}
+
+// Renders the scene to the output files. Given the old scene and the previous
+// image of it, the image is updated instead of rendered from scratch
+fn render(
+    scene: &Scene,
+    config: &RenderConfig,
+    cancel: &AtomicBool,
+    update: Option<(&Scene, Image)>,
+) -> io::Result<RenderStats> {
+    render_with(|stats| {
+        if let Some((old, previous)) = update {
+            // The file was written after finish, and only flipping can be undone
+            let previous = Framebuffer::from_image(previous);
+            let previous = if config.flip_y {
+                previous.flipped_y()
+            } else {
+                previous
+            };
+            let (framebuffer, _) = rerender_changed(old, scene, config, &previous, cancel, stats)?;
+            write_image(config, &finish(config, framebuffer))?;
+        } else if config.stream {
+            stream_ppm(scene, config, cancel, stats)?;
+        } else {
+            render_passes(scene, config, cancel, stats, |framebuffer| {
+                write_image(config, &framebuffer)
+            })?;
+        }
+        for &aov in &config.aovs {
+            write_aov(scene, config, aov, cancel, stats)?;
+        }
+        if config.masks {
+            write_masks(scene, config, stats)?;
+        }
+        Ok(())
+    })
+}
+
+// Renders without touching the filesystem, returning the final image as RGB
+// bytes. This backs --output -, which is how a wasm32 build of the binary
+// hands its image to the host
+fn render_to_buffer(
+    scene: &Scene,
+    config: &RenderConfig,
+    cancel: &AtomicBool,
+) -> io::Result<(Vec<u8>, RenderStats)> {
+    let mut pixels = Vec::new();
+    let stats = render_with(|stats| {
+        render_passes(scene, config, cancel, stats, |framebuffer| {
+            pixels = quantize(&framebuffer.radiance, 0, config);
+            Ok(())
+        })
+    })?;
+    Ok((pixels, stats))
+}
+
+fn render_with<F>(run: F) -> io::Result<RenderStats>
+where
+    F: FnOnce(&mut RenderStats) -> io::Result<()>,
+{
+    #[cfg(not(feature = "wasm"))]
+    let start = Instant::now();
+    let start_counts = TRACE_COUNTS.get();
+    let mut stats = RenderStats::default();
+    run(&mut stats)?;
+    let counts = TRACE_COUNTS.get() - start_counts;
+    stats.intersection_tests = counts.intersection_tests;
+    stats.shadow_rays = counts.shadow_rays;
+    stats.reflection_rays = counts.reflection_rays;
+    stats.refraction_rays = counts.refraction_rays;
+    stats.indirect_rays = counts.indirect_rays;
+    #[cfg(not(feature = "wasm"))]
+    {
+        stats.elapsed = start.elapsed();
+    }
+    Ok(stats)
+}
+
+fn render_passes<F>(
+    scene: &Scene,
+    config: &RenderConfig,
+    cancel: &AtomicBool,
+    stats: &mut RenderStats,
+    mut emit: F,
+) -> io::Result<()>
+where
+    F: FnMut(Framebuffer) -> io::Result<()>,
+{
+    let preview = config.preview();
+    let internal = preview.as_ref().unwrap_or(config);
+    let region = Region::full(internal);
+    let max_samples = config.max_samples();
+    if max_samples <= 1 {
+        return emit(finish(
+            config,
+            render_radiance(scene, internal, region, cancel, stats)?,
+        ));
+    }
+
+    // Write the refined image after every batch when progressive output is
+    // on, and only once at the end otherwise
+    let mut state = Accumulator::new(region);
+    accumulate_passes(&mut state, scene, internal, cancel, stats, |state| {
+        if config.progressive || state.samples == max_samples {
+            emit(finish(config, state.framebuffer(region)))?;
+        }
+        Ok(())
+    })
+}
+
+fn accumulate_passes<F>(
+    state: &mut Accumulator,
+    scene: &Scene,
+    internal: &RenderConfig,
+    cancel: &AtomicBool,
+    stats: &mut RenderStats,
+    mut pass: F,
+) -> io::Result<()>
+where
+    F: FnMut(&Accumulator) -> io::Result<()>,
+{
+    // Accumulate the whole frame in doubling batches up to the largest sample
+    // count, handing the state to pass after each one
+    let region = Region::full(internal);
+    let max_samples = internal.max_samples();
+    let mut batch = 1;
+    while state.samples < max_samples {
+        let samples = batch.min(max_samples - state.samples);
+        accumulate_samples(state, scene, internal, region, samples, cancel, stats)?;
+        pass(state)?;
+        batch *= 2;
+    }
+    Ok(())
+}
+
+// Writes every pass of a progressive render to out as a PPM of its own, so a
+// viewer reading the stream can show the image as it refines
+fn render_frames(
+    scene: &Scene,
+    config: &RenderConfig,
+    cancel: &AtomicBool,
+    mut out: impl Write,
+) -> io::Result<RenderStats> {
+    if config.max_samples() <= 1 {
+        let (pixels, stats) = render_to_buffer(scene, config, cancel)?;
+        write_ppm(&mut out, config.width, config.height, &pixels)?;
+        return Ok(stats);
+    }
+    render_with(|stats| {
+        let preview = config.preview();
+        let internal = preview.as_ref().unwrap_or(config);
+        let mut state = Accumulator::new(Region::full(internal));
+        accumulate_passes(&mut state, scene, internal, cancel, stats, |state| {
+            write_ppm(
+                &mut out,
+                config.width,
+                config.height,
+                &resolve(state, config),
+            )?;
+            out.flush()
+        })
+    })
+}
+
+// What the viewer loop shows frames on and takes input from. A window crate
+// such as minifb backs it with a window: present turns the RGB bytes into its
+// 0RGB pixels and calls update_with_buffer, and drag reports how far the
+// mouse moved with the left button down since the last frame
+#[cfg(feature = "viewer")]
+trait ViewerWindow {
+    fn is_open(&self) -> bool;
+    fn present(&mut self, width: u32, height: u32, rgb: &[u8]) -> io::Result<()>;
+    fn drag(&mut self) -> Option<(f32, f32)>;
+
+    // Called when the image has every sample, windows stay open for input
+    fn settled(&mut self) {}
+}
+
+// Frames on stdout as a PPM stream, for any viewer that can read one until a
+// window crate is linked in. It takes no input, and closes once the image
+// has converged
+#[cfg(feature = "viewer")]
+struct PpmStreamWindow<W: Write> {
+    out: W,
+    open: bool,
+}
+
+#[cfg(feature = "viewer")]
+impl<W: Write> ViewerWindow for PpmStreamWindow<W> {
+    fn is_open(&self) -> bool {
+        self.open
+    }
+
+    fn present(&mut self, width: u32, height: u32, rgb: &[u8]) -> io::Result<()> {
+        write_ppm(&mut self.out, width, height, rgb)?;
+        self.out.flush()
+    }
+
+    fn drag(&mut self) -> Option<(f32, f32)> {
+        None
+    }
+
+    fn settled(&mut self) {
+        self.open = false;
+    }
+}
+
+// Progressive frames pulled one at a time, as a window loop wants them: each
+// call to frame adds a doubling batch of samples and returns the refined
+// image. A camera change starts the accumulation over
+#[cfg(feature = "viewer")]
+struct Viewer<'a> {
+    scene: &'a Scene,
+    config: RenderConfig,
+    state: Accumulator,
+    batch: u32,
+}
+
+#[cfg(feature = "viewer")]
+impl<'a> Viewer<'a> {
+    fn new(scene: &'a Scene, config: RenderConfig) -> Self {
+        let state = Accumulator::new(Region::full(&Self::internal(&config)));
+        Self {
+            scene,
+            config,
+            state,
+            batch: 1,
+        }
+    }
+
+    fn internal(config: &RenderConfig) -> RenderConfig {
+        config.preview().unwrap_or_else(|| config.clone())
+    }
+
+    fn converged(&self) -> bool {
+        self.state.samples >= self.config.max_samples()
+    }
+
+    // The next frame as width * height RGB bytes. Once every sample is in,
+    // frames repeat the finished image without tracing any more
+    fn frame(&mut self, cancel: &AtomicBool, stats: &mut RenderStats) -> io::Result<Vec<u8>> {
+        let internal = Self::internal(&self.config);
+        if !self.converged() {
+            let samples = self.batch.min(internal.max_samples() - self.state.samples);
+            let region = Region::full(&internal);
+            accumulate_samples(
+                &mut self.state,
+                self.scene,
+                &internal,
+                region,
+                samples,
+                cancel,
+                stats,
+            )?;
+            self.batch *= 2;
+        }
+        Ok(resolve(&self.state, &self.config))
+    }
+
+    // The camera has no position to orbit, so dragging across turns it about
+    // the view axis and dragging up and down zooms, a degree per pixel
+    fn drag(&mut self, dx: f32, dy: f32) {
+        self.config.roll += dx.to_radians();
+        self.config.fov = (self.config.fov + dy.to_radians()).clamp(0.01, PI - 0.01);
+        *self = Self::new(self.scene, self.config.clone());
+    }
+}
+
+#[cfg(feature = "viewer")]
+fn run_viewer(
+    scene: &Scene,
+    config: &RenderConfig,
+    cancel: &AtomicBool,
+    window: &mut impl ViewerWindow,
+) -> io::Result<RenderStats> {
+    render_with(|stats| {
+        let mut viewer = Viewer::new(scene, config.clone());
+        while window.is_open() && !cancel.load(Ordering::Relaxed) {
+            if let Some((dx, dy)) = window.drag() {
+                viewer.drag(dx, dy);
+            }
+            let frame = viewer.frame(cancel, stats)?;
+            window.present(config.width, config.height, &frame)?;
+            if viewer.converged() {
+                window.settled();
+            }
+        }
+        Ok(())
+    })
+}
+
+fn project_bounds(bounds: Bounds, config: &RenderConfig) -> Option<Region> {
+    // The pixels a box can cover, camera_ray run backwards on its corners,
+    // with a pixel to spare for the edge antialiasing corners. None when part
+    // of the box is behind the camera and could cover anything
+    let (width, height) = (config.width as f32, config.height as f32);
+    let half_fov_tan = (config.fov / 2.0).tan();
+    let (right, up, forward) = config.camera_basis();
+    let mut min = Vector3::repeat(f32::INFINITY);
+    let mut max = Vector3::repeat(f32::NEG_INFINITY);
+    for corner in bounds.corners() {
+        let depth = corner.dot(&forward);
+        if depth <= f32::EPSILON {
+            return None;
+        }
+        let x = corner.dot(&right) / depth / (half_fov_tan * (width / height));
+        let y = corner.dot(&up) / depth / half_fov_tan;
+        let pixel = Vector3::new((x + 1.0) * width / 2.0, (1.0 - y) * height / 2.0, 0.0);
+        min = min.inf(&pixel);
+        max = max.sup(&pixel);
+    }
+    let clamp = |v: f32, size: f32| v.clamp(0.0, size) as u32;
+    Some(Region {
+        x0: clamp(min.x.floor() - 1.0, width),
+        y0: clamp(min.y.floor() - 1.0, height),
+        x1: clamp(max.x.ceil() + 1.0, width),
+        y1: clamp(max.y.ceil() + 1.0, height),
+    })
+}
+
+// Each object's hash and bounds, spheres, meshes, differences and instances apart
+fn object_summaries(scene: &Scene) -> [Vec<(u64, Bounds)>; 4] {
+    let summary = |object: &dyn Hittable| {
+        let mut h = Fnv::new();
+        object.hash(&mut h);
+        (h.0, object.bounds())
+    };
+    let mesh_summary = |mesh: &[Triangle]| {
+        let mut h = Fnv::new();
+        mesh.iter().for_each(|triangle| triangle.hash(&mut h));
+        let bounds = mesh
+            .iter()
+            .map(Hittable::bounds)
+            .fold(Bounds::empty(), Bounds::union);
+        (h.0, bounds)
+    };
+    [
+        scene.spheres.iter().map(|sphere| summary(sphere)).collect(),
+        scene.meshes().map(mesh_summary).collect(),
+        scene
+            .differences
+            .iter()
+            .map(|difference| summary(difference))
+            .collect(),
+        scene
+            .instances
+            .iter()
+            .map(|instance| summary(instance))
+            .collect(),
+    ]
+}
+
+fn changed_region(old: &Scene, new: &Scene, config: &RenderConfig) -> Option<Region> {
+    // The pixels that can differ between renders of the two scenes, None if
+    // none can. Without shadows an object only shows where it's seen, so only
+    // added, removed and edited objects count, where they were and where they
+    // are. Shadows and the lighting reach across the whole frame, as does
+    // renumbering the objects of an ID render
+    let (old_summaries, new_summaries) = (object_summaries(old), object_summaries(new));
+    let lighting = |scene: &Scene| {
+        let mut h = Fnv::new();
+        h.lighting(scene);
+        h.0
+    };
+    let renumbered = old_summaries
+        .iter()
+        .zip(&new_summaries)
+        .any(|(old, new)| old.len() != new.len());
+    if config.shadows
+        || lighting(old) != lighting(new)
+        || (config.mode == RenderMode::ObjectId && renumbered)
+    {
+        return Some(Region::full(config));
+    }
+    let mut region: Option<Region> = None;
+    for (old, new) in old_summaries.iter().zip(&new_summaries) {
+        for k in 0..old.len().max(new.len()) {
+            let (was, is) = (old.get(k), new.get(k));
+            if was.map(|&(hash, _)| hash) == is.map(|&(hash, _)| hash) {
+                continue;
+            }
+            for &(_, bounds) in was.into_iter().chain(is) {
+                if bounds.is_empty() {
+                    continue;
+                }
+                let Some(projected) = project_bounds(bounds, config) else {
+                    return Some(Region::full(config));
+                };
+                if !projected.is_empty() {
+                    region = Some(region.map_or(projected, |region| region.union(projected)));
+                }
+            }
+        }
+    }
+    region
+}
+
+impl Framebuffer {
+    fn from_image(image: Image) -> Self {
+        // An image has no alpha, so every pixel counts as foreground
+        Self {
+            width: image.width,
+            height: image.height,
+            coverage: vec![1.0; image.pixels.len()],
+            foreground: image.pixels.clone(),
+            radiance: image.pixels,
+        }
+    }
+
+    fn paste(&mut self, patch: &Framebuffer, region: Region) {
+        let width = region.width() as usize;
+        for (row, j) in (region.y0..region.y1).enumerate() {
+            let start = (j * self.width + region.x0) as usize;
+            let (to, from) = (start..start + width, row * width..(row + 1) * width);
+            self.radiance[to.clone()].copy_from_slice(&patch.radiance[from.clone()]);
+            self.foreground[to.clone()].copy_from_slice(&patch.foreground[from.clone()]);
+            self.coverage[to].copy_from_slice(&patch.coverage[from]);
+        }
+    }
+}
+
+// Updates a render of the old scene into one of the new, re-rendering only
+// the pixels the change can reach. previous is at the internal resolution and
+// before finish, which the result then goes through as a full render would.
+// Also returns the region re-rendered, None when nothing changed
+fn rerender_changed(
+    old: &Scene,
+    new: &Scene,
+    config: &RenderConfig,
+    previous: &Framebuffer,
+    cancel: &AtomicBool,
+    stats: &mut RenderStats,
+) -> io::Result<(Framebuffer, Option<Region>)> {
+    let preview = config.preview();
+    let internal = preview.as_ref().unwrap_or(config);
+    if (previous.width, previous.height) != (internal.width, internal.height) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "previous image is {}x{}, expected {}x{}",
+                previous.width, previous.height, internal.width, internal.height
+            ),
+        ));
+    }
+    let mut framebuffer = previous.clone();
+    let Some(region) = changed_region(old, new, internal) else {
+        return Ok((framebuffer, None));
+    };
+    // All samples in one batch, which sums them exactly as the doubling batches do
+    let max_samples = internal.max_samples();
+    let patch = if max_samples <= 1 {
+        render_radiance(new, internal, region, cancel, stats)?
+    } else {
+        let mut state = Accumulator::new(region);
+        accumulate_samples(
+            &mut state,
+            new,
+            internal,
+            region,
+            max_samples,
+            cancel,
+            stats,
+        )?;
+        state.framebuffer(region)
+    };
+    framebuffer.paste(&patch, region);
+    Ok((framebuffer, Some(region)))
+}
+
+// 64-bit FNV-1a, simple and stable across builds and platforms, unlike
+// std's randomly seeded hashers
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    fn f32(&mut self, v: f32) {
+        // Hash the bit pattern, so equal inputs hash equal without float rounding
+        self.bytes(&v.to_bits().to_le_bytes());
+    }
+
+    fn vec(&mut self, v: Vector3<f32>) {
+        v.iter().for_each(|&c| self.f32(c));
+    }
+
+    fn optional_f32(&mut self, v: Option<f32>) {
+        match v {
+            Some(v) => {
+                self.u64(1);
+                self.f32(v);
+            }
+            None => self.u64(0),
+        }
+    }
+
+    fn str(&mut self, s: &str) {
+        // Length first so adjacent strings can't run into each other
+        self.u64(s.len() as u64);
+        self.bytes(s.as_bytes());
+    }
+
+    fn debug(&mut self, v: &impl fmt::Debug) {
+        self.str(&format!("{:?}", v));
+    }
+
+    fn material(&mut self, material: &Material) {
+        match material {
+            Material::Surface(surface) => {
+                self.u64(0);
+                self.surface(surface);
+            }
+            Material::Blend { a, b, factor } => {
+                self.u64(1);
+                self.surface(a);
+                self.surface(b);
+                self.f32(*factor);
+            }
+        }
+    }
+
+    fn surface(&mut self, surface: &Surface) {
+        self.vec(surface.diffuse_color);
+        self.vec(surface.transmission);
+        self.debug(&surface.shading);
+        self.u64(surface.two_sided as u64);
+        self.vec(surface.ior);
+        self.f32(surface.roughness);
+        self.f32(surface.specular);
+        self.f32(surface.shininess);
+        self.debug(&surface.anisotropy);
+        self.debug(&(surface.texture, surface.normal_map, surface.texture_filter));
+    }
+
+    fn image(&mut self, image: &Image) {
+        self.u64(image.width as u64);
+        self.u64(image.height as u64);
+        image.pixels.iter().for_each(|&p| self.vec(p));
+    }
+
+    fn lighting(&mut self, scene: &Scene) {
+        // The lights, backplate and textures, everything in a scene besides
+        // its objects
+        self.u64(scene.lights.len() as u64);
+        for light in &scene.lights {
+            self.vec(light.position);
+            self.f32(light.intensity);
+            self.debug(&light.group);
+        }
+        match &scene.backplate {
+            Some(backplate) => {
+                self.image(&backplate.image);
+                self.debug(&backplate.fit);
+            }
+            None => self.u64(0),
+        }
+        self.u64(scene.textures.len() as u64);
+        scene
+            .textures
+            .iter()
+            .for_each(|texture| self.image(texture));
+        self.debug(&(scene.background, scene.reflection_background));
+        match &scene.environment {
+            Some(environment) => self.image(&environment.image),
+            None => self.u64(0),
+        }
+    }
+
+    fn sphere(&mut self, sphere: &Sphere) {
+        self.vec(sphere.center);
+        self.f32(sphere.radius);
+        self.material(&sphere.material);
+    }
+}
+
+fn render_hash(scene: &Scene, config: &RenderConfig) -> u64 {
+    // Everything that changes the output pixels, and nothing else: the
+    // output path, thread count, timeout and reporting flags are left out
+    let mut h = Fnv::new();
+    h.u64(scene.spheres.len() as u64);
+    scene.spheres.iter().for_each(|sphere| h.sphere(sphere));
+    h.u64(scene.triangles.len() as u64);
+    for (triangle, &mesh) in scene.triangles.iter().zip(&scene.triangle_meshes) {
+        h.u64(mesh as u64);
+        triangle.hash(&mut h);
+    }
+    h.u64(scene.differences.len() as u64);
+    scene
+        .differences
+        .iter()
+        .for_each(|difference| difference.hash(&mut h));
+    h.u64(scene.instances.len() as u64);
+    scene
+        .instances
+        .iter()
+        .for_each(|instance| instance.hash(&mut h));
+    h.lighting(scene);
+
+    h.u64(config.width as u64);
+    h.u64(config.height as u64);
+    for v in [
+        config.fov,
+        config.roll,
+        config.aperture,
+        config.focus_distance,
+        config.fog_density,
+        config.light_cutoff,
+        config.near,
+        config.far,
+        config.exposure,
+        config.gamma,
+        config.exposure_target,
+        config.rim_power,
+        config.shadow_softness,
+        config.light_radius,
+        config.min_throughput,
+    ] {
+        h.f32(v);
+    }
+    for v in [
+        config.world_up,
+        config.camera_motion,
+        config.ambient_sky,
+        config.ambient_ground,
+        config.fog_color,
+        config.sky_horizon,
+        config.sky_zenith,
+        config.rim_color,
+    ] {
+        h.vec(v);
+    }
+    let mut groups: Vec<_> = config.light_groups.iter().collect();
+    groups.sort_by(|a, b| a.0.cmp(b.0));
+    for (group, multiplier) in groups {
+        h.str(group);
+        h.f32(*multiplier);
+    }
+    match config.diffuse_limit {
+        DiffuseLimit::Unclamped => h.u64(0),
+        DiffuseLimit::Clamp(max) => {
+            h.u64(1);
+            h.f32(max);
+        }
+        DiffuseLimit::SoftKnee(max) => {
+            h.u64(2);
+            h.f32(max);
+        }
+    }
+    h.optional_f32(config.light_markers);
+    h.optional_f32(config.wireframe);
+    h.debug(&(
+        config.mode,
+        config.tone_map,
+        config.gamut,
+        config.preview_filter,
+    ));
+    h.debug(&(
+        config.legacy_background,
+        config.dither,
+        config.auto_exposure,
+    ));
+    h.debug(&(
+        config.flag_nonfinite,
+        config.flip_y,
+        config.shadows,
+        config.shader.is_some(),
+        config.classic_specular,
+        config.spectral,
+    ));
+    match &config.sample_map {
+        Some(map) => map.iter().for_each(|&count| h.u64(count as u64)),
+        None => h.u64(0),
+    }
+    h.u64(config.edge_aa as u64);
+    h.u64(config.samples as u64);
+    h.u64(config.shadow_samples as u64);
+    h.u64(config.max_depth as u64);
+    h.u64(config.glossy_samples as u64);
+    h.u64(config.indirect_samples as u64);
+    h.u64(config.seed);
+    h.u64(config.preview_scale as u64);
+    h.0
+}
+
+fn sample_counts(map: &Image, config: &RenderConfig) -> io::Result<Arc<[u32]>> {
+    // Full red gets --samples, every pixel gets at least one
+    if (map.width, map.height) != (config.width, config.height) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "sample map is {}x{} but the output is {}x{}",
+                map.width, map.height, config.width, config.height
+            ),
+        ));
+    }
+    Ok(map
+        .pixels
+        .iter()
+        .map(|pixel| ((pixel.x * config.samples as f32).ceil() as u32).max(1))
+        .collect())
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn json_f32(v: f32) -> String {
+    // JSON has no infinities or NaN
+    if v.is_finite() {
+        v.to_string()
+    } else {
+        "null".to_string()
+    }
+}
+
+fn json_vec(v: Vector3<f32>) -> String {
+    format!("[{}, {}, {}]", json_f32(v.x), json_f32(v.y), json_f32(v.z))
+}
+
+fn json_option(v: Option<String>) -> String {
+    v.unwrap_or_else(|| "null".to_string())
+}
+
+fn json_debug(v: &impl fmt::Debug) -> String {
+    json_string(&format!("{:?}", v))
+}
+
+fn json_vec2(v: Vector2<f32>) -> String {
+    format!("[{}, {}]", json_f32(v.x), json_f32(v.y))
+}
+
+fn json_array(items: impl Iterator<Item = String>) -> String {
+    format!("[{}]", items.collect::<Vec<_>>().join(", "))
+}
+
+fn json_object(fields: &[(&str, String)]) -> String {
+    let fields: Vec<_> = fields
+        .iter()
+        .map(|(name, value)| format!("{}: {}", json_string(name), value))
+        .collect();
+    format!("{{{}}}", fields.join(", "))
+}
+
+fn manifest_config(config: &RenderConfig) -> Vec<(&'static str, String)> {
+    // The resolved settings, after presets, focal length and defaults, for
+    // reading back what a render used. Replaying goes through the arguments
+    let strings = |values: &[String]| {
+        let values: Vec<_> = values.iter().map(|v| json_string(v)).collect();
+        format!("[{}]", values.join(", "))
+    };
+    let mut groups: Vec<_> = config.light_groups.iter().collect();
+    groups.sort_by(|a, b| a.0.cmp(b.0));
+    let groups: Vec<_> = groups
+        .into_iter()
+        .map(|(group, multiplier)| format!("{}: {}", json_string(group), json_f32(*multiplier)))
+        .collect();
+    let aovs: Vec<_> = config
+        .aovs
+        .iter()
+        .map(|aov| json_string(aov.name()))
+        .collect();
+    vec![
+        ("width", config.width.to_string()),
+        ("height", config.height.to_string()),
+        ("fov", json_f32(config.fov)),
+        ("roll", json_f32(config.roll)),
+        ("world_up", json_vec(config.world_up)),
+        ("aperture", json_f32(config.aperture)),
+        ("focus_distance", json_f32(config.focus_distance)),
+        ("camera_motion", json_vec(config.camera_motion)),
+        ("near", json_f32(config.near)),
+        ("far", json_f32(config.far)),
+        ("demo", json_debug(&config.demo)),
+        ("meshes", strings(&config.meshes)),
+        (
+            "scene_file",
+            json_option(config.scene_file.as_deref().map(json_string)),
+        ),
+        ("single_sided", config.single_sided.to_string()),
+        (
+            "texture",
+            json_option(config.texture.as_deref().map(json_string)),
+        ),
+        (
+            "normal_map",
+            json_option(config.normal_map.as_deref().map(json_string)),
+        ),
+        ("texture_filter", json_debug(&config.texture_filter)),
+        (
+            "previous_image",
+            json_option(config.previous_image.as_deref().map(json_string)),
+        ),
+        ("previous_meshes", strings(&config.previous_meshes)),
+        (
+            "backplate",
+            json_option(config.backplate.as_deref().map(json_string)),
+        ),
+        ("backplate_fit", json_debug(&config.backplate_fit)),
+        (
+            "environment",
+            json_option(config.environment.as_deref().map(json_string)),
+        ),
+        ("mode", json_debug(&config.mode)),
+        ("custom_shader", config.shader.is_some().to_string()),
+        ("samples", config.samples.to_string()),
+        (
+            "sample_map",
+            json_option(config.sample_map_file.as_deref().map(json_string)),
+        ),
+        ("seed", config.seed.to_string()),
+        ("edge_aa", config.edge_aa.to_string()),
+        ("preview_scale", config.preview_scale.to_string()),
+        ("preview_filter", json_debug(&config.preview_filter)),
+        ("ambient_sky", json_vec(config.ambient_sky)),
+        ("ambient_ground", json_vec(config.ambient_ground)),
+        ("sky_horizon", json_vec(config.sky_horizon)),
+        ("sky_zenith", json_vec(config.sky_zenith)),
+        ("legacy_background", config.legacy_background.to_string()),
+        ("background", json_debug(&config.background)),
+        (
+            "reflection_background",
+            json_option(config.reflection_background.as_ref().map(json_debug)),
+        ),
+        ("fog_color", json_vec(config.fog_color)),
+        ("fog_density", json_f32(config.fog_density)),
+        ("light_groups", format!("{{{}}}", groups.join(", "))),
+        ("light_cutoff", json_f32(config.light_cutoff)),
+        ("diffuse_limit", json_debug(&config.diffuse_limit)),
+        ("rim_color", json_vec(config.rim_color)),
+        ("rim_power", json_f32(config.rim_power)),
+        (
+            "light_markers",
+            json_option(config.light_markers.map(json_f32)),
+        ),
+        ("wireframe", json_option(config.wireframe.map(json_f32))),
+        ("auto_exposure", config.auto_exposure.to_string()),
+        ("exposure_target", json_f32(config.exposure_target)),
+        ("exposure", json_f32(config.exposure)),
+        ("tone_map", json_debug(&config.tone_map)),
+        ("gamut", json_debug(&config.gamut)),
+        ("gamma", json_f32(config.gamma)),
+        ("dither", config.dither.to_string()),
+        ("flag_nonfinite", config.flag_nonfinite.to_string()),
+        ("flip_y", config.flip_y.to_string()),
+        ("shadows", config.shadows.to_string()),
+        ("shadow_softness", json_f32(config.shadow_softness)),
+        ("light_radius", json_f32(config.light_radius)),
+        ("shadow_samples", config.shadow_samples.to_string()),
+        ("max_depth", config.max_depth.to_string()),
+        ("glossy_samples", config.glossy_samples.to_string()),
+        ("min_throughput", json_f32(config.min_throughput)),
+        ("indirect_samples", config.indirect_samples.to_string()),
+        ("classic_specular", config.classic_specular.to_string()),
+        ("spectral", config.spectral.to_string()),
+        ("aovs", format!("[{}]", aovs.join(", "))),
+        ("masks", config.masks.to_string()),
+    ]
+}
+
+// Records what is needed to reproduce a render: the arguments the config was
+// parsed from, the config they resolved to, the hash of the scene and config,
+// and how long it took. Meshes, backplates and sample maps are referenced by
+// path, so they must be kept alongside for the hash to match again
+fn write_manifest(
+    path: &str,
+    args: &[String],
+    scene: &Scene,
+    config: &RenderConfig,
+    stats: &RenderStats,
+) -> io::Result<()> {
+    let args: Vec<_> = args.iter().map(|arg| json_string(arg)).collect();
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "{{")?;
+    writeln!(
+        file,
+        "  \"version\": {},",
+        json_string(env!("CARGO_PKG_VERSION"))
+    )?;
+    writeln!(file, "  \"args\": [{}],", args.join(", "))?;
+    writeln!(file, "  \"output\": {},", json_string(&config.filename))?;
+    writeln!(file, "  \"config\": {{")?;
+    let fields = manifest_config(config);
+    for (index, (name, value)) in fields.iter().enumerate() {
+        let separator = if index + 1 < fields.len() { "," } else { "" };
+        writeln!(file, "    {}: {}{}", json_string(name), value, separator)?;
+    }
+    writeln!(file, "  }},")?;
+    writeln!(
+        file,
+        "  \"render_hash\": \"{:016x}\",",
+        render_hash(scene, config)
+    )?;
+    writeln!(file, "  \"primary_rays\": {},", stats.primary_rays)?;
+    writeln!(file, "  \"shadow_rays\": {},", stats.shadow_rays)?;
+    writeln!(file, "  \"reflection_rays\": {},", stats.reflection_rays)?;
+    writeln!(file, "  \"refraction_rays\": {},", stats.refraction_rays)?;
+    writeln!(file, "  \"indirect_rays\": {},", stats.indirect_rays)?;
+    writeln!(
+        file,
+        "  \"elapsed_seconds\": {}",
+        stats.elapsed.as_secs_f64()
+    )?;
+    writeln!(file, "}}")?;
+    file.flush()
+}
+
+// Just enough JSON to read manifests and scene files back
+#[derive(Debug)]
+enum Json {
+    String(String),
+    Number(f64),
+    Bool(bool),
+    Null,
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(text: &str) -> io::Result<Json> {
+        let mut parser = JsonParser {
+            bytes: text.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value()?;
+        parser.whitespace();
+        if parser.pos != parser.bytes.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(name, _)| name == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_f32(&self) -> Option<f32> {
+        match self {
+            Json::Number(v) => Some(*v as f32),
+            _ => None,
+        }
+    }
+
+    fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+struct JsonParser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl JsonParser<'_> {
+    fn error(&self, message: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid JSON at byte {}: {}", self.pos, message),
+        )
+    }
+
+    fn whitespace(&mut self) {
+        while self.pos < self.bytes.len() && self.bytes[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        self.whitespace();
+        let found = self.bytes.get(self.pos) == Some(&byte);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, byte: u8) -> io::Result<()> {
+        if self.eat(byte) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", byte as char)))
+        }
+    }
+
+    fn value(&mut self) -> io::Result<Json> {
+        self.whitespace();
+        match self.bytes.get(self.pos) {
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if !self.eat(b']') {
+                    loop {
+                        items.push(self.value()?);
+                        if self.eat(b']') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Json::Array(items))
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                if !self.eat(b'}') {
+                    loop {
+                        self.whitespace();
+                        let name = self.string()?;
+                        self.expect(b':')?;
+                        fields.push((name, self.value()?));
+                        if self.eat(b'}') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Json::Object(fields))
+            }
+            _ => self.scalar(),
+        }
+    }
+
+    fn scalar(&mut self) -> io::Result<Json> {
+        let rest = &self.bytes[self.pos..];
+        for (literal, value) in [
+            (&b"null"[..], Json::Null),
+            (b"true", Json::Bool(true)),
+            (b"false", Json::Bool(false)),
+        ] {
+            if rest.starts_with(literal) {
+                self.pos += literal.len();
+                return Ok(value);
+            }
+        }
+        let length = rest
+            .iter()
+            .take_while(|b| b.is_ascii_digit() || b"+-.eE".contains(b))
+            .count();
+        let number = std::str::from_utf8(&rest[..length]).unwrap_or("");
+        let Ok(number) = number.parse::<f64>() else {
+            return Err(self.error("expected a value"));
+        };
+        self.pos += length;
+        Ok(Json::Number(number))
+    }
+
+    fn hex4(&mut self) -> io::Result<u32> {
+        let digits = self
+            .bytes
+            .get(self.pos..self.pos + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| self.error("invalid \\u escape"))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        if self.bytes.get(self.pos) != Some(&b'"') {
+            return Err(self.error("expected a string"));
+        }
+        self.pos += 1;
+        let mut bytes = Vec::new();
+        loop {
+            let Some(&byte) = self.bytes.get(self.pos) else {
+                return Err(self.error("unterminated string"));
+            };
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let Some(&escape) = self.bytes.get(self.pos) else {
+                        return Err(self.error("unterminated string"));
+                    };
+                    self.pos += 1;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            // Characters outside the BMP come as a surrogate pair
+                            if (0xD800..0xDC00).contains(&code)
+                                && self.bytes[self.pos..].starts_with(b"\\u")
+                            {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                code = 0x10000
+                                    + ((code - 0xD800) << 10)
+                                    + (low.wrapping_sub(0xDC00) & 0x3FF);
+                            }
+                            char::from_u32(code).ok_or_else(|| self.error("invalid \\u escape"))?
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                byte => bytes.push(byte),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| self.error("invalid UTF-8"))
+    }
+}
+
+// A render to reproduce: the arguments it was made with and the render hash
+// they must arrive at again
+struct Replay {
+    manifest: String,
+    version: String,
+    render_hash: u64,
+    // The image the recorded render wrote
+    output: String,
+}
+
+fn read_manifest(path: &str) -> io::Result<(Vec<String>, Replay)> {
+    let json = Json::parse(&std::fs::read_to_string(path)?)?;
+    let invalid = |field: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("manifest {} has no valid {}", path, field),
+        )
+    };
+    let args = match json.get("args") {
+        Some(Json::Array(args)) => args
+            .iter()
+            .map(|arg| arg.as_str().map(str::to_string))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| invalid("args"))?,
+        _ => return Err(invalid("args")),
+    };
+    let render_hash = json
+        .get("render_hash")
+        .and_then(Json::as_str)
+        .and_then(|hash| u64::from_str_radix(hash, 16).ok())
+        .ok_or_else(|| invalid("render_hash"))?;
+    let version = json
+        .get("version")
+        .and_then(Json::as_str)
+        .ok_or_else(|| invalid("version"))?;
+    let output = json
+        .get("output")
+        .and_then(Json::as_str)
+        .ok_or_else(|| invalid("output"))?;
+    Ok((
+        args,
+        Replay {
+            manifest: path.to_string(),
+            version: version.to_string(),
+            render_hash,
+            output: output.to_string(),
+        },
+    ))
+}
+
+// Scene files hold a whole Scene and the camera looking at it, everything
+// build_scene and the camera flags would otherwise set up. Enums are written
+// by their Debug names and textures by their index in Scene::textures
+impl Scene {
+    fn to_json_file(&self, filename: &str, config: &RenderConfig) -> io::Result<()> {
+        let mut meshes: Vec<Vec<&Triangle>> = vec![Vec::new(); self.mesh_count()];
+        for (triangle, &mesh) in self.triangles.iter().zip(&self.triangle_meshes) {
+            meshes[mesh].push(triangle);
+        }
+        let camera = json_object(&[
+            ("fov", json_f32(config.fov)),
+            ("roll", json_f32(config.roll)),
+            ("world_up", json_vec(config.world_up)),
+            ("aperture", json_f32(config.aperture)),
+            ("focus_distance", json_f32(config.focus_distance)),
+            ("camera_motion", json_vec(config.camera_motion)),
+        ]);
+        let lights = self.lights.iter().map(|light| {
+            json_object(&[
+                ("position", json_vec(light.position)),
+                ("intensity", json_f32(light.intensity)),
+                (
+                    "group",
+                    json_option(light.group.as_deref().map(json_string)),
+                ),
+            ])
+        });
+        let fields = [
+            ("camera", camera),
+            ("lights", json_array(lights)),
+            ("spheres", json_array(self.spheres.iter().map(sphere_json))),
+            (
+                "meshes",
+                json_array(
+                    meshes
+                        .iter()
+                        .map(|mesh| json_array(mesh.iter().map(|&t| triangle_json(t)))),
+                ),
+            ),
+            (
+                "differences",
+                json_array(self.differences.iter().map(difference_json)),
+            ),
+            (
+                "instances",
+                json_array(self.instances.iter().map(instance_json)),
+            ),
+            ("textures", json_array(self.textures.iter().map(image_json))),
+            ("background", background_json(&self.background)),
+            (
+                "reflection_background",
+                json_option(self.reflection_background.as_ref().map(background_json)),
+            ),
+            (
+                "environment",
+                json_option(
+                    self.environment
+                        .as_ref()
+                        .map(|environment| image_json(&environment.image)),
+                ),
+            ),
+            (
+                "backplate",
+                json_option(self.backplate.as_ref().map(|backplate| {
+                    json_object(&[
+                        ("image", image_json(&backplate.image)),
+                        ("fit", json_debug(&backplate.fit)),
+                    ])
+                })),
+            ),
+        ];
+        // One top-level field per line, so files diff sensibly
+        let fields: Vec<_> = fields
+            .iter()
+            .map(|(name, value)| format!("  {}: {}", json_string(name), value))
+            .collect();
+        std::fs::write(filename, format!("{{\n{}\n}}\n", fields.join(",\n")))
+    }
+
+    fn from_json_file(filename: &str, config: &mut RenderConfig) -> io::Result<Self> {
+        let json = Json::parse(&std::fs::read_to_string(filename)?)?;
+        let camera = field(&json, "camera")?;
+        config.fov = f32_from_json(field(camera, "fov")?)?;
+        config.roll = f32_from_json(field(camera, "roll")?)?;
+        config.world_up = vec_from_json(field(camera, "world_up")?)?;
+        config.aperture = f32_from_json(field(camera, "aperture")?)?;
+        config.focus_distance = f32_from_json(field(camera, "focus_distance")?)?;
+        config.camera_motion = vec_from_json(field(camera, "camera_motion")?)?;
+        config.validate()?;
+
+        let textures = array_from_json(field(&json, "textures")?)?;
+        let textures = textures.len();
+        let lights = array_from_json(field(&json, "lights")?)?
+            .iter()
+            .map(|light| {
+                Ok(Light {
+                    position: vec_from_json(field(light, "position")?)?,
+                    intensity: f32_from_json(field(light, "intensity")?)?,
+                    group: match field(light, "group")? {
+                        Json::Null => None,
+                        group => Some(str_from_json(group)?.to_string()),
+                    },
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        let spheres = array_from_json(field(&json, "spheres")?)?
+            .iter()
+            .map(|sphere| sphere_from_json(sphere, textures))
+            .collect::<io::Result<_>>()?;
+        let mut scene = Scene::new(spheres, lights);
+        for mesh in array_from_json(field(&json, "meshes")?)? {
+            scene.add_mesh(
+                array_from_json(mesh)?
+                    .iter()
+                    .map(|triangle| triangle_from_json(triangle, textures))
+                    .collect::<io::Result<_>>()?,
+            );
+        }
+        scene.differences = array_from_json(field(&json, "differences")?)?
+            .iter()
+            .map(|difference| difference_from_json(difference, textures))
+            .collect::<io::Result<_>>()?;
+        scene.instances = array_from_json(field(&json, "instances")?)?
+            .iter()
+            .map(|instance| instance_from_json(instance, textures))
+            .collect::<io::Result<_>>()?;
+        scene.textures = array_from_json(field(&json, "textures")?)?
+            .iter()
+            .map(image_from_json)
+            .collect::<io::Result<_>>()?;
+        scene.background = background_from_json(field(&json, "background")?)?;
+        scene.reflection_background = match field(&json, "reflection_background")? {
+            Json::Null => None,
+            background => Some(background_from_json(background)?),
+        };
+        scene.environment = match field(&json, "environment")? {
+            Json::Null => None,
+            image => Some(Environment::new(image_from_json(image)?)),
+        };
+        scene.backplate = match field(&json, "backplate")? {
+            Json::Null => None,
+            backplate => Some(Backplate::new(
+                image_from_json(field(backplate, "image")?)?,
+                match str_from_json(field(backplate, "fit")?)? {
+                    "Stretch" => BackplateFit::Stretch,
+                    "Fit" => BackplateFit::Fit,
+                    "Fill" => BackplateFit::Fill,
+                    other => return Err(scene_error(&format!("unknown fit {}", other))),
+                },
+            )),
+        };
+        let uses_environment = scene.background == Background::Environment
+            || scene.reflection_background == Some(Background::Environment);
+        if uses_environment && scene.environment.is_none() {
+            return Err(scene_error(
+                "an environment background needs an environment",
+            ));
+        }
+        Ok(scene)
+    }
+}
+
+fn sphere_json(sphere: &Sphere) -> String {
+    json_object(&[
+        ("center", json_vec(sphere.center)),
+        ("radius", json_f32(sphere.radius)),
+        ("material", material_json(&sphere.material)),
+    ])
+}
+
+fn difference_json(difference: &SphereDifference) -> String {
+    json_object(&[
+        ("base", sphere_json(&difference.base)),
+        ("bite", sphere_json(&difference.bite)),
+    ])
+}
+
+fn triangle_json(triangle: &Triangle) -> String {
+    // The face normal and tangent follow from the rest
+    json_object(&[
+        (
+            "vertices",
+            json_array(triangle.vertices.iter().map(|&v| json_vec(v))),
+        ),
+        (
+            "vertex_normals",
+            json_option(
+                triangle
+                    .vertex_normals
+                    .map(|normals| json_array(normals.iter().map(|&n| json_vec(n)))),
+            ),
+        ),
+        (
+            "uvs",
+            json_array(triangle.uvs.iter().map(|&uv| json_vec2(uv))),
+        ),
+        ("material", material_json(&triangle.material)),
+    ])
+}
+
+fn instance_json(instance: &Transformed) -> String {
+    // The matrix column by column, as nalgebra stores it
+    json_object(&[
+        (
+            "to_world",
+            json_array(instance.to_world.matrix().iter().map(|&v| json_f32(v))),
+        ),
+        ("object", instance.object.json()),
+    ])
+}
+
+fn surface_json(surface: &Surface) -> String {
+    let texture = |texture: Option<TextureId>| json_option(texture.map(|t| t.0.to_string()));
+    json_object(&[
+        ("diffuse_color", json_vec(surface.diffuse_color)),
+        ("shading", json_debug(&surface.shading)),
+        ("transmission", json_vec(surface.transmission)),
+        ("two_sided", surface.two_sided.to_string()),
+        ("texture", texture(surface.texture)),
+        ("normal_map", texture(surface.normal_map)),
+        ("texture_filter", json_debug(&surface.texture_filter)),
+        ("ior", json_vec(surface.ior)),
+        ("roughness", json_f32(surface.roughness)),
+        ("specular", json_f32(surface.specular)),
+        ("shininess", json_f32(surface.shininess)),
+        ("anisotropy", json_option(surface.anisotropy.map(json_vec2))),
+    ])
+}
+
+fn material_json(material: &Material) -> String {
+    match material {
+        Material::Surface(surface) => json_object(&[("surface", surface_json(surface))]),
+        Material::Blend { a, b, factor } => json_object(&[(
+            "blend",
+            json_object(&[
+                ("a", surface_json(a)),
+                ("b", surface_json(b)),
+                ("factor", json_f32(*factor)),
+            ]),
+        )]),
+    }
+}
+
+fn image_json(image: &Image) -> String {
+    json_object(&[
+        ("width", image.width.to_string()),
+        ("height", image.height.to_string()),
+        (
+            "pixels",
+            json_array(image.pixels.iter().map(|&p| json_vec(p))),
+        ),
+    ])
+}
+
+fn background_json(background: &Background) -> String {
+    match background {
+        Background::Sky => json_string("sky"),
+        Background::Environment => json_string("environment"),
+        Background::Color(color) => json_vec(*color),
+    }
+}
+
+fn scene_error(message: &str) -> io::Error {
+    invalid_data(&format!("invalid scene file: {}", message))
+}
+
+fn field<'a>(json: &'a Json, name: &str) -> io::Result<&'a Json> {
+    json.get(name)
+        .ok_or_else(|| scene_error(&format!("missing {}", name)))
+}
+
+fn f32_from_json(json: &Json) -> io::Result<f32> {
+    json.as_f32()
+        .ok_or_else(|| scene_error("expected a number"))
+}
+
+fn str_from_json(json: &Json) -> io::Result<&str> {
+    json.as_str()
+        .ok_or_else(|| scene_error("expected a string"))
+}
+
+fn array_from_json(json: &Json) -> io::Result<&[Json]> {
+    json.as_array()
+        .ok_or_else(|| scene_error("expected an array"))
+}
+
+fn floats_from_json<const N: usize>(json: &Json) -> io::Result<[f32; N]> {
+    let values = array_from_json(json)?
+        .iter()
+        .map(f32_from_json)
+        .collect::<io::Result<Vec<_>>>()?;
+    values
+        .try_into()
+        .map_err(|_| scene_error(&format!("expected {} numbers", N)))
+}
+
+fn vec_from_json(json: &Json) -> io::Result<Vector3<f32>> {
+    Ok(Vector3::from(floats_from_json::<3>(json)?))
+}
+
+fn vec2_from_json(json: &Json) -> io::Result<Vector2<f32>> {
+    Ok(Vector2::from(floats_from_json::<2>(json)?))
+}
+
+fn vecs_from_json(json: &Json) -> io::Result<[Vector3<f32>; 3]> {
+    let vecs = array_from_json(json)?
+        .iter()
+        .map(vec_from_json)
+        .collect::<io::Result<Vec<_>>>()?;
+    vecs.try_into()
+        .map_err(|_| scene_error("expected 3 vectors"))
+}
+
+fn sphere_from_json(json: &Json, textures: usize) -> io::Result<Sphere> {
+    Ok(Sphere::new(
+        vec_from_json(field(json, "center")?)?,
+        f32_from_json(field(json, "radius")?)?,
+        material_from_json(field(json, "material")?, textures)?,
+    ))
+}
+
+fn difference_from_json(json: &Json, textures: usize) -> io::Result<SphereDifference> {
+    Ok(SphereDifference::new(
+        sphere_from_json(field(json, "base")?, textures)?,
+        sphere_from_json(field(json, "bite")?, textures)?,
+    ))
+}
+
+fn triangle_from_json(json: &Json, textures: usize) -> io::Result<Triangle> {
+    let uvs = array_from_json(field(json, "uvs")?)?
+        .iter()
+        .map(vec2_from_json)
+        .collect::<io::Result<Vec<_>>>()?;
+    let uvs: [Vector2<f32>; 3] = uvs.try_into().map_err(|_| scene_error("expected 3 uvs"))?;
+    let triangle = Triangle::new(
+        vecs_from_json(field(json, "vertices")?)?,
+        material_from_json(field(json, "material")?, textures)?,
+    )
+    .with_uvs(uvs);
+    Ok(match field(json, "vertex_normals")? {
+        Json::Null => triangle,
+        normals => triangle.with_vertex_normals(vecs_from_json(normals)?),
+    })
+}
+
+fn instance_from_json(json: &Json, textures: usize) -> io::Result<Transformed> {
+    let to_world = floats_from_json::<16>(field(json, "to_world")?)?;
+    Ok(Transformed::boxed(
+        object_from_json(field(json, "object")?, textures)?,
+        Affine3::from_matrix_unchecked(Matrix4::from_column_slice(&to_world)),
+    ))
+}
+
+fn object_from_json(json: &Json, textures: usize) -> io::Result<Box<dyn Hittable + Sync>> {
+    // Any object Hittable::json writes, by the kind it is tagged with
+    let Json::Object(fields) = json else {
+        return Err(scene_error("expected an object"));
+    };
+    let [(kind, object)] = &fields[..] else {
+        return Err(scene_error("expected one kind of object"));
+    };
+    Ok(match kind.as_str() {
+        "sphere" => Box::new(sphere_from_json(object, textures)?),
+        "difference" => Box::new(difference_from_json(object, textures)?),
+        "triangle" => Box::new(triangle_from_json(object, textures)?),
+        "instance" => Box::new(instance_from_json(object, textures)?),
+        "group" => Box::new(
+            array_from_json(object)?
+                .iter()
+                .map(|part| object_from_json(part, textures))
+                .collect::<io::Result<Vec<_>>>()?,
+        ),
+        other => return Err(scene_error(&format!("unknown object kind {}", other))),
+    })
+}
+
+fn surface_from_json(json: &Json, textures: usize) -> io::Result<Surface> {
+    let texture = |name: &str| match field(json, name)? {
+        Json::Null => Ok(None),
+        index => match index.as_f32() {
+            Some(index) if index >= 0.0 && (index as usize) < textures => {
+                Ok(Some(TextureId(index as usize)))
+            }
+            _ => Err(scene_error(&format!("no texture for {}", name))),
+        },
+    };
+    let number = |name: &str| f32_from_json(field(json, name)?);
+    Ok(Surface {
+        diffuse_color: vec_from_json(field(json, "diffuse_color")?)?,
+        shading: match str_from_json(field(json, "shading")?)? {
+            "Lambert" => ShadingModel::Lambert,
+            "Unlit" => ShadingModel::Unlit,
+            "Metal" => ShadingModel::Metal,
+            "Dielectric" => ShadingModel::Dielectric,
+            other => return Err(scene_error(&format!("unknown shading {}", other))),
+        },
+        transmission: vec_from_json(field(json, "transmission")?)?,
+        two_sided: field(json, "two_sided")?
+            .as_bool()
+            .ok_or_else(|| scene_error("expected a boolean"))?,
+        texture: texture("texture")?,
+        normal_map: texture("normal_map")?,
+        texture_filter: match str_from_json(field(json, "texture_filter")?)? {
+            "Nearest" => TextureFilter::Nearest,
+            "Bilinear" => TextureFilter::Bilinear,
+            other => return Err(scene_error(&format!("unknown filter {}", other))),
+        },
+        ior: vec_from_json(field(json, "ior")?)?,
+        roughness: number("roughness")?,
+        specular: number("specular")?,
+        shininess: number("shininess")?,
+        anisotropy: match field(json, "anisotropy")? {
+            Json::Null => None,
+            roughness => Some(vec2_from_json(roughness)?),
+        },
+    })
+}
+
+fn material_from_json(json: &Json, textures: usize) -> io::Result<Material> {
+    if let Some(surface) = json.get("surface") {
+        return Ok(Material::Surface(surface_from_json(surface, textures)?));
+    }
+    let blend = field(json, "blend")?;
+    Ok(Material::Blend {
+        a: surface_from_json(field(blend, "a")?, textures)?,
+        b: surface_from_json(field(blend, "b")?, textures)?,
+        factor: f32_from_json(field(blend, "factor")?)?,
+    })
+}
+
+fn image_from_json(json: &Json) -> io::Result<Image> {
+    let size = |name: &str| match field(json, name)?.as_f32() {
+        Some(size) if size >= 1.0 && size.fract() == 0.0 => Ok(size as u32),
+        _ => Err(scene_error(&format!("invalid image {}", name))),
+    };
+    let (width, height) = (size("width")?, size("height")?);
+    let pixels = array_from_json(field(json, "pixels")?)?
+        .iter()
+        .map(vec_from_json)
+        .collect::<io::Result<Vec<_>>>()?;
+    if pixels.len() != (width * height) as usize {
+        return Err(scene_error("image pixels don't match its size"));
+    }
+    Ok(Image {
+        width,
+        height,
+        pixels,
+    })
+}
+
+fn background_from_json(json: &Json) -> io::Result<Background> {
+    match json {
+        Json::String(name) if name == "sky" => Ok(Background::Sky),
+        Json::String(name) if name == "environment" => Ok(Background::Environment),
+        color => Ok(Background::Color(vec_from_json(color)?)),
+    }
+}
+
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    // The value of the last occurrence of a flag, which is the one that counts
+    let index = args.iter().rposition(|arg| arg == flag)?;
+    args.get(index + 1).map(String::as_str)
+}
+
+fn expand_manifest(args: Vec<String>) -> io::Result<(Vec<String>, Option<Replay>)> {
+    // --from-manifest stands in for the arguments the manifest recorded, so
+    // flags after it can still change what doesn't affect the pixels, like
+    // --threads. The recorded --output and --manifest are left out, so a
+    // replay can't overwrite the render it is checked against: it names an
+    // output of its own, and a manifest of its own if it wants one
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let Some(index) = args.iter().position(|arg| arg == "--from-manifest") else {
+        return Ok((args, None));
+    };
+    let path = args
+        .get(index + 1)
+        .ok_or_else(|| invalid("missing value for --from-manifest".to_string()))?;
+    let (recorded, replay) = read_manifest(path)?;
+    let mut recorded = recorded.into_iter();
+    let mut expanded = args[..index].to_vec();
+    while let Some(arg) = recorded.next() {
+        if arg == "--output" || arg == "--manifest" {
+            recorded.next();
+        } else {
+            expanded.push(arg);
+        }
+    }
+    expanded.extend_from_slice(&args[index + 2..]);
+    if expanded.iter().any(|arg| arg == "--from-manifest") {
+        return Err(invalid(
+            "only one --from-manifest can be replayed".to_string(),
+        ));
+    }
+    match flag_value(&expanded, "--output") {
+        None => {
+            return Err(invalid(format!(
+                "replaying {} needs an --output of its own",
+                path
+            )))
+        }
+        Some(output) if output == replay.output || output == path.as_str() => {
+            return Err(invalid(format!(
+                "replaying {} would overwrite {}",
+                path, output
+            )))
+        }
+        Some(_) => {}
+    }
+    if let Some(manifest) = flag_value(&expanded, "--manifest") {
+        if manifest == path.as_str() || manifest == replay.output {
+            return Err(invalid(format!(
+                "replaying {} would overwrite {}",
+                path, manifest
+            )));
+        }
+    }
+    Ok((expanded, Some(replay)))
+}
+
+fn build_scene(config: &RenderConfig, meshes: &[String]) -> io::Result<Scene> {
+    let mut scene = match config.demo {
+        DemoScene::Spheres => Scene::spheres_demo(),
+        DemoScene::CornellBox => Scene::cornell_box(),
+        DemoScene::Carved => Scene::carved_demo(),
+        DemoScene::Instances => Scene::instances_demo(),
+        DemoScene::Shadows => Scene::shadows_demo(),
+        DemoScene::Materials => Scene::materials_demo(),
+        DemoScene::SphereGrid(n) => Scene::sphere_grid(n, 2.0),
+    };
+    let mut gray = Material::new(Vector3::new(0.6, 0.6, 0.6)).filtered(config.texture_filter);
+    if config.single_sided {
+        gray = gray.single_sided();
+    }
+    if let Some(texture) = &config.texture {
+        gray = gray.with_texture(scene.add_texture(load_ppm(texture)?));
+    }
+    if let Some(normal_map) = &config.normal_map {
+        gray = gray.with_normal_map(scene.add_texture(load_ppm(normal_map)?));
+    }
+    for mesh in meshes {
+        scene.add_mesh(load_obj(mesh, gray)?);
+    }
+    if let Some(backplate) = &config.backplate {
+        scene.backplate = Some(Backplate::new(load_ppm(backplate)?, config.backplate_fit));
+    }
+    if let Some(environment) = &config.environment {
+        scene.environment = Some(Environment::new(load_pfm(environment)?));
+    }
+    scene.background = config.background;
+    scene.reflection_background = config.reflection_background;
+    Ok(scene)
+}
+
+fn main() -> io::Result<()> {
+    let (args, replay) = expand_manifest(std::env::args().skip(1).collect())?;
+    let mut config = parse_args(args.iter().cloned())?;
+    if let Some(sample_map) = &config.sample_map_file {
+        let map = load_ppm(sample_map)?;
+        config.sample_map = Some(sample_counts(&map, &config)?);
+    }
+    let scene = match config.scene_file.clone() {
+        Some(scene_file) => Scene::from_json_file(&scene_file, &mut config)?,
+        None => build_scene(&config, &config.meshes)?,
+    };
+    if let Some(save_scene) = &config.save_scene {
+        scene.to_json_file(save_scene, &config)?;
+    }
+    if let Some(replay) = &replay {
+        let hash = render_hash(&scene, &config);
+        if hash != replay.render_hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "render hash {:016x} does not match {:016x} from {} (written by version {})",
+                    hash, replay.render_hash, replay.manifest, replay.version
+                ),
+            ));
+        }
+    }
+    if config.print_hash {
+        println!("{:016x}", render_hash(&scene, &config));
+        return Ok(());
+    }
+    for &(i, j) in &config.debug_pixels {
+        eprintln!("{}", debug_pixel(&scene, &config, i, j));
+    }
+    let cancel = Arc::new(AtomicBool::new(false));
+    // The watchdog needs a thread of its own, which wasm32 can't spawn
+    #[cfg(not(feature = "wasm"))]
+    if let Some(timeout) = config.timeout {
+        let cancel = Arc::clone(&cancel);
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            cancel.store(true, Ordering::Relaxed);
+        });
+    }
+    #[cfg(feature = "viewer")]
+    if config.viewer {
+        let mut window = PpmStreamWindow {
+            out: io::stdout().lock(),
+            open: true,
+        };
+        let stats = run_viewer(&scene, &config, &cancel, &mut window)?;
+        if config.stats {
+            eprintln!("{}", stats);
+        }
+        return Ok(());
+    }
+    // An output of - streams the PPM to stdout instead of writing a file,
+    // one after every pass when progressive
+    let stats = if config.filename == "-" && config.progressive {
+        render_frames(&scene, &config, &cancel, io::stdout().lock())?
+    } else if config.filename == "-" {
+        let (pixels, stats) = render_to_buffer(&scene, &config, &cancel)?;
+        write_ppm(io::stdout().lock(), config.width, config.height, &pixels)?;
+        stats
+    } else {
+        let old = match &config.previous_image {
+            Some(_) => Some(build_scene(&config, &config.previous_meshes)?),
+            None => None,
+        };
+        let previous = config.previous_image.as_deref().map(load_pfm).transpose()?;
+        render(&scene, &config, &cancel, old.as_ref().zip(previous))?
+    };
+    if config.stats {
+        eprintln!("{}", stats);
+    }
+    if let Some(manifest) = &config.manifest {
+        write_manifest(manifest, &args, &scene, &config, &stats)?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stretched_sphere_is_an_ellipse_with_ellipsoid_normals() {
+        // A unit sphere stretched 2x along X, straight ahead of the camera
+        let center = Vector3::new(0.0, 0.0, -10.0);
+        let scale = Vector3::new(2.0, 1.0, 1.0);
+        let mut scene = Scene::new(Vec::new(), Vec::new());
+        scene.instances.push(Transformed::new(
+            Sphere::new(Vector3::zeros(), 1.0, Material::new(Vector3::repeat(1.0))),
+            placement(center, Vector3::zeros(), scale),
+        ));
+        let config = RenderConfig {
+            width: 64,
+            height: 64,
+            ..RenderConfig::default()
+        };
+        let (mut columns, mut rows) = (0, 0);
+        for (i, j) in (0..64).flat_map(|j| (0..64).map(move |i| (i, j))) {
+            let (origin, dir) = camera_ray(i as f32 + 0.5, j as f32 + 0.5, &config);
+            let Some(hit) = scene_intersect(origin, dir, &scene, config.primary_range()) else {
+                continue;
+            };
+            columns += (j == 32) as u32;
+            rows += (i == 32) as u32;
+            // Hits lie on the ellipsoid, and its gradient (x/a^2, y/b^2, z/c^2)
+            // is the outward normal there
+            let local = (hit.point - center).component_div(&scale);
+            assert!((local.norm() - 1.0).abs() < 1e-3);
+            let expected = local.component_div(&scale).normalize();
+            assert!(hit.normal.dot(&expected) > 0.9999, "pixel ({}, {})", i, j);
+        }
+        // Twice as wide as it is tall on screen, within a pixel either way
+        assert!(rows > 0);
+        assert!(
+            (columns as i32 - 2 * rows as i32).abs() <= 2,
+            "{}x{}",
+            columns,
+            rows
+        );
+    }
+
+    #[test]
+    fn any_hit_agrees_with_scene_intersect() {
+        let scenes = [
+            Scene::spheres_demo(),
+            Scene::cornell_box(),
+            Scene::carved_demo(),
+            Scene::instances_demo(),
+        ];
+        for scene in &scenes {
+            let (mut blocked, mut clear) = (0, 0);
+            for k in 0..2000 {
+                let random = |dimension| sample_random(7, k, 0, 0, dimension);
+                let origin = Vector3::new(
+                    random(0) * 12.0 - 6.0,
+                    random(1) * 12.0 - 6.0,
+                    random(2) * -16.0,
+                );
+                let direction = Vector3::new(random(3), random(4), random(5))
+                    .add_scalar(-0.5)
+                    .normalize();
+                let range = Interval::new(SHADOW_BIAS, random(6) * 20.0);
+                let expected = scene_intersect(origin, direction, scene, range).is_some();
+                assert_eq!(
+                    any_hit(origin, direction, scene, range, |_| true),
+                    expected,
+                    "ray {}",
+                    k
+                );
+                if expected {
+                    blocked += 1;
+                } else {
+                    clear += 1;
+                }
+            }
+            assert!(
+                blocked > 100 && clear > 100,
+                "{} blocked, {} clear",
+                blocked,
+                clear
+            );
+        }
+    }
+
+    #[test]
+    fn shadow_edges_soften_with_occluder_distance() {
+        let config = RenderConfig {
+            shadows: true,
+            shadow_softness: 0.1,
+            ..RenderConfig::default()
+        };
+        // Width of the partly lit band on the ground under a unit sphere at a height
+        let penumbra = |height: f32| {
+            let sphere = Sphere::new(
+                Vector3::new(0.0, height, 0.0),
+                1.0,
+                Material::new(Vector3::zeros()),
+            );
+            let light = Light::new(Vector3::new(0.0, 50.0, 0.0), 1.0);
+            let scene = Scene::new(vec![sphere], vec![light]);
+            (0..1000)
+                .map(|k| Vector3::new(k as f32 * 0.005, 0.0, 0.0))
+                .map(|point| light_visibility(point, &scene.lights[0], &scene, &config))
+                .filter(|visibility| 0.02 < visibility.x && visibility.x < 0.98)
+                .count()
+        };
+        let (near, far) = (penumbra(1.5), penumbra(6.0));
+        assert!(near > 0 && far > 2 * near, "near {}, far {}", near, far);
+    }
+
+    #[test]
+    fn tinted_objects_cast_colored_shadows() {
+        let config = RenderConfig {
+            shadows: true,
+            ..RenderConfig::default()
+        };
+        let glass = Surface::new(Vector3::repeat(1.0)).tinted(Vector3::new(0.9, 0.15, 0.1));
+        let sphere = Sphere::new(Vector3::new(0.0, 2.0, 0.0), 1.0, Material::Surface(glass));
+        let scene = Scene::new(
+            vec![sphere],
+            vec![Light::new(Vector3::new(0.0, 50.0, 0.0), 1.0)],
+        );
+        // The shadow ray crosses the sphere once, as it only counts the nearest hit
+        let visibility = light_visibility(Vector3::zeros(), &scene.lights[0], &scene, &config);
+        assert_eq!(visibility, Vector3::new(0.9, 0.15, 0.1));
+        let opaque = Scene::new(
+            vec![Sphere::new(
+                Vector3::new(0.0, 2.0, 0.0),
+                1.0,
+                Material::new(Vector3::repeat(1.0)),
+            )],
+            vec![Light::new(Vector3::new(0.0, 50.0, 0.0), 1.0)],
+        );
+        let visibility = light_visibility(Vector3::zeros(), &opaque.lights[0], &opaque, &config);
+        assert_eq!(visibility, Vector3::zeros());
+    }
+
+    #[test]
+    fn shadow_samples_smooth_the_penumbra_but_not_the_silhouettes() {
+        let scene = Scene::shadows_demo();
+        let config = |shadow_samples| RenderConfig {
+            width: 64,
+            height: 64,
+            shadows: true,
+            light_radius: 3.0,
+            shadow_samples,
+            ..RenderConfig::default()
+        };
+        // Visibility along a line on the floor through the red sphere's shadow
+        let visibility = |config: &RenderConfig| {
+            (0..2000)
+                .map(|k| Vector3::new(-6.0 + k as f32 * 0.004, -2.0, -10.0))
+                .map(|point| light_visibility(point, &scene.lights[0], &scene, config).x)
+                .collect::<Vec<_>>()
+        };
+        let largest_step = |visibility: &[f32]| {
+            visibility
+                .windows(2)
+                .map(|pair| (pair[1] - pair[0]).abs())
+                .fold(0.0, f32::max)
+        };
+        let (hard, soft) = (visibility(&config(1)), visibility(&config(64)));
+        assert_eq!(largest_step(&hard), 1.0);
+        assert!(largest_step(&soft) < 0.1, "step {}", largest_step(&soft));
+
+        let cancel = AtomicBool::new(false);
+        let mut stats = RenderStats::default();
+        let coverage = |config: &RenderConfig, stats: &mut RenderStats| {
+            render_radiance(&scene, config, Region::full(config), &cancel, stats)
+                .unwrap()
+                .coverage
+        };
+        assert_eq!(
+            coverage(&config(1), &mut stats),
+            coverage(&config(64), &mut stats)
+        );
+    }
+
+    #[test]
+    fn moving_a_sphere_rerenders_only_around_it() {
+        let config = RenderConfig {
+            width: 96,
+            height: 64,
+            edge_aa: 3,
+            ..RenderConfig::default()
+        };
+        let cancel = AtomicBool::new(false);
+        let mut stats = RenderStats::default();
+        let full = Region::full(&config);
+        let old = Scene::spheres_demo();
+        let mut new = Scene::spheres_demo();
+        new.spheres[2].center.x -= 0.5;
+        let previous = render_radiance(&old, &config, full, &cancel, &mut stats).unwrap();
+        let expected = render_radiance(&new, &config, full, &cancel, &mut stats).unwrap();
+
+        let (updated, region) =
+            rerender_changed(&old, &new, &config, &previous, &cancel, &mut stats).unwrap();
+        let region = region.unwrap();
+        assert!(region.width() * region.height() < config.width * config.height / 4);
+        // The moved sphere's center is inside the region
+        let center = project_bounds(Bounds::around(new.spheres[2].center, 0.0), &config).unwrap();
+        assert!(region.x0 < center.x0 && center.x1 < region.x1);
+        assert!(region.y0 < center.y0 && center.y1 < region.y1);
+        // Pixels outside it are the previous ones, and the update is the full render
+        for j in 0..config.height {
+            for i in 0..config.width {
+                let index = (j * config.width + i) as usize;
+                let inside =
+                    (region.x0..region.x1).contains(&i) && (region.y0..region.y1).contains(&j);
+                if !inside {
+                    assert_eq!(updated.radiance[index], previous.radiance[index]);
+                }
+                assert_eq!(
+                    updated.radiance[index], expected.radiance[index],
+                    "pixel {} {}",
+                    i, j
+                );
+            }
+        }
+        assert!(updated.radiance != previous.radiance);
+
+        let unchanged = rerender_changed(&new, &new, &config, &expected, &cancel, &mut stats);
+        assert_eq!(unchanged.unwrap().1, None);
+    }
+
+    fn temp_path(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("tinyraytracer-{}-{}", std::process::id(), name));
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn pfm_keeps_values_above_one_exactly() {
+        let path = temp_path("hdr.pfm");
+        let radiance = [
+            Vector3::new(3.75, 0.5, 1.0e-3),
+            Vector3::new(0.0, 1.0, 17.125),
+        ];
+        write_pfm_image(&path, 1, 2, &radiance).unwrap();
+        let image = load_pfm(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((image.width, image.height), (1, 2));
+        assert_eq!(image.pixels, radiance);
+    }
+
+    #[test]
+    fn accumulating_in_batches_matches_one_batch() {
+        let scene = Scene::spheres_demo();
+        let config = RenderConfig {
+            width: 32,
+            height: 24,
+            samples: 4,
+            seed: 7,
+            ..RenderConfig::default()
+        };
+        let cancel = AtomicBool::new(false);
+        let mut stats = RenderStats::default();
+        let region = Region::full(&config);
+        let mut batched = Accumulator::new(region);
+        accumulate_samples(
+            &mut batched,
+            &scene,
+            &config,
+            region,
+            2,
+            &cancel,
+            &mut stats,
+        )
+        .unwrap();
+        accumulate_samples(
+            &mut batched,
+            &scene,
+            &config,
+            region,
+            2,
+            &cancel,
+            &mut stats,
+        )
+        .unwrap();
+        let mut single = Accumulator::new(region);
+        accumulate_samples(&mut single, &scene, &config, region, 4, &cancel, &mut stats).unwrap();
+        assert_eq!(batched.samples, 4);
+        assert_eq!(
+            batched.framebuffer(region).radiance,
+            single.framebuffer(region).radiance
+        );
+        assert_eq!(resolve(&batched, &config), resolve(&single, &config));
+        assert_eq!(resolve(&single, &config).len(), 32 * 24 * 3);
+    }
+
+    #[test]
+    fn misses_are_transparent_and_hits_opaque() {
+        let scene = Scene::spheres_demo();
+        let config = RenderConfig {
+            width: 32,
+            height: 32,
+            edge_aa: 4,
+            ..RenderConfig::default()
+        };
+        let cancel = AtomicBool::new(false);
+        let framebuffer = render_radiance(
+            &scene,
+            &config,
+            Region::full(&config),
+            &cancel,
+            &mut RenderStats::default(),
+        )
+        .unwrap();
+        let rgba = quantize_rgba(&framebuffer, &config);
+        let alpha = |i: u32, j: u32| rgba[(j * config.width + i) as usize * 4 + 3];
+        // The top left corner sees only sky, the center the big red sphere
+        assert_eq!(alpha(0, 0), 0);
+        assert_eq!(alpha(16, 16), 255);
+        // Silhouette pixels are partly covered
+        assert!(rgba.chunks(4).any(|pixel| 0 < pixel[3] && pixel[3] < 255));
+    }
+
+    fn args(args: &str) -> io::Result<RenderConfig> {
+        parse_args(args.split_whitespace().map(str::to_string))
+    }
+
+    #[test]
+    fn out_of_range_fovs_are_rejected() {
+        for fov in ["0", "-30", "180", "270", "NaN", "inf"] {
+            let error = args(&format!("--fov {}", fov)).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "fov {}", fov);
+        }
+        // Just inside the bounds every ray direction is still finite
+        for fov in ["0.01", "179.9"] {
+            let config = args(&format!("--fov {} --width 8 --height 8", fov)).unwrap();
+            for (px, py) in [(0.0, 0.0), (4.0, 4.0), (8.0, 8.0)] {
+                let (_, direction) = camera_ray(px, py, &config);
+                assert!(direction.iter().all(|v| v.is_finite()), "fov {}", fov);
+            }
+        }
+    }
+
+    #[test]
+    fn thread_count_does_not_change_the_image() {
+        // Jittered samples and soft shadows, over more rows than one tile so
+        // the threads share the work
+        let scene = Scene::shadows_demo();
+        let render = |threads| {
+            let config = RenderConfig {
+                width: 40,
+                height: 30,
+                samples: 3,
+                seed: 11,
+                shadows: true,
+                light_radius: 2.0,
+                shadow_samples: 4,
+                threads,
+                ..RenderConfig::default()
+            };
+            render_to_buffer(&scene, &config, &AtomicBool::new(false))
+                .unwrap()
+                .0
+        };
+        assert_eq!(render(1), render(4));
+    }
+
+    #[test]
+    fn non_finite_channels_are_sanitized() {
+        let config = RenderConfig::default();
+        let bad = Vector3::new(f32::NAN, 0.5, f32::INFINITY);
+        assert_eq!(display_transform(bad, (0, 0), &config), [0, 127, 0]);
+        let bad = Vector3::new(f32::NEG_INFINITY, f32::NAN, 1.0);
+        assert_eq!(display_transform(bad, (0, 0), &config), [0, 0, 255]);
+        // Flagged, the whole pixel turns magenta
+        let flagged = RenderConfig {
+            flag_nonfinite: true,
+            ..RenderConfig::default()
+        };
+        assert_eq!(display_transform(bad, (0, 0), &flagged), [255, 0, 255]);
+        let good = Vector3::new(1.0, 0.5, 0.0);
+        assert_eq!(display_transform(good, (0, 0), &flagged), [255, 127, 0]);
+    }
+
+    #[test]
+    fn coincident_surfaces_resolve_to_the_first_object() {
+        let red = Material::new(Vector3::new(1.0, 0.0, 0.0));
+        let blue = Material::new(Vector3::new(0.0, 0.0, 1.0));
+        let center = Vector3::new(0.0, 0.0, -5.0);
+        let scene = |first, second| {
+            Scene::new(
+                vec![
+                    Sphere::new(center, 1.0, first),
+                    Sphere::new(center, 1.0, second),
+                ],
+                Vec::new(),
+            )
+        };
+        let range = Interval::new(0.0, f32::INFINITY);
+        for (scene, color) in [
+            (scene(red, blue), red.diffuse()),
+            (scene(blue, red), blue.diffuse()),
+        ] {
+            for k in 0..50 {
+                let direction = Vector3::new(k as f32 * 0.003 - 0.075, 0.01, -1.0);
+                let hit = scene_intersect(Vector3::zeros(), direction, &scene, range).unwrap();
+                assert_eq!(hit.object, 0);
+                assert_eq!(hit.material.diffuse(), color);
+            }
+        }
+    }
+
+    #[test]
+    fn buffer_render_matches_the_written_file() {
+        let scene = Scene::spheres_demo();
+        let config = RenderConfig {
+            width: 24,
+            height: 16,
+            samples: 2,
+            filename: temp_path("buffer.ppm"),
+            ..RenderConfig::default()
+        };
+        let cancel = AtomicBool::new(false);
+        render(&scene, &config, &cancel, None).unwrap();
+        let file = std::fs::read(&config.filename).unwrap();
+        std::fs::remove_file(&config.filename).unwrap();
+        let (pixels, _) = render_to_buffer(&scene, &config, &cancel).unwrap();
+        let mut expected = Vec::new();
+        write_ppm(&mut expected, config.width, config.height, &pixels).unwrap();
+        assert_eq!(file, expected);
+    }
+
+    #[test]
+    fn half_red_half_blue_blend_shades_purple() {
+        let red = Surface::new(Vector3::new(1.0, 0.0, 0.0));
+        let blue = Surface::new(Vector3::new(0.0, 0.0, 1.0));
+        let sphere = Sphere::new(
+            Vector3::new(0.0, 0.0, -5.0),
+            1.0,
+            Material::blend(red, blue, 0.5),
+        );
+        let light = Light::new(Vector3::new(0.0, 0.0, 10.0), 1.0);
+        let scene = Scene::new(vec![sphere], vec![light]);
+        let config = RenderConfig::default();
+        let range = config.primary_range();
+        let color = cast_ray(Vector3::zeros(), -Vector3::z(), &scene, range, &config, 0.5).unwrap();
+        assert!(color.x > 0.2 && color.z > 0.2, "{:?}", color);
+        // Only the sky-tinted ambient tells the two channels apart
+        assert!((color.x / color.z - 1.0).abs() < 0.1, "{:?}", color);
+        assert!(color.y < 1e-6, "{:?}", color);
+        // Each side is shaded with its own model: half lit red, half unlit blue
+        let sphere = Sphere::new(
+            Vector3::new(0.0, 0.0, -5.0),
+            1.0,
+            Material::blend(red, blue.unlit(), 0.5),
+        );
+        let light = Light::new(Vector3::new(0.0, 0.0, 10.0), 1.0);
+        let scene = Scene::new(vec![sphere], vec![light]);
+        let mixed = cast_ray(Vector3::zeros(), -Vector3::z(), &scene, range, &config, 0.5).unwrap();
+        assert_eq!(mixed.z, 0.5);
+        assert_eq!(mixed.x, color.x);
+    }
+
+    #[test]
+    fn streamed_ppm_matches_the_buffered_one() {
+        let scene = Scene::spheres_demo();
+        let cancel = AtomicBool::new(false);
+        for flip_y in [false, true] {
+            let write = |stream, name: &str| {
+                let config = RenderConfig {
+                    width: 24,
+                    height: 20,
+                    edge_aa: 3,
+                    flip_y,
+                    stream,
+                    filename: temp_path(name),
+                    ..RenderConfig::default()
+                };
+                render(&scene, &config, &cancel, None).unwrap();
+                let bytes = std::fs::read(&config.filename).unwrap();
+                std::fs::remove_file(&config.filename).unwrap();
+                bytes
+            };
+            assert_eq!(write(true, "streamed.ppm"), write(false, "buffered.ppm"));
+        }
+    }
+
+    #[test]
+    fn default_display_transform_is_the_original_vec_to_rgb() {
+        // The conversion every pixel went through before the pipeline existed
+        fn vec_to_rgb(v: Vector3<f32>) -> [u8; 3] {
+            [
+                (255.0 * v.x.clamp(0.0, 1.0)) as u8,
+                (255.0 * v.y.clamp(0.0, 1.0)) as u8,
+                (255.0 * v.z.clamp(0.0, 1.0)) as u8,
+            ]
+        }
+        let config = RenderConfig::default();
+        for k in 0..4000u32 {
+            let channel = |d: u32| sample_random(1, k, 0, 0, d) * 2.0 - 0.5;
+            let v = Vector3::new(k as f32 / 2000.0 - 0.5, channel(0), channel(1));
+            assert_eq!(
+                display_transform(v, (k, k), &config),
+                vec_to_rgb(v),
+                "{:?}",
+                v
+            );
+        }
+    }
+
+    #[test]
+    fn every_primitive_renders_in_its_screen_region() {
+        // One of each kind of object in its own quadrant, numbered in the
+        // order scene_intersect tags them
+        let material = Material::new(Vector3::new(0.8, 0.6, 0.4));
+        let light = Light::new(Vector3::new(0.0, 10.0, 10.0), 1.0);
+        let mut scene = Scene::new(
+            vec![Sphere::new(Vector3::new(-3.0, 1.5, -10.0), 1.0, material)],
+            vec![light],
+        );
+        scene.add_mesh(vec![Triangle::new(
+            [
+                Vector3::new(2.0, 0.5, -10.0),
+                Vector3::new(4.0, 0.5, -10.0),
+                Vector3::new(3.0, 2.5, -10.0),
+            ],
+            material,
+        )]);
+        scene.differences.push(SphereDifference::new(
+            Sphere::new(Vector3::new(-3.0, -1.5, -10.0), 1.2, material),
+            Sphere::new(Vector3::new(-3.0, -1.5, -11.5), 0.8, material),
+        ));
+        scene.instances.push(Transformed::new(
+            Sphere::new(Vector3::zeros(), 1.0, material),
+            placement(
+                Vector3::new(3.0, -1.5, -10.0),
+                Vector3::zeros(),
+                Vector3::new(1.5, 0.8, 1.0),
+            ),
+        ));
+        let empty = Scene::new(Vec::new(), Vec::new());
+
+        let config = RenderConfig {
+            width: 48,
+            height: 32,
+            samples: 2,
+            seed: 5,
+            ..RenderConfig::default()
+        };
+        let ids = RenderConfig {
+            mode: RenderMode::ObjectId,
+            ..config.clone()
+        };
+        let cancel = AtomicBool::new(false);
+        let render = |scene: &Scene, config: &RenderConfig| {
+            let region = Region::full(config);
+            let mut state = Accumulator::new(region);
+            let mut stats = RenderStats::default();
+            accumulate_samples(
+                &mut state,
+                scene,
+                config,
+                region,
+                config.samples,
+                &cancel,
+                &mut stats,
+            )
+            .unwrap();
+            state.framebuffer(region)
+        };
+        let (shaded, background, objects) = (
+            render(&scene, &config),
+            render(&empty, &config),
+            render(&scene, &ids),
+        );
+
+        let summaries = object_summaries(&scene);
+        let bounds: Vec<_> = summaries
+            .iter()
+            .flatten()
+            .map(|&(_, bounds)| bounds)
+            .collect();
+        assert_eq!(bounds.len(), scene.object_count());
+        for (object, &bounds) in bounds.iter().enumerate() {
+            let region = project_bounds(bounds, &config).unwrap();
+            let (i, j) = ((region.x0 + region.x1) / 2, (region.y0 + region.y1) / 2);
+            let index = (j * config.width + i) as usize;
+            let color = shaded.radiance[index];
+            assert!(color.iter().all(|c| c.is_finite()), "object {}", object);
+            assert_eq!(shaded.coverage[index], 1.0, "object {}", object);
+            assert!(color != background.radiance[index], "object {}", object);
+            assert!(color.norm() > 0.1, "object {}", object);
+            assert_eq!(
+                objects.radiance[index],
+                object_id_color(object),
+                "object {}",
+                object
+            );
+        }
+    }
+
+    #[test]
+    fn ambient_comes_from_the_sky_above_and_the_ground_below() {
+        let config = args("--ambient-sky 0.1,0.3,0.9 --ambient-ground 0.4,0.2,0").unwrap();
+        let (sky, ground) = (Vector3::new(0.1, 0.3, 0.9), Vector3::new(0.4, 0.2, 0.0));
+        assert_eq!(hemisphere_ambient(Vector3::y(), &config), sky);
+        assert_eq!(hemisphere_ambient(-Vector3::y(), &config), ground);
+        assert_eq!(
+            hemisphere_ambient(Vector3::x(), &config),
+            (sky + ground) / 2.0
+        );
+        // Unlit, a sphere's top and bottom show just the two ambient colors
+        let white = Material::new(Vector3::repeat(1.0));
+        let scene = Scene::new(vec![Sphere::new(Vector3::zeros(), 1.0, white)], Vec::new());
+        let range = config.primary_range();
+        let top = cast_ray(
+            Vector3::new(0.0, 5.0, 0.0),
+            -Vector3::y(),
+            &scene,
+            range,
+            &config,
+            0.5,
+        );
+        let bottom = cast_ray(
+            Vector3::new(0.0, -5.0, 0.0),
+            Vector3::y(),
+            &scene,
+            range,
+            &config,
+            0.5,
+        );
+        assert_eq!((top, bottom), (Some(sky), Some(ground)));
+    }
+
+    #[test]
+    fn fog_tints_distant_objects_more() {
+        let config = args("--fog-density 0.05 --fog-color 1,0,1").unwrap();
+        let green = Material::new(Vector3::new(0.0, 1.0, 0.0));
+        let sphere_at = |z| {
+            let light = Light::new(Vector3::new(0.0, 0.0, 10.0), 1.0);
+            let scene = Scene::new(
+                vec![Sphere::new(Vector3::new(0.0, 0.0, z), 1.0, green)],
+                vec![light],
+            );
+            cast_ray(
+                Vector3::zeros(),
+                -Vector3::z(),
+                &scene,
+                config.primary_range(),
+                &config,
+                0.5,
+            )
+            .unwrap()
+        };
+        // Both spheres face the light head on and shade alike without fog,
+        // so only the fog tells them apart
+        let (near, far) = (sphere_at(-5.0), sphere_at(-40.0));
+        assert!(
+            far.x > near.x && far.z > near.z,
+            "near {:?}, far {:?}",
+            near,
+            far
+        );
+        assert!(far.y < near.y, "near {:?}, far {:?}", near, far);
+        assert!(args("--fog-density -1").is_err());
+    }
+
+    #[test]
+    fn the_sky_follows_world_up_not_the_camera() {
+        let scene = Scene::new(Vec::new(), Vec::new());
+        let sky = |roll: &str, i, j| {
+            let config = args(&format!("--width 33 --height 33 --roll {}", roll)).unwrap();
+            let (_, direction) = camera_ray(i as f32 + 0.5, j as f32 + 0.5, &config);
+            background(direction, i, j, &scene, &config)
+        };
+        let horizon = RenderConfig::default().sky_horizon;
+        // The view center looks level, whatever the roll
+        for roll in ["0", "30", "90", "-45"] {
+            assert!((sky(roll, 16, 16) - horizon).norm() < 1e-6, "roll {}", roll);
+        }
+        // Unrolled, the top of the frame looks up into the sky and the bottom
+        // down at the horizon color. Rolled a quarter turn the sky moves to a side
+        assert!(sky("0", 16, 0) != horizon);
+        assert_eq!(sky("0", 16, 32), horizon);
+        assert!((sky("90", 16, 0) - horizon).norm() < 1e-6);
+        assert!((sky("90", 32, 16) - sky("0", 16, 0)).norm() < 1e-6);
+        assert!((sky("90", 0, 16) - horizon).norm() < 1e-6);
+    }
+
+    #[test]
+    fn center_ray_picks_the_big_red_sphere() {
+        let scene = Scene::spheres_demo();
+        let config = RenderConfig::default();
+        let (origin, direction) = camera_ray(
+            config.width as f32 / 2.0,
+            config.height as f32 / 2.0,
+            &config,
+        );
+        let hit = scene_intersect(origin, direction, &scene, config.primary_range()).unwrap();
+        assert_eq!(hit.object, 0);
+        assert_eq!(hit.material.diffuse(), scene.spheres[0].material.diffuse());
+        // The red sphere's near side, 16 away along -Z with its center off axis by sqrt(5)
+        assert!(
+            (hit.distance - (16.0 - 20.0_f32.sqrt())).abs() < 1e-4,
+            "{}",
+            hit.distance
+        );
+    }
+
+    #[test]
+    fn id_render_gives_each_object_its_own_flat_color() {
+        let scene = Scene::spheres_demo();
+        let config = args("--mode id --width 64 --height 64 --edge-aa 4").unwrap();
+        let (pixels, _) = render_to_buffer(&scene, &config, &AtomicBool::new(false)).unwrap();
+        // Flat colors only, no blends along silhouettes: the background and one per sphere
+        let colors: std::collections::HashSet<&[u8]> = pixels.chunks(3).collect();
+        assert_eq!(colors.len(), scene.object_count() + 1);
+        let pixel = |i: u32, j: u32| &pixels[(j * config.width + i) as usize * 3..][..3];
+        assert_eq!(pixel(0, 0), [0, 0, 0]);
+        let color = |object| display_transform(object_id_color(object), (0, 0), &config);
+        assert_eq!(pixel(32, 32), color(0));
+        assert!((0..scene.object_count()).all(|object| colors.contains(&color(object)[..])));
+    }
+
+    #[test]
+    fn silhouette_pixels_blend_by_coverage() {
+        let red = Material::new(Vector3::new(1.0, 0.0, 0.0)).unlit();
+        let scene = Scene::new(
+            vec![Sphere::new(Vector3::new(0.0, 0.0, -10.0), 3.0, red)],
+            Vec::new(),
+        );
+        let config = args("--width 32 --height 32 --edge-aa 4").unwrap();
+        let cancel = AtomicBool::new(false);
+        let framebuffer = render_radiance(
+            &scene,
+            &config,
+            Region::full(&config),
+            &cancel,
+            &mut RenderStats::default(),
+        )
+        .unwrap();
+        // Walk out from the center along the middle row to the first partly covered pixel
+        let row = 16 * config.width as usize;
+        let edge = (16..32)
+            .find(|&i| framebuffer.coverage[row + i] < 1.0)
+            .unwrap();
+        let coverage = framebuffer.coverage[row + edge];
+        assert!(0.0 < coverage && coverage < 1.0, "coverage {}", coverage);
+        let (inside, on_edge, outside) = (
+            framebuffer.radiance[row + edge - 1],
+            framebuffer.radiance[row + edge],
+            framebuffer.radiance[row + edge + 1],
+        );
+        assert_eq!(inside, Vector3::new(1.0, 0.0, 0.0));
+        assert_eq!(framebuffer.coverage[row + edge + 1], 0.0);
+        for c in 0..3 {
+            let (low, high) = (inside[c].min(outside[c]), inside[c].max(outside[c]));
+            assert!(low < on_edge[c] && on_edge[c] < high, "{:?}", on_edge);
+        }
+    }
+
+    #[test]
+    fn group_multipliers_scale_every_light_in_the_group() {
+        let white = Material::new(Vector3::repeat(1.0));
+        let scene = Scene::new(
+            vec![Sphere::new(Vector3::new(0.0, 0.0, -5.0), 1.0, white)],
+            vec![
+                Light::new(Vector3::new(-5.0, 5.0, 5.0), 0.4).in_group("key"),
+                Light::new(Vector3::new(5.0, 5.0, 5.0), 0.3).in_group("key"),
+                Light::new(Vector3::new(0.0, -5.0, 5.0), 0.2),
+            ],
+        );
+        let shade = |flags: &str| {
+            let config = args(&format!(
+                "--ambient-sky 0,0,0 --ambient-ground 0,0,0 {}",
+                flags
+            ))
+            .unwrap();
+            let direction = Vector3::new(0.05, 0.02, -1.0);
+            cast_ray(
+                Vector3::zeros(),
+                direction,
+                &scene,
+                config.primary_range(),
+                &config,
+                0.5,
+            )
+            .unwrap()
+            .x
+        };
+        let (full, half, fill) = (
+            shade(""),
+            shade("--light-group key=0.5"),
+            shade("--light-group key=0"),
+        );
+        assert!(fill > 0.0 && full > fill);
+        assert!(
+            ((half - fill) - (full - fill) / 2.0).abs() < 1e-6,
+            "{} {} {}",
+            full,
+            half,
+            fill
+        );
+    }
+
+    #[test]
+    fn hits_past_the_range_end_are_ignored() {
+        // A shadow ray from the origin towards a light with a sphere 4 away in between
+        let black = Material::new(Vector3::zeros());
+        let scene = Scene::new(
+            vec![Sphere::new(Vector3::new(0.0, 5.0, 0.0), 1.0, black)],
+            Vec::new(),
+        );
+        let up = Vector3::y();
+        let blocked = |max| {
+            any_hit(
+                Vector3::zeros(),
+                up,
+                &scene,
+                Interval::new(SHADOW_BIAS, max),
+                |_| true,
+            )
+        };
+        assert!(!blocked(4.0 - 1e-3));
+        assert!(blocked(4.0 + 1e-3));
+        let nearest = |max| scene_intersect(Vector3::zeros(), up, &scene, Interval::new(0.0, max));
+        assert!(nearest(4.0 - 1e-3).is_none());
+        assert!((nearest(4.0 + 1e-3).unwrap().distance - 4.0).abs() < 1e-5);
+        // From inside the sphere the crossing behind the origin is out of range
+        let inside = scene_intersect(
+            Vector3::new(0.0, 4.5, 0.0),
+            up,
+            &scene,
+            Interval::new(0.0, 10.0),
+        );
+        assert!((inside.unwrap().distance - 1.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn obj_quad_is_hit_through_both_triangles() {
+        let source = "# a unit quad at z = -3\nv -1 -1 -3\nv 1 -1 -3\nv 1 1 -3\nv -1 1 -3\nf 1 2 3\nf 1 3 -1\n";
+        let triangles = parse_obj(source, Material::new(Vector3::repeat(1.0))).unwrap();
+        assert_eq!(triangles.len(), 2);
+        let mut scene = Scene::new(Vec::new(), Vec::new());
+        scene.add_mesh(triangles);
+        let range = Interval::new(0.0, f32::INFINITY);
+        let hit = |x, y| scene_intersect(Vector3::zeros(), Vector3::new(x, y, -3.0), &scene, range);
+        // One point in each half of the quad, then one beside it
+        for (x, y) in [(0.5, -0.5), (-0.5, 0.5)] {
+            let hit = hit(x, y).unwrap();
+            assert_eq!(hit.object, 0);
+            assert!((hit.point - Vector3::new(x, y, -3.0)).norm() < 1e-5);
+        }
+        assert!(hit(1.5, 0.0).is_none());
+        assert!(parse_obj("v 0 0 0\nf 1 2 3\n", Material::new(Vector3::zeros())).is_err());
+    }
+
+    #[test]
+    fn vertex_normals_interpolate_across_the_triangle() {
+        let vertices = [
+            Vector3::new(-1.0, -1.0, -3.0),
+            Vector3::new(1.0, -1.0, -3.0),
+            Vector3::new(0.0, 1.0, -3.0),
+        ];
+        let normals = [
+            Vector3::new(-1.0, 0.0, 1.0),
+            Vector3::new(1.0, 0.0, 1.0),
+            Vector3::new(0.0, 1.0, 1.0),
+        ];
+        let triangle = Triangle::new(vertices, Material::new(Vector3::repeat(1.0)))
+            .with_vertex_normals(normals);
+        let centroid = (vertices[0] + vertices[1] + vertices[2]) / 3.0;
+        let range = Interval::new(0.0, f32::INFINITY);
+        let hit = triangle
+            .ray_intersect(Vector3::zeros(), centroid, range)
+            .unwrap();
+        let expected =
+            (normals[0].normalize() + normals[1].normalize() + normals[2].normalize()).normalize();
+        assert!((hit.normal - expected).norm() < 1e-5, "{:?}", hit.normal);
+        for normal in normals {
+            assert!((hit.normal - normal.normalize()).norm() > 0.1);
+        }
+        // The geometric normal stays the flat face normal
+        assert_eq!(hit.geometric_normal, Vector3::z());
+    }
+
+    #[test]
+    fn cancelling_stops_the_render_early() {
+        // A shader that counts the pixels shaded and cancels after the first few rows
+        let cancel = Arc::new(AtomicBool::new(false));
+        let shaded = Arc::new(AtomicU64::new(0));
+        let shader = {
+            let (cancel, shaded) = (Arc::clone(&cancel), Arc::clone(&shaded));
+            move |_: Vector3<f32>, _: Vector3<f32>, _: Option<&Intersection>, _: &Scene| {
+                if shaded.fetch_add(1, Ordering::Relaxed) == 64 * 3 {
+                    cancel.store(true, Ordering::Relaxed);
+                }
+                Vector3::zeros()
+            }
+        };
+        let config = RenderConfig {
+            width: 64,
+            height: 64,
+            threads: 1,
+            shader: Some(Arc::new(shader)),
+            ..RenderConfig::default()
+        };
+        let scene = Scene::spheres_demo();
+        let error = render_to_buffer(&scene, &config, &cancel).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::Interrupted);
+        // The row being shaded is finished, then nothing more
+        assert_eq!(shaded.load(Ordering::Relaxed), 64 * 4);
+    }
+
+    #[test]
+    fn center_pixel_looks_down_minus_z() {
+        let config = RenderConfig::default();
+        let (width, height) = (config.width as f32, config.height as f32);
+        let (origin, direction) = camera_ray(width / 2.0, height / 2.0, &config);
+        assert_eq!(origin, Vector3::zeros());
+        assert!((direction - -Vector3::z()).norm() < 1e-6, "{:?}", direction);
+        // The center pixel of an even-sized image is half a pixel off it
+        let report = debug_pixel(
+            &Scene::spheres_demo(),
+            &config,
+            config.width / 2,
+            config.height / 2,
+        );
+        let (_, direction) = camera_ray(width / 2.0 + 0.5, height / 2.0 + 0.5, &config);
+        assert!(direction.z < -0.99999 && direction.x > 0.0 && direction.y < 0.0);
+        assert!(report.starts_with(&format!(
+            "pixel (128, 128)\n  ray origin [0.0, 0.0, 0.0] direction {:?}\n  hit object 0",
+            direction.as_slice()
+        )));
+    }
+
+    #[test]
+    fn misses_show_the_backplate_pixel() {
+        // A 16x16 backplate with a different color in every pixel
+        let mut ppm = b"P6\n# test pattern\n16 16\n255\n".to_vec();
+        for j in 0..16u8 {
+            for i in 0..16u8 {
+                ppm.extend_from_slice(&[i * 16, j * 16, 255 - i - j]);
+            }
+        }
+        let image = parse_ppm(&ppm).unwrap();
+        let plate = |(i, j): (u32, u32)| image.pixels[(j * 16 + i) as usize];
+        let expected = [(0, 0), (15, 0), (3, 12)].map(plate);
+        let mut scene = Scene::spheres_demo();
+        scene.backplate = Some(Backplate::new(image, BackplateFit::Stretch));
+        let config = args("--width 16 --height 16").unwrap();
+        let cancel = AtomicBool::new(false);
+        let framebuffer = render_radiance(
+            &scene,
+            &config,
+            Region::full(&config),
+            &cancel,
+            &mut RenderStats::default(),
+        )
+        .unwrap();
+        for ((i, j), expected) in [(0, 0), (15, 0), (3, 12)].into_iter().zip(expected) {
+            let index = (j * 16 + i) as usize;
+            assert_eq!(framebuffer.coverage[index], 0.0, "pixel {} {}", i, j);
+            assert_eq!(framebuffer.radiance[index], expected, "pixel {} {}", i, j);
+        }
+        assert!(parse_ppm(b"P6 16 16 255\n").is_err());
+        assert!(parse_ppm(b"P6 4294967295 4294967295 255\n").is_err());
+    }
+
+    #[test]
+    fn stats_count_rays_and_shadow_tests() {
+        let scene = Scene::shadows_demo();
+        let cancel = AtomicBool::new(false);
+        let stats = |words| {
+            let mut config = args(words).unwrap();
+            config.filename = "-".to_string();
+            render_to_buffer(&scene, &config, &cancel).unwrap().1
+        };
+        let plain = stats("--width 16 --height 8 --threads 1");
+        assert_eq!(plain.primary_rays, 16 * 8);
+        assert_eq!(plain.shadow_rays, 0);
+        assert_eq!(
+            plain.intersection_tests,
+            16 * 8 * (scene.primitive_count() as u64)
+        );
+        let shadowed = stats("--width 16 --height 8 --threads 1 --shadows");
+        assert_eq!(shadowed.primary_rays, plain.primary_rays);
+        // At most one shadow ray per pixel, as the demo has a single light
+        assert!(shadowed.shadow_rays > 0 && shadowed.shadow_rays <= 16 * 8);
+        assert!(shadowed.intersection_tests > plain.intersection_tests);
+        // Rays cast on worker threads are counted too
+        let threaded = stats("--width 16 --height 8 --threads 4 --shadows");
+        assert_eq!(threaded.shadow_rays, shadowed.shadow_rays);
+        assert_eq!(threaded.intersection_tests, shadowed.intersection_tests);
+    }
+
+    #[test]
+    fn constant_shader_fills_the_image() {
+        let color = Vector3::new(0.25, 0.5, 0.75);
+        let shader =
+            move |_: Vector3<f32>, _: Vector3<f32>, _: Option<&Intersection>, _: &Scene| color;
+        let config = RenderConfig {
+            width: 24,
+            height: 16,
+            samples: 4,
+            shader: Some(Arc::new(shader)),
+            ..RenderConfig::default()
+        };
+        let cancel = AtomicBool::new(false);
+        let (pixels, _) = render_to_buffer(&Scene::spheres_demo(), &config, &cancel).unwrap();
+        let expected = display_transform(color, (0, 0), &config);
+        assert_eq!(pixels.len(), 24 * 16 * 3);
+        assert!(pixels.chunks(3).all(|pixel| pixel == expected));
+    }
+
+    #[test]
+    fn lighting_mode_ignores_material_colors() {
+        // Mirror-image red and blue spheres under a light between them
+        let sphere = |x, color| Sphere::new(Vector3::new(x, 0.0, -5.0), 1.0, Material::new(color));
+        let spheres = vec![
+            sphere(-1.5, Vector3::new(1.0, 0.1, 0.1)),
+            sphere(1.5, Vector3::new(0.1, 0.1, 1.0)),
+        ];
+        let lights = vec![Light::new(Vector3::new(0.0, 5.0, 10.0), 1.0)];
+        let scene = Scene::new(spheres, lights);
+        let colors = |mode| {
+            let config = RenderConfig {
+                mode,
+                ..RenderConfig::default()
+            };
+            let range = config.primary_range();
+            [-1.4f32, 1.4].map(|x| {
+                let direction = Vector3::new(x, 0.2, -4.0).normalize();
+                cast_ray(Vector3::zeros(), direction, &scene, range, &config, 0.5).unwrap()
+            })
+        };
+        let [left, right] = colors(RenderMode::Lighting);
+        assert!(left.x > 0.1, "{:?}", left);
+        assert!((left - right).norm() < 1e-5, "{:?} {:?}", left, right);
+        let [left, right] = colors(RenderMode::Shaded);
+        assert!(left.x > 2.0 * right.x && right.z > 2.0 * left.z);
+    }
+
+    #[test]
+    fn samples_are_averaged_before_gamma() {
+        let config = RenderConfig {
+            width: 7,
+            height: 7,
+            samples: 2,
+            threads: 1,
+            gamma: 2.2,
+            ..RenderConfig::default()
+        };
+        let (black, white) = (Vector3::zeros(), Vector3::repeat(1.0));
+        let encode = |v| display_transform(v, (0, 0), &config)[0];
+        let linear = encode((black + white) / 2.0);
+        let encoded = ((encode(black) as u32 + encode(white) as u32) / 2) as u8;
+        assert_eq!((linear, encoded), (186, 127));
+        // The shader alternates between black and white, and with an odd pixel
+        // count each pixel gets one of each from its two sample passes
+        let shaded = AtomicU64::new(0);
+        let shader =
+            move |_: Vector3<f32>, _: Vector3<f32>, _: Option<&Intersection>, _: &Scene| {
+                if shaded.fetch_add(1, Ordering::Relaxed).is_multiple_of(2) {
+                    black
+                } else {
+                    white
+                }
+            };
+        let config = RenderConfig {
+            shader: Some(Arc::new(shader)),
+            ..config.clone()
+        };
+        let cancel = AtomicBool::new(false);
+        let (pixels, _) = render_to_buffer(&Scene::spheres_demo(), &config, &cancel).unwrap();
+        assert!(pixels.iter().all(|&c| c == linear));
+    }
+
+    #[test]
+    fn cornell_box_walls_are_red_and_green() {
+        let scene = Scene::cornell_box();
+        let config = RenderConfig::default();
+        let range = config.primary_range();
+        let shade = |x, y, z| {
+            let direction = Vector3::new(x, y, z).normalize();
+            cast_ray(Vector3::zeros(), direction, &scene, range, &config, 0.5).unwrap()
+        };
+        let (left, right) = (shade(-2.75, 0.0, -8.0), shade(2.75, 0.0, -8.0));
+        assert!(left.x > 4.0 * left.y && left.x > 4.0 * left.z, "{:?}", left);
+        assert!(
+            right.y > 2.0 * right.x && right.y > 2.0 * right.z,
+            "{:?}",
+            right
+        );
+        // Light bounced off the walls bleeds onto the white floor, redder
+        // next to the red wall and greener next to the green one
+        let config = args("--indirect-samples 64 --seed 7").unwrap();
+        let shade = |x, y, z| {
+            let direction = Vector3::new(x, y, z).normalize();
+            cast_ray(Vector3::zeros(), direction, &scene, range, &config, 0.5).unwrap()
+        };
+        let (floor_left, floor_right) = (shade(-2.4, -2.75, -7.0), shade(2.4, -2.75, -7.0));
+        assert!(floor_left.min() > 0.1, "{:?}", floor_left);
+        assert!(
+            floor_left.x > floor_right.x * 1.1,
+            "{:?} {:?}",
+            floor_left,
+            floor_right
+        );
+        assert!(
+            floor_right.y > floor_left.y * 1.05,
+            "{:?} {:?}",
+            floor_left,
+            floor_right
+        );
+        // The same seed traces the same indirect rays
+        assert_eq!(shade(-2.4, -2.75, -7.0), floor_left);
+    }
+
+    #[test]
+    fn preview_renders_small_and_upsamples() {
+        let config = RenderConfig {
+            width: 8,
+            height: 8,
+            preview_scale: 2,
+            ..RenderConfig::default()
+        };
+        let internal = config.preview().unwrap();
+        assert_eq!((internal.width, internal.height), (4, 4));
+        let scene = Scene::spheres_demo();
+        let cancel = AtomicBool::new(false);
+        let small = render_radiance(
+            &scene,
+            &internal,
+            Region::full(&internal),
+            &cancel,
+            &mut RenderStats::default(),
+        )
+        .unwrap();
+        let full = finish(&config, small.clone());
+        assert_eq!((full.width, full.height), (8, 8));
+        // Nearest upsampling repeats each internal pixel over a 2x2 block
+        for j in 0..8 {
+            for i in 0..8 {
+                assert_eq!(full.radiance[j * 8 + i], small.radiance[j / 2 * 4 + i / 2]);
+            }
+        }
+        let (pixels, stats) = render_to_buffer(&scene, &config, &cancel).unwrap();
+        assert_eq!((pixels.len(), stats.primary_rays), (8 * 8 * 3, 4 * 4));
+    }
+
+    #[test]
+    fn sphere_grid_has_n_cubed_spheres_in_view() {
+        let scene = Scene::sphere_grid(2, 2.0);
+        assert_eq!(scene.spheres.len(), 8);
+        let config = RenderConfig {
+            width: 32,
+            height: 32,
+            mode: RenderMode::ObjectId,
+            ..RenderConfig::default()
+        };
+        let cancel = AtomicBool::new(false);
+        let framebuffer = render_radiance(
+            &scene,
+            &config,
+            Region::full(&config),
+            &cancel,
+            &mut RenderStats::default(),
+        )
+        .unwrap();
+        // The block fits the view with background all around it
+        let edges = (0..32).flat_map(|k| [k, 31 * 32 + k, k * 32, k * 32 + 31]);
+        assert!(edges
+            .into_iter()
+            .all(|index| framebuffer.coverage[index] == 0.0));
+        // and shows the front four spheres, which hide the back four, with the
+        // view axis running through the gap between them
+        let mut objects: Vec<_> = framebuffer
+            .radiance
+            .iter()
+            .map(|c| c.map(f32::to_bits))
+            .collect();
+        objects.sort_by_key(|c| (c.x, c.y, c.z));
+        objects.dedup();
+        assert_eq!(objects.len(), 4 + 1);
+        assert_eq!(framebuffer.coverage[16 * 32 + 16], 0.0);
+        assert!(Scene::sphere_grid(0, 2.0).spheres.is_empty());
+    }
+
+    #[test]
+    fn quarter_roll_swaps_the_sphere_extents() {
+        // A side by side pair, much wider than it is tall
+        let material = Material::new(Vector3::repeat(1.0));
+        let spheres = [-1.5, 1.5].map(|x| Sphere::new(Vector3::new(x, 0.0, -8.0), 1.0, material));
+        let scene = Scene::new(spheres.into(), Vec::new());
+        let extents = |roll| {
+            let config = args(&format!("--width 64 --height 64 --roll {}", roll)).unwrap();
+            let cancel = AtomicBool::new(false);
+            let framebuffer = render_radiance(
+                &scene,
+                &config,
+                Region::full(&config),
+                &cancel,
+                &mut RenderStats::default(),
+            )
+            .unwrap();
+            let covered: Vec<_> = (0..64 * 64)
+                .filter(|&index| framebuffer.coverage[index] > 0.0)
+                .map(|index| (index % 64, index / 64))
+                .collect();
+            let extent = |axis: fn(&(usize, usize)) -> usize| {
+                covered.iter().map(axis).max().unwrap() - covered.iter().map(axis).min().unwrap()
+            };
+            (extent(|p| p.0), extent(|p| p.1))
+        };
+        let (width, height) = extents(0);
+        assert!(width > height * 2, "{} {}", width, height);
+        assert_eq!(extents(90), (height, width));
+    }
+
+    #[test]
+    fn spheres_before_the_near_plane_are_clipped() {
+        let material = Material::new(Vector3::repeat(1.0));
+        let sphere = Sphere::new(Vector3::new(0.0, 0.0, -2.0), 0.5, material);
+        let scene = Scene::new(vec![sphere], vec![Light::new(Vector3::zeros(), 1.0)]);
+        let hit = |near| {
+            let config = args(&format!("--near {}", near)).unwrap();
+            let range = config.primary_range();
+            cast_ray(Vector3::zeros(), -Vector3::z(), &scene, range, &config, 0.5).is_some()
+        };
+        assert!(hit("0.001"));
+        assert!(hit("1"));
+        // The whole sphere lies between 1.5 and 2.5 along the ray
+        assert!(!hit("3"));
+    }
+
+    #[test]
+    fn auto_exposure_moves_the_log_average_to_the_target() {
+        let image = |scale: f32| {
+            let pixels = (1..=16).map(|k| Vector3::new(1.0, 0.8, 0.6) * k as f32 * scale);
+            Framebuffer::from_image(Image {
+                width: 4,
+                height: 4,
+                pixels: pixels.collect(),
+            })
+        };
+        let log_average = |framebuffer: &Framebuffer| {
+            let sum: f32 = framebuffer
+                .radiance
+                .iter()
+                .map(|&v| luminance(v).ln())
+                .sum();
+            (sum / framebuffer.radiance.len() as f32).exp()
+        };
+        let mean = |framebuffer: &Framebuffer| {
+            framebuffer
+                .radiance
+                .iter()
+                .map(|&v| luminance(v))
+                .sum::<f32>()
+                / 16.0
+        };
+        for (scale, brightened) in [(0.01, true), (10.0, false)] {
+            let framebuffer = image(scale);
+            let exposed = framebuffer.exposed(0.18);
+            assert_eq!(mean(&exposed) > mean(&framebuffer), brightened);
+            assert!(
+                (log_average(&exposed) / 0.18 - 1.0).abs() < 0.02,
+                "scale {}",
+                scale
+            );
+            assert_eq!(exposed.coverage, framebuffer.coverage);
+        }
+    }
+
+    #[test]
+    fn rim_light_brightens_only_the_silhouette() {
+        let material = Material::new(Vector3::new(0.4, 0.4, 0.4));
+        let sphere = Sphere::new(Vector3::new(0.0, 0.0, -5.0), 1.0, material);
+        let scene = Scene::new(
+            vec![sphere],
+            vec![Light::new(Vector3::new(0.0, 0.0, 10.0), 1.0)],
+        );
+        let shade = |words: &str, x: f32| {
+            let config = args(words).unwrap();
+            let direction = Vector3::new(x, 0.0, -1.0).normalize();
+            cast_ray(
+                Vector3::zeros(),
+                direction,
+                &scene,
+                config.primary_range(),
+                &config,
+                0.5,
+            )
+            .unwrap()
+        };
+        // The sphere's silhouette is at sin(angle) = 1/5 from the view axis
+        let edge = 0.19 / (1.0f32 - 0.19 * 0.19).sqrt();
+        let rim = "--rim 1,0.5,0 --rim-power 2";
+        assert_eq!(shade(rim, 0.0), shade("", 0.0));
+        let (plain, glowing) = (shade("", edge), shade(rim, edge));
+        assert!(glowing.x > plain.x + 0.3, "{:?} {:?}", plain, glowing);
+        assert!(glowing.y > plain.y && glowing.z == plain.z);
+    }
+
+    #[test]
+    fn flip_y_mirrors_the_image_vertically() {
+        let scene = Scene::spheres_demo();
+        let cancel = AtomicBool::new(false);
+        for (samples, edge_aa) in [(1, 0), (1, 3), (3, 0)] {
+            let render = |flip_y| {
+                let config = RenderConfig {
+                    width: 24,
+                    height: 16,
+                    samples,
+                    edge_aa,
+                    flip_y,
+                    ..RenderConfig::default()
+                };
+                render_to_buffer(&scene, &config, &cancel).unwrap().0
+            };
+            let flipped: Vec<u8> = render(true)
+                .chunks(24 * 3)
+                .rev()
+                .flatten()
+                .copied()
+                .collect();
+            assert_eq!(
+                flipped,
+                render(false),
+                "{} samples, edge aa {}",
+                samples,
+                edge_aa
+            );
+        }
+    }
+
+    #[test]
+    fn light_markers_show_lights_without_changing_shading() {
+        // A light straight ahead of the camera and a sphere off to the side
+        let material = Material::new(Vector3::repeat(0.8));
+        let sphere = Sphere::new(Vector3::new(3.0, 0.0, -10.0), 1.0, material);
+        let light = Light::new(Vector3::new(0.0, 0.0, -6.0), 1.5);
+        let scene = Scene::new(vec![sphere], vec![light]);
+        let sample = |markers: &str, i: u32| {
+            let config = args(&format!("--width 33 --height 33 {}", markers)).unwrap();
+            sample_pixel(
+                &scene,
+                &config,
+                i,
+                16,
+                CameraSample::at(i as f32 + 0.5, 16.5),
+            )
+        };
+        let marked = sample("--light-markers 0.2", 16);
+        assert!(marked.hit);
+        assert_eq!(marked.color, Vector3::repeat(1.5));
+        let plain = sample("", 16);
+        assert!(!plain.hit);
+        assert_eq!(plain.color, RenderConfig::default().sky_horizon);
+        // The sphere is lit exactly the same either way
+        let sphere_pixel = (0..33).find(|&i| sample("", i).hit).unwrap();
+        assert_eq!(
+            sample("--light-markers 0.2", sphere_pixel).color,
+            sample("", sphere_pixel).color
+        );
+    }
+
+    #[test]
+    fn back_face_hits_turn_the_shading_normal_to_the_ray() {
+        let material = Material::new(Vector3::repeat(1.0));
+        let z = |x: f32, y: f32| Vector3::new(x, y, -5.0);
+        let triangle = Triangle::new([z(-1.0, -1.0), z(1.0, -1.0), z(0.0, 1.0)], material);
+        let range = Interval::new(0.001, f32::MAX);
+        let front = triangle
+            .ray_intersect(Vector3::zeros(), -Vector3::z(), range)
+            .unwrap();
+        assert!(front.front_face);
+        assert_eq!(front.geometric_normal, Vector3::z());
+        assert_eq!(front.normal, Vector3::z());
+        let behind = Vector3::new(0.0, 0.0, -10.0);
+        let back = triangle.ray_intersect(behind, Vector3::z(), range).unwrap();
+        assert!(!back.front_face);
+        assert_eq!(back.geometric_normal, Vector3::z());
+        assert_eq!(back.normal, -Vector3::z());
+        // From inside a sphere every hit is on the back of the surface
+        let sphere = Sphere::new(Vector3::zeros(), 2.0, material);
+        let inside = sphere
+            .ray_intersect(Vector3::zeros(), Vector3::x(), range)
+            .unwrap();
+        assert!(!inside.front_face);
+        assert!((inside.geometric_normal - Vector3::x()).norm() < 1e-6);
+        assert!((inside.normal + Vector3::x()).norm() < 1e-6);
+    }
+
+    #[test]
+    fn z_up_renders_the_equivalent_scene_the_same() {
+        // The spheres demo in Y-up coordinates, and turned so +Y up becomes +Z up
+        let scene = |to_up: fn(Vector3<f32>) -> Vector3<f32>| {
+            let chartreuse = Material::new(Vector3::new(0.5, 0.8, 0.3));
+            let red = Material::new(Vector3::new(1.0, 0.5, 0.5));
+            let spheres = vec![
+                Sphere::new(to_up(Vector3::new(2.0, 1.0, -16.0)), 5.0, red),
+                Sphere::new(to_up(Vector3::new(2.0, 3.0, -11.0)), 1.0, chartreuse),
+                Sphere::new(to_up(Vector3::new(-3.0, 0.0, -16.0)), 2.0, chartreuse),
+            ];
+            let lights = vec![Light::new(to_up(Vector3::new(-20.0, 20.0, 20.0)), 1.0)];
+            Scene::new(spheres, lights)
+        };
+        let y_up = scene(|p| p);
+        let z_up = scene(|p| Vector3::new(p.x, -p.z, p.y));
+        let render = |scene: &Scene, words: &str| {
+            let config = args(&format!("--width 32 --height 24 {}", words)).unwrap();
+            let cancel = AtomicBool::new(false);
+            render_radiance(
+                scene,
+                &config,
+                Region::full(&config),
+                &cancel,
+                &mut RenderStats::default(),
+            )
+            .unwrap()
+        };
+        let (expected, turned) = (render(&y_up, ""), render(&z_up, "--world-up z"));
+        assert_eq!(expected.coverage, turned.coverage);
+        for (a, b) in expected.radiance.iter().zip(&turned.radiance) {
+            assert!((a - b).norm() < 1e-4, "{:?} {:?}", a, b);
+        }
+        // Z-up looks level along +Y from the view center
+        let config = args("--world-up z").unwrap();
+        let (_, level) = camera_ray(
+            config.width as f32 / 2.0,
+            config.height as f32 / 2.0,
+            &config,
+        );
+        assert!(level.z.abs() < 1e-6 && level.y > 0.99);
+    }
+
+    #[test]
+    fn wireframe_darkens_only_near_triangle_edges() {
+        let material = Material::new(Vector3::repeat(0.8));
+        let z = |x: f32, y: f32| Vector3::new(x, y, -5.0);
+        let mut scene = Scene::new(Vec::new(), vec![Light::new(Vector3::zeros(), 1.0)]);
+        scene.add_mesh(vec![Triangle::new(
+            [z(-2.0, -2.0), z(2.0, -2.0), z(0.0, 2.0)],
+            material,
+        )]);
+        let shade = |words: &str, target: Vector3<f32>| {
+            let config = args(words).unwrap();
+            let range = config.primary_range();
+            cast_ray(
+                Vector3::zeros(),
+                target.normalize(),
+                &scene,
+                range,
+                &config,
+                0.5,
+            )
+            .unwrap()
+        };
+        let (center, edge) = (z(0.0, -2.0 / 3.0), z(0.0, -1.95));
+        let wire = "--wireframe 0.05";
+        assert_eq!(shade(wire, center), shade("", center));
+        assert!(shade(wire, edge).norm() < 0.5 * shade("", edge).norm());
+    }
+
+    #[test]
+    fn each_sphere_uses_its_own_shading_model() {
+        // Twin spheres lit from the front, one of them unlit
+        let color = Vector3::new(0.6, 0.4, 0.2);
+        let lit = Material::new(color);
+        let unlit = Material::Surface(Surface::new(color).unlit());
+        let spheres = vec![
+            Sphere::new(Vector3::new(-1.5, 0.0, -5.0), 1.0, lit),
+            Sphere::new(Vector3::new(1.5, 0.0, -5.0), 1.0, unlit),
+        ];
+        let scene = Scene::new(spheres, vec![Light::new(Vector3::new(0.0, 0.0, 10.0), 1.0)]);
+        let config = RenderConfig::default();
+        let range = config.primary_range();
+        let [left, right] = [-1.5f32, 1.5].map(|x| {
+            let direction = Vector3::new(x, 0.0, -5.0).normalize();
+            cast_ray(Vector3::zeros(), direction, &scene, range, &config, 0.5).unwrap()
+        });
+        assert_eq!(right, color);
+        assert!(left.x > color.x, "{:?}", left);
+    }
+
+    #[test]
+    fn tangent_rays_miss_consistently() {
+        let material = Material::new(Vector3::repeat(1.0));
+        let sphere = Sphere::new(Vector3::new(0.0, 0.0, -5.0), 1.0, material);
+        let range = Interval::new(0.001, f32::MAX);
+        // Rays grazing the silhouette at x = 1, nudged by a few ulps either way
+        for k in -8..=8 {
+            let nudge = k as f32 * f32::EPSILON;
+            let origin = Vector3::new(1.0 + nudge, 0.0, 0.0);
+            let direction = Vector3::new(nudge / 10.0, 0.0, -1.0);
+            assert!(
+                sphere.ray_intersect(origin, direction, range).is_none(),
+                "nudge {}",
+                nudge
+            );
+        }
+        // Just inside the silhouette the ray hits the front of the sphere
+        let hit = sphere.ray_intersect(Vector3::new(0.999, 0.0, 0.0), -Vector3::z(), range);
+        assert!(hit.unwrap().distance < 5.0);
+    }
+
+    #[test]
+    fn aov_outputs_sit_beside_the_beauty_image() {
+        let filename = temp_path("aovs.pfm");
+        let config = args(&format!(
+            "--width 12 --height 8 --output {} --aov albedo --aov normal --aov depth",
+            filename
+        ))
+        .unwrap();
+        let scene = Scene::spheres_demo();
+        render(&scene, &config, &AtomicBool::new(false), None).unwrap();
+        let mut images = Vec::new();
+        for name in [
+            "aovs.pfm",
+            "aovs.albedo.pfm",
+            "aovs.normal.pfm",
+            "aovs.depth.pfm",
+        ] {
+            let path = temp_path(name);
+            images.push(load_pfm(&path).unwrap());
+            std::fs::remove_file(&path).unwrap();
+        }
+        assert!(images
+            .iter()
+            .all(|image| (image.width, image.height) == (12, 8)));
+        // Every buffer sees the hit through the pixel center from the same camera
+        let center = 4 * 12 + 6;
+        let (origin, direction) = camera_ray(6.5, 4.5, &config);
+        let hit = scene_intersect(origin, direction, &scene, config.primary_range()).unwrap();
+        let [_, albedo, normal, depth] = &images[..] else {
+            unreachable!()
+        };
+        assert_eq!(albedo.pixels[center], hit.material.diffuse());
+        assert_eq!(normal.pixels[center], hit.normal);
+        assert_eq!(depth.pixels[center], Vector3::repeat(hit.distance));
+    }
+
+    #[test]
+    fn light_cutoff_drops_only_the_dim_light() {
+        let material = Material::new(Vector3::repeat(0.8));
+        let sphere = || Sphere::new(Vector3::new(0.0, 0.0, -5.0), 1.0, material);
+        let bright = || Light::new(Vector3::new(0.0, 0.0, 10.0), 1.0);
+        let dim = Light::new(Vector3::new(-100.0, 0.0, -5.0), 0.05);
+        let both = Scene::new(vec![sphere()], vec![bright(), dim]);
+        let bright_only = Scene::new(vec![sphere()], vec![bright()]);
+        let shade = |scene: &Scene, cutoff: &str| {
+            let config = args(&format!("--light-cutoff {}", cutoff)).unwrap();
+            let direction = Vector3::new(-0.15, 0.0, -1.0).normalize();
+            cast_ray(
+                Vector3::zeros(),
+                direction,
+                scene,
+                config.primary_range(),
+                &config,
+                0.5,
+            )
+            .unwrap()
+        };
+        // The left of the sphere faces both lights
+        assert!(shade(&both, "0").x > shade(&bright_only, "0").x);
+        assert_eq!(shade(&both, "0.1"), shade(&bright_only, "0.1"));
+        assert_eq!(shade(&bright_only, "0.1"), shade(&bright_only, "0"));
+    }
+
+    #[test]
+    fn focal_length_sets_the_fov_and_ray_spread() {
+        // A 50mm lens on a 24mm high sensor sees 12mm either side at 50mm
+        let config = args("--height 64 --focal-length 50 --sensor-height 24").unwrap();
+        assert!((config.fov - 2.0 * 0.24f32.atan()).abs() < 1e-6);
+        assert!((config.fov.to_degrees() - 26.99).abs() < 0.01);
+        let (_, top) = camera_ray(config.width as f32 / 2.0, 0.0, &config);
+        assert!((top.y / -top.z - 0.24).abs() < 1e-5, "{:?}", top);
+        // The focal length wins over --fov in either order, and must be positive
+        let later_fov = args("--focal-length 50 --sensor-height 24 --fov 90").unwrap();
+        assert_eq!(later_fov.fov, config.fov);
+        assert!(args("--focal-length 0").is_err());
+        assert!(args("--focal-length 50 --sensor-height -1").is_err());
+    }
+
+    #[test]
+    fn diffuse_clamp_caps_overlapping_lights() {
+        let albedo = Vector3::repeat(0.5);
+        let sphere = Sphere::new(Vector3::new(0.0, 0.0, -5.0), 1.0, Material::new(albedo));
+        let lights = [-0.5, 0.0, 0.5].map(|x| Light::new(Vector3::new(x, 0.0, 10.0), 1.0));
+        let scene = Scene::new(vec![sphere], lights.into());
+        let shade = |words: &str| {
+            let config = args(words).unwrap();
+            let color = cast_ray(
+                Vector3::zeros(),
+                -Vector3::z(),
+                &scene,
+                config.primary_range(),
+                &config,
+                0.5,
+            );
+            (color.unwrap(), hemisphere_ambient(Vector3::z(), &config))
+        };
+        // Facing all three lights head on, N.L sums to nearly 3
+        let (unclamped, ambient) = shade("");
+        let cap = albedo.component_mul(&(ambient + Vector3::repeat(1.0)));
+        assert!(unclamped.x > cap.x + 0.9, "{:?}", unclamped);
+        assert!((shade("--diffuse-clamp 1").0 - cap).norm() < 1e-6);
+        let (soft, _) = shade("--diffuse-knee 1");
+        assert!(soft.x < cap.x && soft.x > cap.x - 0.05, "{:?}", soft);
+    }
+
+    #[test]
+    fn object_masks_cover_every_pixel_once() {
+        let filename = temp_path("masks.pfm");
+        let config = args(&format!(
+            "--width 24 --height 16 --samples 4 --masks --output {}",
+            filename
+        ))
+        .unwrap();
+        let scene = Scene::spheres_demo();
+        render(&scene, &config, &AtomicBool::new(false), None).unwrap();
+        std::fs::remove_file(&filename).unwrap();
+        let names = (0..scene.object_count())
+            .map(|object| format!("masks.mask{}.pfm", object))
+            .chain(["masks.background.pfm".to_string()]);
+        let mut total = vec![0.0; 24 * 16];
+        for name in names {
+            let path = temp_path(&name);
+            let mask = load_pfm(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert!(mask.pixels.iter().any(|v| v.x == 1.0), "{} is empty", name);
+            for (sum, v) in total.iter_mut().zip(&mask.pixels) {
+                assert!(v.x == 0.0 || v.x == 1.0);
+                *sum += v.x;
+            }
+        }
+        assert!(total.iter().all(|&sum| sum == 1.0));
+    }
+
+    #[test]
+    fn rays_through_the_bite_hit_the_carved_surface() {
+        let material = Material::new(Vector3::repeat(1.0));
+        let base = || Sphere::new(Vector3::new(0.0, 0.0, -5.0), 1.0, material);
+        // A bite out of the front of the sphere, facing the camera
+        let bite = Sphere::new(Vector3::new(0.0, 0.0, -4.0), 0.5, material);
+        let carved = SphereDifference::new(base(), bite);
+        let range = Interval::new(0.001, f32::MAX);
+        let hit = carved
+            .ray_intersect(Vector3::zeros(), -Vector3::z(), range)
+            .unwrap();
+        // The bottom of the bite, whose inward normal faces back out of the hollow
+        assert!((hit.distance - 4.5).abs() < 1e-5);
+        assert!((hit.geometric_normal - Vector3::z()).norm() < 1e-5);
+        assert!(hit.front_face);
+        // Rays missing the bite still see the base sphere
+        let origin = Vector3::new(0.8, 0.0, 0.0);
+        let beside = carved.ray_intersect(origin, -Vector3::z(), range).unwrap();
+        let expected = base().ray_intersect(origin, -Vector3::z(), range).unwrap();
+        assert_eq!(beside.distance, expected.distance);
+        // A bite bigger than the base leaves nothing to hit
+        let everything = Sphere::new(Vector3::new(0.0, 0.0, -5.0), 1.2, material);
+        let gone = SphereDifference::new(base(), everything);
+        assert!(gone
+            .ray_intersect(Vector3::zeros(), -Vector3::z(), range)
+            .is_none());
+    }
+
+    #[test]
+    fn quality_presets_are_valid_and_ordered() {
+        let [draft, medium, last] =
+            ["draft", "medium", "final"].map(|name| args(&format!("--quality {}", name)).unwrap());
+        for config in [&draft, &medium, &last] {
+            config.validate().unwrap();
+        }
+        assert!(last.samples >= medium.samples && medium.samples >= draft.samples);
+        assert!(last.samples > draft.samples);
+        // Draft is the only one that trades resolution for speed
+        assert_eq!(
+            [
+                draft.preview_scale,
+                medium.preview_scale,
+                last.preview_scale
+            ],
+            [2, 1, 1]
+        );
+        // Explicit flags win over the preset in either order
+        assert_eq!(args("--samples 3 --quality final").unwrap().samples, 3);
+        assert_eq!(args("--quality final --samples 3").unwrap().samples, 3);
+        assert!(args("--quality best").is_err());
+    }
+
+    #[test]
+    fn dark_lights_change_nothing_and_cast_no_shadow_rays() {
+        let config = args("--width 24 --height 16 --threads 1 --shadows").unwrap();
+        let cancel = AtomicBool::new(false);
+        let scene = Scene::shadows_demo();
+        let (expected, stats) = render_to_buffer(&scene, &config, &cancel).unwrap();
+        assert!(stats.shadow_rays > 0);
+        for intensity in [0.0, -1.0] {
+            let mut darkened = Scene::shadows_demo();
+            darkened
+                .lights
+                .push(Light::new(Vector3::new(0.0, 10.0, 0.0), intensity));
+            let (pixels, darkened_stats) = render_to_buffer(&darkened, &config, &cancel).unwrap();
+            assert_eq!(pixels, expected);
+            assert_eq!(darkened_stats.shadow_rays, stats.shadow_rays);
+        }
+    }
+
+    #[test]
+    fn mapped_pixels_get_more_samples_and_less_noise() {
+        // White noise keyed on the jittered ray direction
+        let shader =
+            |_: Vector3<f32>, direction: Vector3<f32>, _: Option<&Intersection>, _: &Scene| {
+                let bits = direction.x.to_bits() ^ direction.y.to_bits().rotate_left(16);
+                Vector3::repeat((bits.wrapping_mul(0x9E37_79B9) >> 8) as f32 / (1 << 24) as f32)
+            };
+        // The top half of the image is flagged for 32 samples, the rest gets one
+        let (width, height) = (16, 16);
+        let map: Vec<u32> = (0..width * height)
+            .map(|index| if index < width * height / 2 { 32 } else { 1 })
+            .collect();
+        let config = RenderConfig {
+            width,
+            height,
+            shader: Some(Arc::new(shader)),
+            sample_map: Some(map.into()),
+            ..RenderConfig::default()
+        };
+        let cancel = AtomicBool::new(false);
+        let mut stats = RenderStats::default();
+        let mut framebuffer = None;
+        render_passes(
+            &Scene::spheres_demo(),
+            &config,
+            &cancel,
+            &mut stats,
+            |image| {
+                framebuffer = Some(image);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(stats.primary_rays, (width * height / 2 * (32 + 1)) as u64);
+        let variance = |pixels: &[Vector3<f32>]| {
+            let mean = pixels.iter().map(|v| v.x).sum::<f32>() / pixels.len() as f32;
+            pixels.iter().map(|v| (v.x - mean).powi(2)).sum::<f32>() / pixels.len() as f32
+        };
+        let radiance = framebuffer.unwrap().radiance;
+        let (flagged, unflagged) = radiance.split_at((width * height / 2) as usize);
+        assert!(
+            variance(flagged) * 8.0 < variance(unflagged),
+            "{} {}",
+            variance(flagged),
+            variance(unflagged)
+        );
+    }
+
+    #[test]
+    fn render_hash_tracks_the_scene_and_not_the_output_path() {
+        let config = RenderConfig::default();
+        let hash = render_hash(&Scene::spheres_demo(), &config);
+        assert_eq!(render_hash(&Scene::spheres_demo(), &config), hash);
+        let mut grown = Scene::spheres_demo();
+        grown.spheres[1].radius += 0.01;
+        assert_ne!(render_hash(&grown, &config), hash);
+        let renamed = RenderConfig {
+            filename: "elsewhere.ppm".to_string(),
+            threads: 3,
+            ..config.clone()
+        };
+        assert_eq!(render_hash(&Scene::spheres_demo(), &renamed), hash);
+        let wider = RenderConfig {
+            width: config.width + 1,
+            ..config
+        };
+        assert_ne!(render_hash(&Scene::spheres_demo(), &wider), hash);
+    }
+
+    #[test]
+    fn desaturating_keeps_the_hue_that_clipping_shifts() {
+        // With red the largest channel and blue the smallest, the hue is fixed
+        // by where green sits between them
+        let hue = |c: Vector3<f32>| (c.y - c.z) / (c.x - c.z);
+        let saturation = |c: Vector3<f32>| (c.max() - c.min()) / c.max();
+        let bright = Vector3::new(2.0, 0.5, 0.1);
+        let clipped = GamutMap::Clip.apply(bright);
+        let mapped = GamutMap::Desaturate.apply(bright);
+        assert_eq!(clipped, Vector3::new(1.0, 0.5, 0.1));
+        assert!((hue(clipped) - hue(bright)).abs() > 0.2);
+        assert!((hue(mapped) - hue(bright)).abs() < 1e-5, "{:?}", mapped);
+        assert!((mapped.x - 1.0).abs() < 1e-6);
+        assert!(saturation(mapped) < saturation(bright));
+        assert!((luminance(mapped) - luminance(bright)).abs() < 1e-5);
+        // Colors already in gamut pass through either way
+        let dim = Vector3::new(0.9, 0.5, 0.1);
+        assert_eq!(GamutMap::Desaturate.apply(dim), dim);
+        assert_eq!(GamutMap::Clip.apply(dim), dim);
+    }
+
+    #[test]
+    fn manifest_round_trips_and_replays_the_image() {
+        let output = temp_path("manifest \"quoted\" \\ é.ppm");
+        let manifest = temp_path("manifest.json");
+        let recorded: Vec<String> = ["--width", "12", "--height", "8", "--samples", "3"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .chain([
+                "--seed".to_string(),
+                "7".to_string(),
+                "--output".to_string(),
+                output,
+            ])
+            .collect();
+        let config = parse_args(recorded.iter().cloned()).unwrap();
+        let scene = Scene::spheres_demo();
+        let cancel = AtomicBool::new(false);
+        let stats = render(&scene, &config, &cancel, None).unwrap();
+        write_manifest(&manifest, &recorded, &scene, &config, &stats).unwrap();
+        let image = std::fs::read(&config.filename).unwrap();
+        std::fs::remove_file(&config.filename).unwrap();
+
+        let (args, replay) = read_manifest(&manifest).unwrap();
+        std::fs::remove_file(&manifest).unwrap();
+        assert_eq!(args, recorded);
+        assert_eq!(replay.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(replay.render_hash, render_hash(&scene, &config));
+        // Replaying the recorded arguments renders the same image again
+        let replayed = parse_args(args.into_iter()).unwrap();
+        render(&scene, &replayed, &cancel, None).unwrap();
+        assert_eq!(std::fs::read(&replayed.filename).unwrap(), image);
+        std::fs::remove_file(&replayed.filename).unwrap();
+    }
+
+    #[test]
+    fn tiny_csv_render_lists_the_exact_radiance() {
+        let filename = temp_path("tiny.csv");
+        let config = args(&format!(
+            "--width 2 --height 2 --fov 150 --output {}",
+            filename
+        ))
+        .unwrap();
+        let scene = Scene::spheres_demo();
+        let cancel = AtomicBool::new(false);
+        render(&scene, &config, &cancel, None).unwrap();
+        let csv = std::fs::read_to_string(&filename).unwrap();
+        std::fs::remove_file(&filename).unwrap();
+        let mut radiance = Vec::new();
+        let mut stats = RenderStats::default();
+        render_passes(&scene, &config, &cancel, &mut stats, |framebuffer| {
+            radiance = framebuffer.radiance;
+            Ok(())
+        })
+        .unwrap();
+
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines[0], "x,y,r,g,b");
+        assert_eq!(lines.len(), 1 + 4);
+        for (index, line) in lines[1..].iter().enumerate() {
+            let fields: Vec<&str> = line.split(',').collect();
+            assert_eq!(
+                fields[..2],
+                [(index % 2).to_string(), (index / 2).to_string()]
+            );
+            let rgb: Vec<f32> = fields[2..]
+                .iter()
+                .map(|field| field.parse().unwrap())
+                .collect();
+            assert_eq!(rgb, radiance[index].as_slice());
+        }
+        let too_big = format!("--width 1000 --height 1000 --output {}", filename);
+        assert!(args(&too_big).is_err());
+    }
+
+    #[test]
+    fn progressive_streams_write_every_pass() {
+        let config = args("--width 12 --height 8 --samples 5 --progressive --output -").unwrap();
+        let scene = Scene::spheres_demo();
+        let cancel = AtomicBool::new(false);
+        let mut stream = Vec::new();
+        let stats = render_frames(&scene, &config, &cancel, &mut stream).unwrap();
+        assert_eq!(stats.primary_rays, 12 * 8 * 5);
+        // Batches of 1, 2 and 2 samples, each followed by a whole frame
+        let header = b"P6 12 8 255\n";
+        let frame = header.len() + 12 * 8 * 3;
+        assert_eq!(stream.len(), 3 * frame);
+        let frames: Vec<_> = stream.chunks(frame).collect();
+        assert!(frames.iter().all(|frame| frame.starts_with(header)));
+        let (last, _) = render_to_buffer(&scene, &config, &cancel).unwrap();
+        assert_eq!(&frames[2][header.len()..], &last[..]);
+        assert_ne!(frames[0], frames[2]);
+    }
+
+    #[test]
+    fn replaying_a_manifest_never_overwrites_the_archived_render() {
+        let output = temp_path("archived.ppm");
+        let manifest = temp_path("archived.json");
+        let recorded: Vec<String> = format!(
+            "--width 8 --height 6 --output {} --manifest {} --seed 3",
+            output, manifest
+        )
+        .split_whitespace()
+        .map(str::to_string)
+        .collect();
+        let config = parse_args(recorded.iter().cloned()).unwrap();
+        let scene = Scene::spheres_demo();
+        let cancel = AtomicBool::new(false);
+        let stats = render(&scene, &config, &cancel, None).unwrap();
+        write_manifest(&manifest, &recorded, &scene, &config, &stats).unwrap();
+        let archived = std::fs::read(&output).unwrap();
+
+        let replay = |extra: &str| {
+            let mut args = vec!["--from-manifest".to_string(), manifest.clone()];
+            args.extend(extra.split_whitespace().map(str::to_string));
+            expand_manifest(args)
+        };
+        let replayed = temp_path("replayed.ppm");
+        // Without an output of its own, or with the archived one, it refuses
+        for extra in [
+            String::new(),
+            format!("--output {}", output),
+            format!("--output {}", manifest),
+            format!("--output {} --manifest {}", replayed, manifest),
+            format!("--output {} --manifest {}", replayed, output),
+        ] {
+            let error = replay(&extra).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{}", extra);
+        }
+        // The recorded outputs are dropped, the rest replays as recorded
+        let (args, _) = replay(&format!("--output {}", replayed)).unwrap();
+        assert!(!args.contains(&output) && !args.contains(&"--manifest".to_string()));
+        let config = parse_args(args.into_iter()).unwrap();
+        assert_eq!(
+            (config.filename.as_str(), config.seed),
+            (replayed.as_str(), 3)
+        );
+        assert_eq!(config.manifest, None);
+        render(&scene, &config, &cancel, None).unwrap();
+        assert_eq!(std::fs::read(&replayed).unwrap(), archived);
+        assert_eq!(std::fs::read(&output).unwrap(), archived);
+        for path in [&output, &manifest, &replayed] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn single_sided_backs_are_unlit_and_two_sided_ones_lit() {
+        // A triangle facing away from the camera, lit from the camera's side,
+        // with no ambient so only the light can brighten it
+        let config = args("--ambient-sky 0,0,0 --ambient-ground 0,0,0").unwrap();
+        let z = |x: f32, y: f32| Vector3::new(x, y, -5.0);
+        let shade = |material: Material| {
+            let mut scene = Scene::new(
+                Vec::new(),
+                vec![Light::new(Vector3::new(0.0, 0.0, 5.0), 1.0)],
+            );
+            let triangle = Triangle::new([z(-1.0, -1.0), z(0.0, 1.0), z(1.0, -1.0)], material);
+            assert_eq!(triangle.normal, -Vector3::z());
+            scene.add_mesh(vec![triangle]);
+            let range = config.primary_range();
+            cast_ray(Vector3::zeros(), -Vector3::z(), &scene, range, &config, 0.5).unwrap()
+        };
+        let white = Material::new(Vector3::repeat(1.0));
+        assert!(white.two_sided());
+        assert!(shade(white).min() > 0.9);
+        assert_eq!(shade(white.single_sided()), Vector3::zeros());
+        // A blend shows its back when either surface does
+        let single = Surface::new(Vector3::repeat(1.0)).single_sided();
+        let double = Surface::new(Vector3::repeat(1.0));
+        assert!(Material::blend(single, double, 0.5).two_sided());
+        assert!(!Material::blend(single, single, 0.5).two_sided());
+    }
+
+    #[test]
+    fn flat_normal_map_leaves_the_normal_unchanged() {
+        let flat = Image {
+            width: 1,
+            height: 1,
+            pixels: vec![Vector3::new(0.5, 0.5, 1.0)],
+        };
+        // Leaning halfway towards +x in tangent space
+        let leaning = Image {
+            width: 1,
+            height: 1,
+            pixels: vec![Vector3::new(1.0, 0.5, 1.0)],
+        };
+        let mut scene = Scene::new(Vec::new(), Vec::new());
+        let (flat, leaning) = (scene.add_texture(flat), scene.add_texture(leaning));
+        let plain = Surface::new(Vector3::repeat(0.8));
+        let sphere = Sphere::new(Vector3::new(0.0, 0.0, -4.0), 1.0, Material::Surface(plain));
+        let triangle = Triangle::new(
+            [
+                Vector3::new(-1.0, -1.0, -3.0),
+                Vector3::new(1.0, -1.0, -3.0),
+                Vector3::new(0.0, 1.0, -3.0),
+            ],
+            Material::Surface(plain),
+        )
+        .with_vertex_normals([
+            Vector3::new(-0.3, 0.0, 1.0),
+            Vector3::new(0.3, 0.0, 1.0),
+            Vector3::new(0.0, 0.3, 1.0),
+        ]);
+        let range = Interval::new(0.0, 100.0);
+        for (x, y) in [(0.0, 0.0), (0.3, -0.2), (-0.45, 0.6), (0.1, 0.7)] {
+            let direction = Vector3::new(x, y, -4.0).normalize();
+            let hits = [
+                sphere.ray_intersect(Vector3::zeros(), direction, range),
+                triangle.ray_intersect(Vector3::zeros(), direction, range),
+            ];
+            for hit in hits.into_iter().flatten() {
+                let mapped = plain.with_normal_map(flat).normal_at(&hit, &scene);
+                assert!(
+                    (mapped - hit.normal).norm() < 1e-5,
+                    "{mapped:?} vs {:?}",
+                    hit.normal
+                );
+                assert_eq!(plain.normal_at(&hit, &scene), hit.normal);
+                // A leaning map tilts the normal towards the tangent
+                let tilted = plain.with_normal_map(leaning).normal_at(&hit, &scene);
+                assert!((tilted.norm() - 1.0).abs() < 1e-5);
+                assert!(tilted.dot(&hit.tangent) > hit.normal.dot(&hit.tangent) + 0.3);
+            }
+        }
+    }
+
+    #[test]
+    fn obj_texture_coordinates_reach_the_hit() {
+        let obj =
+            "v 0 0 -2\nv 1 0 -2\nv 0 1 -2\nvt 0.2 0.2\nvt 0.8 0.2\nvt 0.2 0.8\nf 1/1 2/2 3/3\n";
+        let triangles = parse_obj(obj, Material::new(Vector3::repeat(1.0))).unwrap();
+        let direction = Vector3::new(0.25, 0.25, -2.0);
+        let hit = triangles[0]
+            .ray_intersect(Vector3::zeros(), direction, Interval::new(0.0, 10.0))
+            .unwrap();
+        // A quarter of the way along both edges
+        assert!((hit.uv - Vector2::new(0.35, 0.35)).norm() < 1e-5);
+        assert!((hit.tangent - Vector3::x()).norm() < 1e-5);
+        // A two-texel texture splits the triangle down u = 0.5
+        let mut scene = Scene::new(Vec::new(), Vec::new());
+        let texture = scene.add_texture(Image {
+            width: 2,
+            height: 1,
+            pixels: vec![Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0)],
+        });
+        let textured = Surface::new(Vector3::repeat(0.5)).with_texture(texture);
+        assert_eq!(
+            textured.albedo_at(&hit, &scene),
+            Vector3::new(0.5, 0.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn bilinear_sampling_between_texels_averages_them() {
+        // Red and blue columns, so halfway across the texture is between them
+        let (red, blue) = (Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0));
+        let image = Image {
+            width: 2,
+            height: 2,
+            pixels: vec![red, blue, red, blue],
+        };
+        let between = Vector2::new(0.5, 0.75);
+        let average = Vector3::new(0.5, 0.0, 0.5);
+        assert!((image.sample(between, TextureFilter::Bilinear) - average).norm() < 1e-6);
+        assert_eq!(image.sample(between, TextureFilter::Nearest), blue);
+        // Texel centers come back exactly either way
+        for filter in [TextureFilter::Nearest, TextureFilter::Bilinear] {
+            assert_eq!(image.sample(Vector2::new(0.25, 0.25), filter), red);
+            assert_eq!(image.sample(Vector2::new(0.75, 0.75), filter), blue);
+        }
+        // The filter is the material's choice
+        let mut scene = Scene::new(Vec::new(), Vec::new());
+        let texture = scene.add_texture(image);
+        let white = Surface::new(Vector3::repeat(1.0)).with_texture(texture);
+        let sphere = Sphere::new(Vector3::new(0.0, 0.0, -4.0), 1.0, Material::Surface(white));
+        let hit = sphere
+            .ray_intersect(Vector3::zeros(), -Vector3::z(), Interval::new(0.0, 10.0))
+            .unwrap();
+        let hit = Intersection { uv: between, ..hit };
+        assert_eq!(white.albedo_at(&hit, &scene), blue);
+        let smooth = white.filtered(TextureFilter::Bilinear);
+        assert!((smooth.albedo_at(&hit, &scene) - average).norm() < 1e-6);
+    }
+
+    #[test]
+    fn metal_reflections_are_tinted_and_dielectric_ones_neutral() {
+        let gold = Vector3::new(1.0, 0.78, 0.34);
+        let metal = Surface::new(gold).metal();
+        let glass = Surface::new(gold).dielectric(1.5);
+        let ball = |surface| {
+            Sphere::new(
+                Vector3::new(0.0, 0.0, -4.0),
+                1.0,
+                Material::Surface(surface),
+            )
+        };
+        for x in [0.0, 0.1, 0.2] {
+            let direction = Vector3::new(x, 0.0, -1.0).normalize();
+            let range = Interval::new(0.0, 10.0);
+            let hit = ball(metal)
+                .ray_intersect(Vector3::zeros(), direction, range)
+                .unwrap();
+            let metal_bounces = specular_bounces(&metal, gold, &hit, direction, Path::CAMERA);
+            let glass_bounces = specular_bounces(&glass, gold, &hit, direction, Path::CAMERA);
+            let [reflection] = metal_bounces[..] else {
+                panic!("metal sends one ray, got {:?}", metal_bounces);
+            };
+            assert_eq!(reflection.kind, BounceKind::Reflection);
+            assert!(reflection.weight.x > reflection.weight.y);
+            assert!(reflection.weight.y > reflection.weight.z);
+            let [reflection, refraction] = glass_bounces[..] else {
+                panic!("glass sends two rays, got {:?}", glass_bounces);
+            };
+            assert_eq!(reflection.kind, BounceKind::Reflection);
+            assert_eq!(reflection.weight, Vector3::repeat(reflection.weight.x));
+            assert!(reflection.weight.x > 0.0 && reflection.weight.x < 0.1);
+            assert_eq!(refraction.kind, BounceKind::Refraction);
+            assert!(refraction.weight.x > refraction.weight.z);
+            // Both mirror the ray the same way
+            assert!((reflection.direction - metal_bounces[0].direction).norm() < 1e-6);
+        }
+        // Head on against a white sky, the metal shows exactly its own color
+        let config = args("--sky-horizon 1,1,1 --sky-zenith 1,1,1").unwrap();
+        let scene = Scene::new(vec![ball(metal)], Vec::new());
+        let color = cast_ray(
+            Vector3::zeros(),
+            -Vector3::z(),
+            &scene,
+            config.primary_range(),
+            &config,
+            0.5,
+        )
+        .unwrap();
+        assert!((color - gold).norm() < 1e-5, "{:?}", color);
+        // Only reflections are traced, and none at all without depth to spare
+        let config = args("--width 8 --height 8 --fov 10").unwrap();
+        let cancel = AtomicBool::new(false);
+        let (_, stats) = render_to_buffer(&scene, &config, &cancel).unwrap();
+        assert_eq!(stats.reflection_rays, 64);
+        assert_eq!(stats.refraction_rays, 0);
+        let flat = RenderConfig {
+            max_depth: 0,
+            ..config
+        };
+        let (pixels, stats) = render_to_buffer(&scene, &flat, &cancel).unwrap();
+        assert_eq!(stats.reflection_rays, 0);
+        assert!(pixels.iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn dispersion_refracts_each_channel_its_own_way() {
+        let clear = Surface::new(Vector3::repeat(1.0)).dielectric(1.5);
+        let prism = clear.with_dispersion(0.1);
+        let ball = Sphere::new(Vector3::new(0.0, 0.0, -4.0), 1.0, Material::Surface(prism));
+        let direction = Vector3::new(0.2, 0.0, -1.0).normalize();
+        let hit = ball
+            .ray_intersect(Vector3::zeros(), direction, Interval::new(0.0, 10.0))
+            .unwrap();
+        let white = Vector3::repeat(1.0);
+        let refractions = |surface: &Surface, path| {
+            specular_bounces(surface, white, &hit, direction, path)
+                .into_iter()
+                .filter(|bounce| bounce.kind == BounceKind::Refraction)
+                .collect::<Vec<_>>()
+        };
+        // One ray per channel, each carrying only its own channel
+        let split = refractions(&prism, Path::CAMERA);
+        assert_eq!(split.len(), 3);
+        for (c, bounce) in split.iter().enumerate() {
+            assert_eq!(bounce.channel, Some(c));
+            assert_eq!(bounce.weight[(c + 1) % 3], 0.0);
+            assert!(bounce.weight[c] > 0.9);
+        }
+        // Blue bends the most towards the inward normal, red the least,
+        // measurably apart for this spread
+        let bend = |bounce: &Bounce| bounce.direction.dot(&-hit.normal).acos();
+        let (red, green, blue) = (bend(&split[0]), bend(&split[1]), bend(&split[2]));
+        assert!(red > green && green > blue);
+        assert!(red - blue > 0.003, "{} {}", red, blue);
+        // Without a spread the ray stays whole, and a split one stays split
+        let whole = refractions(&clear, Path::CAMERA);
+        assert_eq!(whole.len(), 1);
+        assert_eq!(whole[0].channel, None);
+        assert!((whole[0].direction - split[1].direction).norm() < 1e-6);
+        let red_path = Path {
+            channel: Some(0),
+            ..Path::CAMERA
+        };
+        let carried = refractions(&prism, red_path);
+        assert_eq!(carried.len(), 1);
+        assert_eq!(carried[0].channel, Some(0));
+        assert_eq!(carried[0].direction, split[0].direction);
+    }
+
+    #[test]
+    fn more_glossy_samples_quiet_a_rough_reflection() {
+        // A rough mirror head on, reflecting a sky that runs from black at
+        // the horizon to white overhead
+        let config = args("--sky-horizon 0,0,0 --sky-zenith 1,1,1").unwrap();
+        let mirror = Surface::new(Vector3::repeat(1.0)).metal();
+        let scene = |surface| {
+            Scene::new(
+                vec![Sphere::new(
+                    Vector3::new(0.0, 0.0, -4.0),
+                    1.0,
+                    Material::Surface(surface),
+                )],
+                Vec::new(),
+            )
+        };
+        let direction = Vector3::new(0.0, 0.25, -1.0).normalize();
+        let shade = |scene: &Scene, config: &RenderConfig, key: u64| {
+            let range = config.primary_range();
+            let path = Path::CAMERA.branch(splitmix(config.seed, key));
+            trace_ray(Vector3::zeros(), direction, scene, range, config, path).unwrap()
+        };
+        // One glossy sample is the sharp reflection, whatever the roughness
+        let sharp = shade(&scene(mirror), &config, 0);
+        let rough = scene(mirror.rough(0.4));
+        for key in 0..8 {
+            assert_eq!(shade(&rough, &config, key), sharp);
+        }
+        // The spread of the estimates over paths shrinks with more samples
+        let variance = |glossy_samples| {
+            let config = RenderConfig {
+                glossy_samples,
+                ..config.clone()
+            };
+            let estimates: Vec<f32> = (0..64).map(|key| shade(&rough, &config, key).x).collect();
+            let mean = estimates.iter().sum::<f32>() / estimates.len() as f32;
+            estimates.iter().map(|e| (e - mean).powi(2)).sum::<f32>() / estimates.len() as f32
+        };
+        let (few, many) = (variance(2), variance(32));
+        assert!(few > 0.0);
+        assert!(many < few / 4.0, "{} vs {}", many, few);
+    }
+
+    #[test]
+    fn rays_through_overlapping_glass_track_the_medium_they_are_in() {
+        // Entering two spheres in turn and leaving the outer one first
+        let (a, b) = (Vector3::repeat(1.5), Vector3::repeat(1.3));
+        let both = Media::NONE.entered(1, a).entered(2, b);
+        assert_eq!(both.ior(), b);
+        assert_eq!(both.left(1).ior(), b);
+        assert_eq!(both.left(2).ior(), a);
+        assert_eq!(both.left(1).left(2), Media::NONE);
+        assert_eq!(Media::NONE.ior(), Vector3::repeat(1.0));
+
+        // Two overlapping balls of the same glass on the view axis
+        let glass = Surface::new(Vector3::repeat(1.0)).dielectric(1.5);
+        let ball = |z: f32| Sphere::new(Vector3::new(0.0, 0.0, z), 1.0, Material::Surface(glass));
+        let direction = Vector3::new(0.1, 0.05, -1.0).normalize();
+        let range = Interval::new(0.0, 10.0);
+        let mut hit = ball(-5.0)
+            .ray_intersect(Vector3::zeros(), direction, range)
+            .unwrap();
+        hit.object = 1;
+        // Inside the first ball and meeting the second, glass on both sides
+        // of the boundary: no bend and no reflection
+        let inside = Path {
+            media: Media::NONE.entered(2, glass.ior),
+            ..Path::CAMERA
+        };
+        let white = Vector3::repeat(1.0);
+        let bounces = specular_bounces(&glass, white, &hit, direction, inside);
+        assert_eq!(bounces[0].weight, Vector3::zeros());
+        assert!((bounces[1].direction - direction).norm() < 1e-5);
+        assert_eq!(bounces[1].media.len, 2);
+        // From air the same boundary bends the ray
+        let bounces = specular_bounces(&glass, white, &hit, direction, Path::CAMERA);
+        assert!((bounces[1].direction - direction).norm() > 0.01);
+        assert_eq!(bounces[1].media.len, 1);
+
+        // A ray through both comes out the far side lit by the white sky
+        let config = args("--sky-horizon 1,1,1 --sky-zenith 1,1,1 --max-depth 8").unwrap();
+        let scene = Scene::new(vec![ball(-5.0), ball(-6.2)], Vec::new());
+        let color = cast_ray(
+            Vector3::zeros(),
+            direction,
+            &scene,
+            config.primary_range(),
+            &config,
+            0.5,
+        )
+        .unwrap();
+        assert!(
+            color.iter().all(|c| c.is_finite() && *c > 0.5),
+            "{:?}",
+            color
+        );
+    }
+
+    #[test]
+    fn mirrors_show_the_reflection_background_and_misses_the_camera_one() {
+        let config = args(
+            "--width 16 --height 16 --fov 40 --background 1,0,0 --reflection-background 0,0,1",
+        )
+        .unwrap();
+        let mirror = Material::Surface(Surface::new(Vector3::repeat(1.0)).metal());
+        let mut scene = Scene::new(
+            vec![Sphere::new(Vector3::new(0.0, 0.0, -4.0), 1.0, mirror)],
+            Vec::new(),
+        );
+        scene.background = config.background;
+        scene.reflection_background = config.reflection_background;
+        let cancel = AtomicBool::new(false);
+        let (pixels, _) = render_to_buffer(&scene, &config, &cancel).unwrap();
+        let pixel = |i: usize, j: usize| &pixels[(j * 16 + i) * 3..][..3];
+        assert_eq!(pixel(8, 8), [0, 0, 255]);
+        assert_eq!(pixel(0, 0), [255, 0, 0]);
+        assert_eq!(pixel(15, 8), [255, 0, 0]);
+        // Without a reflection background the mirror sees the camera's
+        scene.reflection_background = None;
+        let (pixels, _) = render_to_buffer(&scene, &config, &cancel).unwrap();
+        assert_eq!(&pixels[(8 * 16 + 8) * 3..][..3], [255, 0, 0]);
+    }
+
+    #[test]
+    fn dim_mirrors_stop_recursing_sooner_than_bright_ones() {
+        // From the center of a mirrored ball every reflection comes back
+        // head on, so each bounce weighs exactly the albedo
+        let levels = |albedo: f32, min_throughput: f32| {
+            let config = RenderConfig {
+                max_depth: 50,
+                min_throughput,
+                ..args("").unwrap()
+            };
+            let mirror = Material::Surface(Surface::new(Vector3::repeat(albedo)).metal());
+            let scene = Scene::new(vec![Sphere::new(Vector3::zeros(), 2.0, mirror)], Vec::new());
+            let before = TRACE_COUNTS.get().reflection_rays;
+            let range = config.primary_range();
+            cast_ray(Vector3::zeros(), -Vector3::z(), &scene, range, &config, 0.5).unwrap();
+            TRACE_COUNTS.get().reflection_rays - before
+        };
+        // 0.9^21 is still above 0.1, 0.2^2 is not
+        assert_eq!(levels(0.9, 0.1), 21);
+        assert_eq!(levels(0.2, 0.1), 1);
+        // Without a threshold only max_depth stops them
+        assert_eq!(levels(0.9, 0.0), 50);
+        assert_eq!(levels(0.2, 0.0), 50);
+    }
+
+    #[test]
+    fn sharper_normalized_highlights_reflect_no_more_light() {
+        // The light a highlight reflects towards a viewer 30 degrees off the
+        // normal, summed over every direction the light could come from
+        let normal = Vector3::z();
+        let to_viewer = Vector3::new(0.5, 0.0, 0.75_f32.sqrt());
+        let steps = 600;
+        let reflected = |exponent: f32, classic: bool| {
+            let (d_theta, d_phi) = (PI / 2.0 / steps as f32, 2.0 * PI / steps as f32);
+            let mut sum = 0.0;
+            for t in 0..steps {
+                let theta = (t as f32 + 0.5) * d_theta;
+                for p in 0..steps {
+                    let (sin_phi, cos_phi) = ((p as f32 + 0.5) * d_phi).sin_cos();
+                    let to_light =
+                        Vector3::new(theta.sin() * cos_phi, theta.sin() * sin_phi, theta.cos());
+                    let lobe = phong(normal, to_light, to_viewer, exponent, classic);
+                    sum += lobe * theta.sin() * d_theta * d_phi;
+                }
+            }
+            sum
+        };
+        let exponents = [1.0, 10.0, 100.0, 400.0];
+        let normalized = exponents.map(|e| reflected(e, false));
+        let classic = exponents.map(|e| reflected(e, true));
+        for energy in normalized {
+            assert!(energy > 0.5 && energy <= 1.01, "{:?}", normalized);
+        }
+        // The normalized peak keeps rising, so bounded totals aren't just
+        // dimmer highlights. The classic ones fade as they sharpen
+        let peak = |e| {
+            phong(
+                normal,
+                Vector3::new(-0.5, 0.0, 0.75_f32.sqrt()),
+                to_viewer,
+                e,
+                false,
+            )
+        };
+        assert!(peak(400.0) > 10.0 * peak(10.0));
+        assert!(
+            classic.windows(2).all(|pair| pair[1] < pair[0]),
+            "{:?}",
+            classic
+        );
+        // Classic highlights peak at 1 per unit of light, with many lights
+        // adding up past what any surface reflects
+        let head_on = phong(normal, normal, normal, 10.0, true);
+        assert_eq!(head_on, 1.0);
+    }
+
+    #[test]
+    fn larger_lights_spread_their_highlights() {
+        let light = Light::new(Vector3::new(0.0, 0.0, 10.0), 1.0);
+        let surface = Surface::new(Vector3::repeat(1.0)).shiny(1.0, 200.0);
+        let normal = Vector3::z();
+        let hit = Intersection::new(
+            Vector3::zeros(),
+            1.0,
+            -normal,
+            normal,
+            normal,
+            Material::Surface(surface),
+        );
+        // The highlight seen head on and from 10 degrees off the reflection
+        let highlight = |light_radius: f32| {
+            let config = RenderConfig {
+                light_radius,
+                ..RenderConfig::default()
+            };
+            let off = 10.0_f32.to_radians();
+            let aside = Vector3::new(off.sin(), 0.0, off.cos());
+            (
+                light.specular_for_intersection(&hit, normal, &surface, &config),
+                light.specular_for_intersection(&hit, aside, &surface, &config),
+            )
+        };
+        let (point_peak, point_aside) = highlight(0.0);
+        let (disc_peak, disc_aside) = highlight(2.0);
+        assert!(disc_aside / disc_peak > 2.0 * point_aside / point_peak);
+        // Wider, the peak is dimmer rather than the highlight brighter
+        assert!(disc_peak < point_peak);
+        assert!(disc_aside > point_aside);
+    }
+
+    #[test]
+    fn brushed_highlights_stretch_along_the_tangent() {
+        let light = Light::new(Vector3::new(0.0, 0.0, 10.0), 1.0);
+        let surface = Surface::new(Vector3::repeat(1.0))
+            .shiny(1.0, 50.0)
+            .brushed(0.3, 0.05);
+        let normal = Vector3::z();
+        let mut hit = Intersection::new(
+            Vector3::zeros(),
+            1.0,
+            -normal,
+            normal,
+            normal,
+            Material::Surface(surface),
+        );
+        let config = RenderConfig::default();
+        // The highlight seen from 10 degrees off the reflection, leaning along
+        // x and along y
+        let off = 10.0_f32.to_radians();
+        let views = [
+            Vector3::new(off.sin(), 0.0, off.cos()),
+            Vector3::new(0.0, off.sin(), off.cos()),
+        ];
+        let highlight = |hit: &Intersection| {
+            views.map(|view| light.specular_for_intersection(hit, view, &surface, &config))
+        };
+        hit.tangent = Vector3::x();
+        let [along_x, along_y] = highlight(&hit);
+        assert!(along_x > 10.0 * along_y, "{} {}", along_x, along_y);
+        // Turning the tangent turns the highlight with it
+        hit.tangent = Vector3::y();
+        let [turned_x, turned_y] = highlight(&hit);
+        assert!(turned_y > 10.0 * turned_x, "{} {}", turned_x, turned_y);
+        assert!((turned_y - along_x).abs() < 1e-4 * along_x);
+        // Without anisotropy the Phong highlight is round
+        let round = Surface {
+            anisotropy: None,
+            ..surface
+        };
+        let [round_x, round_y] =
+            views.map(|view| light.specular_for_intersection(&hit, view, &round, &config));
+        assert!((round_x - round_y).abs() < 1e-4 * round_x);
+    }
+
+    #[test]
+    fn environment_samples_head_for_the_bright_spot() {
+        // A dark map with a bright texel block straight up-ish along +X
+        let (width, height) = (64, 32);
+        let bright = |x: u32, y: u32| (31..33).contains(&x) && (10..12).contains(&y);
+        let image = Image {
+            width,
+            height,
+            pixels: (0..width * height)
+                .map(|k| match bright(k % width, k / width) {
+                    true => Vector3::repeat(500.0),
+                    false => Vector3::repeat(0.01),
+                })
+                .collect(),
+        };
+        let spot = {
+            let x = (0..width * height)
+                .find(|&k| bright(k % width, k / width))
+                .unwrap();
+            let (theta, phi) = (
+                PI * (x / width + 1) as f32 / height as f32,
+                2.0 * PI * (0.5 - (x % width + 1) as f32 / width as f32),
+            );
+            Vector3::new(
+                theta.sin() * phi.cos(),
+                theta.cos(),
+                theta.sin() * phi.sin(),
+            )
+        };
+        let environment = Environment::new(image);
+        let path = Path::CAMERA.branch(3);
+        let samples: Vec<_> = (0..2000)
+            .map(|k| {
+                let path = path.branch(k);
+                environment.sample(path.random(0), path.random(1))
+            })
+            .collect();
+        let near = samples
+            .iter()
+            .filter(|(direction, _)| direction.dot(&spot) > 10.0_f32.to_radians().cos())
+            .count();
+        // The spot is well under 1% of the sphere but takes most samples
+        assert!(near > 1800, "{} of {} near the spot", near, samples.len());
+        for (direction, pdf) in &samples {
+            assert!((direction.norm() - 1.0).abs() < 1e-4);
+            assert!((environment.pdf(*direction) - pdf).abs() <= 1e-3 * pdf);
+            assert!(environment.radiance(*direction).x > 0.0);
+        }
+        // The density covers the whole sphere once, so 1 / pdf averages to
+        // its area under uniform sampling
+        let even = Environment::new(Image {
+            width,
+            height,
+            pixels: vec![Vector3::repeat(1.0); (width * height) as usize],
+        });
+        let area = (0..4000)
+            .map(|k| {
+                let path = path.branch(k);
+                1.0 / even.sample(path.random(0), path.random(1)).1
+            })
+            .sum::<f32>()
+            / 4000.0;
+        assert!((area - 4.0 * PI).abs() < 0.05 * 4.0 * PI, "{}", area);
+    }
+
+    #[test]
+    fn environment_backgrounds_need_a_map() {
+        let error = args("--background environment").err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(args("--reflection-background environment --environment sky.pfm").is_ok());
+    }
+
+    #[test]
+    fn environment_sampling_cuts_indirect_noise_without_bias() {
+        let (width, height) = (64, 32);
+        let image = Image {
+            width,
+            height,
+            pixels: (0..width * height)
+                .map(|k| match (k % width, k / width) {
+                    (31..33, 10..12) => Vector3::repeat(500.0),
+                    _ => Vector3::repeat(0.05),
+                })
+                .collect(),
+        };
+        // What a white floor facing up gets from the map, texel by texel
+        let expected: f32 = (0..width * height)
+            .map(|k| {
+                let theta = PI * ((k / width) as f32 + 0.5) / height as f32;
+                let solid_angle = 2.0 * PI * PI * theta.sin() / (width * height) as f32;
+                image.pixels[k as usize].x * theta.cos().max(0.0) / PI * solid_angle
+            })
+            .sum();
+        let mut scene = Scene::new(Vec::new(), Vec::new());
+        scene.background = Background::Environment;
+        scene.environment = Some(Environment::new(image));
+        let config = RenderConfig {
+            indirect_samples: 1,
+            ..RenderConfig::default()
+        };
+        let normal = Vector3::y();
+        let hit = Intersection::new(
+            Vector3::zeros(),
+            1.0,
+            -normal,
+            normal,
+            normal,
+            Material::new(Vector3::repeat(1.0)),
+        );
+        let environment = scene.environment.as_ref().unwrap();
+        let runs = 4000;
+        let (sampled, cosine): (Vec<f32>, Vec<f32>) = (0..runs)
+            .map(|k| {
+                let path = Path::CAMERA.branch(k);
+                let weighted = indirect_light(&hit, Vector3::repeat(1.0), &scene, &config, path);
+                // The same cosine direction alone, as without the map's help
+                let path = Path {
+                    diffuse: true,
+                    ..path.branch(0)
+                };
+                let (r_sq, angle) = (path.random(0), 2.0 * PI * path.random(1));
+                let tangent = perpendicular(normal);
+                let direction = tangent * (r_sq.sqrt() * angle.cos())
+                    + normal.cross(&tangent) * (r_sq.sqrt() * angle.sin())
+                    + normal * (1.0 - r_sq).sqrt();
+                (weighted.x, environment.radiance(direction).x)
+            })
+            .unzip();
+        let stats = |values: &[f32]| {
+            let mean = values.iter().sum::<f32>() / values.len() as f32;
+            let variance =
+                values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / values.len() as f32;
+            (mean, variance)
+        };
+        let (mean, variance) = stats(&sampled);
+        let (_, cosine_variance) = stats(&cosine);
+        assert!(
+            (mean - expected).abs() < 0.05 * expected,
+            "{} vs {}",
+            mean,
+            expected
+        );
+        assert!(
+            variance * 20.0 < cosine_variance,
+            "{} vs {}",
+            variance,
+            cosine_variance
+        );
+    }
+
+    #[test]
+    fn one_camera_sample_is_a_still_pinhole_at_the_pixel_center() {
+        let scene = Scene::spheres_demo();
+        let cancel = AtomicBool::new(false);
+        let render = |extra: &str| {
+            let config = args(&format!("--width 24 --height 18 --seed 5 {}", extra)).unwrap();
+            render_to_buffer(&scene, &config, &cancel).unwrap().0
+        };
+        let effects = "--aperture 0.6 --focus-distance 12 --camera-motion 1.5,0,0";
+        assert_eq!(render(effects), render(""));
+        // Pixels the sample map leaves one sample match too
+        let config = args(&format!("--samples 8 {}", effects)).unwrap();
+        assert_eq!(
+            camera_sample(&config, 3, 4, 0, 1),
+            CameraSample::at(3.5, 4.5)
+        );
+        let camera = CameraSample::at(3.5, 4.5);
+        assert_eq!(lens_ray(&camera, &config), camera_ray(3.5, 4.5, &config),);
+    }
+
+    #[test]
+    fn more_camera_samples_settle_lens_and_motion_blur() {
+        // Depth of field and motion blur at once, each sample drawing pixel,
+        // lens and time from one budget
+        let scene = Scene::spheres_demo();
+        let cancel = AtomicBool::new(false);
+        let render = |samples: u32| {
+            let config = args(&format!(
+                "--width 24 --height 18 --seed 5 --samples {} \
+                 --aperture 0.8 --focus-distance 14 --camera-motion 2,0.5,0",
+                samples
+            ))
+            .unwrap();
+            render_to_buffer(&scene, &config, &cancel).unwrap().0
+        };
+        let reference = render(512);
+        let error = |samples: u32| {
+            let image = render(samples);
+            image
+                .iter()
+                .zip(&reference)
+                .map(|(&a, &b)| (a as f32 - b as f32).powi(2))
+                .sum::<f32>()
+                / image.len() as f32
+        };
+        let (few, many) = (error(4), error(64));
+        assert!(few > 0.0);
+        assert!(many * 4.0 < few, "{} vs {}", many, few);
+        // The samples cover the whole lens and shutter
+        let config = args("--samples 64 --aperture 1").unwrap();
+        let samples: Vec<_> = (0..64)
+            .map(|k| camera_sample(&config, 2, 3, k, 64))
+            .collect();
+        for sample in &samples {
+            assert!(sample.lens.norm() <= 1.0);
+            assert!((2.0..3.0).contains(&sample.pixel.x) && (3.0..4.0).contains(&sample.pixel.y));
+        }
+        for half in [0.0, 0.5] {
+            let in_half = |t: f32| (half..half + 0.5).contains(&t);
+            let count = samples.iter().filter(|sample| in_half(sample.time)).count();
+            assert!(
+                (28..=36).contains(&count),
+                "{} in [{}, {})",
+                count,
+                half,
+                half + 0.5
+            );
+        }
+        let left = samples.iter().filter(|sample| sample.lens.x < 0.0).count();
+        assert!(
+            (28..=36).contains(&left),
+            "{} left of the lens center",
+            left
+        );
+    }
+
+    #[test]
+    fn scene_files_round_trip_scenes_and_cameras() {
+        let camera = "--fov 50 --roll 10 --world-up z --aperture 0.25 --focus-distance 9 \
+                      --camera-motion 0.5,0,0.25";
+        let config = args(&format!("--width 20 --height 16 {}", camera)).unwrap();
+        let mut materials = Scene::materials_demo();
+        // A blended ball, and every light source a scene file can hold
+        materials.spheres.push(Sphere::new(
+            Vector3::new(0.0, 3.0, -16.0),
+            1.0,
+            Material::blend(
+                Surface::new(Vector3::new(0.2, 0.4, 0.9)).dielectric(1.33),
+                Surface::new(Vector3::repeat(0.7)).brushed(0.2, 0.1),
+                0.3,
+            ),
+        ));
+        let sky = Image {
+            width: 4,
+            height: 2,
+            pixels: (0..8).map(|k| Vector3::repeat(k as f32 / 3.0)).collect(),
+        };
+        materials.environment = Some(Environment::new(sky));
+        materials.reflection_background = Some(Background::Environment);
+        materials.background = Background::Color(Vector3::new(0.1, 0.2, 0.3));
+        for (name, scene) in [
+            ("materials", materials),
+            ("instances", Scene::instances_demo()),
+            ("carved", Scene::carved_demo()),
+            ("cornell", Scene::cornell_box()),
+        ] {
+            let path = temp_path(&format!("scene-{}.json", name));
+            scene.to_json_file(&path, &config).unwrap();
+            let mut loaded_config = args("--width 20 --height 16").unwrap();
+            let loaded = Scene::from_json_file(&path, &mut loaded_config).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(loaded.spheres.len(), scene.spheres.len(), "{}", name);
+            let materials = |scene: &Scene| {
+                let spheres = scene.spheres.iter().map(|sphere| sphere.material);
+                let triangles = scene.triangles.iter().map(|triangle| triangle.material);
+                format!("{:?}", spheres.chain(triangles).collect::<Vec<_>>())
+            };
+            assert_eq!(materials(&loaded), materials(&scene), "{}", name);
+            assert_eq!(
+                loaded.primitive_count(),
+                scene.primitive_count(),
+                "{}",
+                name
+            );
+            assert_eq!(loaded.mesh_count(), scene.mesh_count(), "{}", name);
+            let cameras = [&config, &loaded_config].map(|config| {
+                (
+                    config.fov,
+                    config.roll,
+                    config.world_up,
+                    config.aperture,
+                    config.focus_distance,
+                    config.camera_motion,
+                )
+            });
+            assert_eq!(cameras[0], cameras[1], "{}", name);
+            // Nothing that changes the pixels is lost either
+            assert_eq!(
+                render_hash(&loaded, &loaded_config),
+                render_hash(&scene, &config),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn broken_scene_files_are_rejected() {
+        let path = temp_path("broken-scene.json");
+        let config = RenderConfig::default();
+        Scene::spheres_demo().to_json_file(&path, &config).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        for broken in [
+            text.replace("\"radius\"", "\"size\""),
+            text.replace("\"Lambert\"", "\"Waxy\""),
+            text.replace("\"texture\": null", "\"texture\": 3"),
+            text.replacen('[', "", 1),
+        ] {
+            std::fs::write(&path, broken).unwrap();
+            let error = Scene::from_json_file(&path, &mut RenderConfig::default()).err();
+            assert_eq!(error.map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "viewer")]
+    #[test]
+    fn viewer_frames_are_whole_images_every_call() {
+        let scene = Scene::spheres_demo();
+        let config = args("--width 24 --height 18 --samples 8").unwrap();
+        let cancel = AtomicBool::new(false);
+        let mut stats = RenderStats::default();
+        let mut viewer = Viewer::new(&scene, config.clone());
+        let frames: Vec<_> = (0..6)
+            .map(|_| viewer.frame(&cancel, &mut stats).unwrap())
+            .collect();
+        for frame in &frames {
+            assert_eq!(frame.len(), 24 * 18 * 3);
+        }
+        // Batches of 1, 2, 4 and the last 1 reach the 8 samples, after which
+        // frames repeat the finished image, the one a plain render makes
+        assert!(viewer.converged());
+        assert_eq!(frames[4], frames[5]);
+        assert_eq!(
+            frames[5],
+            render_to_buffer(&scene, &config, &cancel).unwrap().0
+        );
+        // Dragging starts over at the new view, and previews still come out
+        // at the full size
+        viewer.drag(15.0, 0.0);
+        assert!(!viewer.converged());
+        assert_ne!(viewer.frame(&cancel, &mut stats).unwrap(), frames[0]);
+        let preview = args("--width 24 --height 18 --samples 8 --preview 2").unwrap();
+        let mut viewer = Viewer::new(&scene, preview);
+        assert_eq!(
+            viewer.frame(&cancel, &mut stats).unwrap().len(),
+            24 * 18 * 3
+        );
+        // The stream closes once the image has settled, after one frame per batch
+        let mut window = PpmStreamWindow {
+            out: Vec::new(),
+            open: true,
+        };
+        run_viewer(&scene, &config, &cancel, &mut window).unwrap();
+        let header = b"P6 24 18 255\n";
+        let frame_size = header.len() + 24 * 18 * 3;
+        assert_eq!(window.out.len() % frame_size, 0);
+        assert_eq!(window.out.len() / frame_size, 4);
+    }
+
+    #[cfg(not(feature = "viewer"))]
+    #[test]
+    fn the_viewer_needs_its_feature() {
+        let error = args("--viewer").err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn spectral_colors_round_trip_to_their_rgb() {
+        // Upsampled at three wavelengths at a time and projected back, on
+        // average over the hero wavelength
+        let path = Path::CAMERA.branch(9);
+        let (first, last) = VISIBLE_WAVELENGTHS;
+        let width = (last - first) / 3.0;
+        let round_trip = |rgb: Vector3<f32>| {
+            let n = 3000;
+            (0..n)
+                .map(|k| {
+                    let hero = first + width * (k as f32 + 0.5) / n as f32;
+                    let wavelengths = Vector3::new(hero, hero + width, hero + 2.0 * width);
+                    let path = Path {
+                        wavelengths: Some(wavelengths),
+                        ..path
+                    };
+                    spectral_to_rgb(path.spectrum(rgb), wavelengths)
+                })
+                .sum::<Vector3<f32>>()
+                / n as f32
+        };
+        for rgb in [
+            Vector3::repeat(1.0),
+            Vector3::repeat(0.5),
+            Vector3::repeat(0.18),
+            Vector3::new(0.8, 0.3, 0.1),
+        ] {
+            let back = round_trip(rgb);
+            assert!(
+                (back - rgb).amax() < 0.01,
+                "{:?} came back as {:?}",
+                rgb,
+                back
+            );
+        }
+        // Neutral spectra are flat, and RGB paths pass colors through
+        let gray = Path {
+            wavelengths: Some(Vector3::new(400.0, 520.0, 690.0)),
+            ..path
+        };
+        assert!((gray.spectrum(Vector3::repeat(0.5)) - Vector3::repeat(0.5)).amax() < 1e-6);
+        assert_eq!(
+            path.spectrum(Vector3::new(0.8, 0.3, 0.1)),
+            Vector3::new(0.8, 0.3, 0.1)
+        );
+        // Glass that doesn't disperse has one index at every wavelength
+        assert_eq!(gray.ior(Vector3::repeat(1.5)), Vector3::repeat(1.5));
+        let dispersed = gray.ior(Vector3::new(1.48, 1.5, 1.52));
+        assert!(dispersed.x > dispersed.y && dispersed.y > dispersed.z);
+    }
+
+    #[test]
+    fn spectral_renders_of_neutral_scenes_match_rgb() {
+        // A gray ball under a white light and the default sky, whose
+        // spectral render converges on the RGB one; three wavelengths per
+        // sample leave color noise that shrinks with the sample count, so
+        // this takes enough samples to settle within a few levels
+        let gray = Material::new(Vector3::repeat(0.6));
+        let scene = Scene::new(
+            vec![Sphere::new(Vector3::new(0.0, 0.0, -8.0), 3.0, gray)],
+            vec![Light::new(Vector3::new(-10.0, 10.0, 10.0), 1.2)],
+        );
+        let cancel = AtomicBool::new(false);
+        let render = |extra: &str| {
+            let config = args(&format!("--width 12 --height 12 --samples 256 {}", extra)).unwrap();
+            render_to_buffer(&scene, &config, &cancel).unwrap().0
+        };
+        let (rgb, spectral) = (render(""), render("--spectral"));
+        let worst = rgb
+            .iter()
+            .zip(&spectral)
+            .map(|(&a, &b)| (a as i32 - b as i32).abs())
+            .max()
+            .unwrap();
+        assert!(worst <= 4, "off by up to {}", worst);
+        assert!(rgb.len() == spectral.len());
+    }
+}